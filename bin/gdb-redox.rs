@@ -48,6 +48,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let res = gdbserver::main(gdbserver::Opt {
         addr: path,
         kind: String::from("unix"),
+        baud: 115200,
+        once: true,
+        env: Vec::new(),
+        cwd: None,
+        startup_with_shell: false,
+        no_aslr: false,
+        tty: None,
+        new_tty: false,
+        stdin: None,
+        stdout: None,
+        stderr: None,
+        trace_file: None,
+        record: false,
+        dump_core_on_crash: None,
+        core: None,
+        wait_for_crash: false,
 
         program: opt.program,
         args: opt.args,
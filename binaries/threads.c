@@ -0,0 +1,22 @@
+#include <pthread.h>
+#include <signal.h>
+
+volatile int started = 0;
+volatile unsigned long counter = 0;
+
+void *spin(void *arg) {
+  started = 1;
+  for (;;) {
+    counter++;
+  }
+  return arg;
+}
+
+int main() {
+  pthread_t thread;
+  pthread_create(&thread, NULL, spin, NULL);
+  while (!started) {
+  }
+  raise(SIGTRAP);
+  return 0;
+}
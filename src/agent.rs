@@ -0,0 +1,493 @@
+//! Running GDB's agent expressions, the bytecode it compiles breakpoint
+//! conditions into. See "Agent Expressions" in the GDB manual.
+
+use crate::{Result, ERROR_BAD_BYTECODE};
+
+use gdb_remote_protocol::Error;
+
+// Opcodes, from gdb/ax.def
+const ADD: u8 = 0x02;
+const SUB: u8 = 0x03;
+const MUL: u8 = 0x04;
+const DIV_SIGNED: u8 = 0x05;
+const DIV_UNSIGNED: u8 = 0x06;
+const REM_SIGNED: u8 = 0x07;
+const REM_UNSIGNED: u8 = 0x08;
+const LSH: u8 = 0x09;
+const RSH_SIGNED: u8 = 0x0A;
+const RSH_UNSIGNED: u8 = 0x0B;
+const TRACE: u8 = 0x0C;
+const TRACE_QUICK: u8 = 0x0D;
+const LOG_NOT: u8 = 0x0E;
+const BIT_AND: u8 = 0x0F;
+const BIT_OR: u8 = 0x10;
+const BIT_XOR: u8 = 0x11;
+const BIT_NOT: u8 = 0x12;
+const EQUAL: u8 = 0x13;
+const LESS_SIGNED: u8 = 0x14;
+const LESS_UNSIGNED: u8 = 0x15;
+const EXT: u8 = 0x16;
+const REF8: u8 = 0x17;
+const REF16: u8 = 0x18;
+const REF32: u8 = 0x19;
+const REF64: u8 = 0x1A;
+const IF_GOTO: u8 = 0x20;
+const GOTO: u8 = 0x21;
+const CONST8: u8 = 0x22;
+const CONST16: u8 = 0x23;
+const CONST32: u8 = 0x24;
+const CONST64: u8 = 0x25;
+const REG: u8 = 0x26;
+const END: u8 = 0x27;
+const DUP: u8 = 0x28;
+const POP: u8 = 0x29;
+const ZERO_EXT: u8 = 0x2A;
+const SWAP: u8 = 0x2B;
+const TRACE_NZ: u8 = 0x2F;
+const TRACE16: u8 = 0x30;
+const PICK: u8 = 0x32;
+const ROT: u8 = 0x33;
+const PRINTF: u8 = 0x34;
+
+// Limits that keep a bad expression from taking the server down with it
+const MAX_STACK: usize = 1024;
+const MAX_STEPS: usize = 100_000;
+const MAX_STRING: usize = 4096;
+
+/// What an expression gets to look at while running
+pub trait Context {
+    /// Read a register by its number in target-desc.xml
+    fn register(&mut self, regno: u64) -> Result<u64>;
+
+    /// Read the tracee's memory
+    fn memory(&mut self, address: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Show text printed by the expression to the user
+    fn output(&mut self, _text: &str) {}
+
+    /// Record memory into a trace frame, which only tracepoint actions can
+    fn trace(&mut self, _address: u64, _size: u64) -> Result<()> {
+        Err(bad())
+    }
+}
+
+fn bad() -> Error {
+    Error::Error(ERROR_BAD_BYTECODE)
+}
+
+struct Machine<'a> {
+    code: &'a [u8],
+    pc: usize,
+    stack: Vec<u64>,
+}
+impl<'a> Machine<'a> {
+    /// Read an immediate operand, which are big-endian
+    fn operand(&mut self, size: usize) -> Result<u64> {
+        let bytes = self.code.get(self.pc..self.pc + size).ok_or_else(bad)?;
+        self.pc += size;
+        Ok(bytes.iter().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+    fn push(&mut self, value: u64) -> Result<()> {
+        if self.stack.len() >= MAX_STACK {
+            return Err(bad());
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+    fn pop(&mut self) -> Result<u64> {
+        self.stack.pop().ok_or_else(bad)
+    }
+    fn top(&mut self) -> Result<&mut u64> {
+        self.stack.last_mut().ok_or_else(bad)
+    }
+    /// Pop two values and push the result of an operation on them, where
+    /// the first argument was deeper in the stack
+    fn binary<F: FnOnce(u64, u64) -> Option<u64>>(&mut self, op: F) -> Result<()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let result = op(a, b).ok_or_else(bad)?;
+        self.push(result)
+    }
+    fn read(&mut self, ctx: &mut dyn Context, size: usize) -> Result<()> {
+        let address = self.pop()?;
+        let mut buf = [0; 8];
+        ctx.memory(address, &mut buf[..size])?;
+        self.push(u64::from_le_bytes(buf))
+    }
+}
+
+fn sign_extend(value: u64, bits: u64) -> u64 {
+    if bits == 0 || bits >= 64 {
+        return value;
+    }
+    let shift = 64 - bits;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+fn zero_extend(value: u64, bits: u64) -> u64 {
+    if bits >= 64 {
+        return value;
+    }
+    value & ((1 << bits) - 1)
+}
+
+/// Read a NUL-terminated string out of the tracee, up to a maximum length
+fn read_string(ctx: &mut dyn Context, mut address: u64, max: usize) -> Result<Vec<u8>> {
+    let mut string = Vec::new();
+    while string.len() < max {
+        let mut byte = [0];
+        ctx.memory(address, &mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        string.push(byte[0]);
+        address += 1;
+    }
+    Ok(string)
+}
+
+/// Pad a formatted value up to the width
+fn pad(text: String, width: usize, left: bool, zero: bool) -> String {
+    if text.len() >= width {
+        return text;
+    }
+    let fill = width - text.len();
+    if left {
+        format!("{}{}", text, " ".repeat(fill))
+    } else if zero {
+        // Zeroes go after any sign or base prefix
+        let split = if text.starts_with("0x") || text.starts_with("0X") {
+            2
+        } else if text.starts_with(&['-', '+', ' '][..]) {
+            1
+        } else {
+            0
+        };
+        format!("{}{}{}", &text[..split], "0".repeat(fill), &text[split..])
+    } else {
+        format!("{}{}", " ".repeat(fill), text)
+    }
+}
+
+/// Format like C's printf, with the arguments in the order they appear.
+/// Strings are read out of the tracee, and floating point isn't supported,
+/// just like in gdbserver.
+fn printf(format: &[u8], args: &[u64], ctx: &mut dyn Context) -> Result<String> {
+    let format = String::from_utf8_lossy(format);
+    let mut args = args.iter().copied();
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let (mut left, mut zero, mut plus, mut space, mut alt) = (false, false, false, false, false);
+        while let Some(&flag) = chars.peek() {
+            match flag {
+                '-' => left = true,
+                '0' => zero = true,
+                '+' => plus = true,
+                ' ' => space = true,
+                '#' => alt = true,
+                _ => break,
+            }
+            chars.next();
+        }
+        let mut width = 0;
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            width = width * 10 + digit as usize;
+            chars.next();
+        }
+        let mut precision = None;
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut digits = 0;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                digits = digits * 10 + digit as usize;
+                chars.next();
+            }
+            precision = Some(digits);
+        }
+        // Values are truncated to the size of their type
+        let mut bits = 32;
+        while let Some(&modifier) = chars.peek() {
+            match modifier {
+                'h' => bits /= 2,
+                'l' | 'z' | 'j' | 't' => bits = 64,
+                _ => break,
+            }
+            chars.next();
+        }
+        let bits = bits.max(8);
+
+        let conversion = chars.next().ok_or_else(bad)?;
+        let text = match conversion {
+            '%' => String::from("%"),
+            'd' | 'i' => {
+                let value = sign_extend(zero_extend(args.next().ok_or_else(bad)?, bits), bits) as i64;
+                let mut digits = value.unsigned_abs().to_string();
+                if let Some(precision) = precision {
+                    digits = format!("{:0>1$}", digits, precision);
+                }
+                let sign = if value < 0 { "-" } else if plus { "+" } else if space { " " } else { "" };
+                format!("{}{}", sign, digits)
+            },
+            'u' | 'x' | 'X' | 'o' | 'c' => {
+                let value = zero_extend(args.next().ok_or_else(bad)?, bits);
+                let mut digits = match conversion {
+                    'u' => value.to_string(),
+                    'x' => format!("{:x}", value),
+                    'X' => format!("{:X}", value),
+                    'o' => format!("{:o}", value),
+                    _ => (value as u8 as char).to_string(),
+                };
+                if let Some(precision) = precision {
+                    digits = format!("{:0>1$}", digits, precision);
+                }
+                match conversion {
+                    'x' if alt && value != 0 => format!("0x{}", digits),
+                    'X' if alt && value != 0 => format!("0X{}", digits),
+                    'o' if alt && !digits.starts_with('0') => format!("0{}", digits),
+                    _ => digits,
+                }
+            },
+            'p' => format!("0x{:x}", args.next().ok_or_else(bad)?),
+            's' => {
+                let address = args.next().ok_or_else(bad)?;
+                let mut string = read_string(ctx, address, MAX_STRING)?;
+                if let Some(precision) = precision {
+                    string.truncate(precision);
+                }
+                String::from_utf8_lossy(&string).into_owned()
+            },
+            _ => return Err(bad()),
+        };
+        let zero = zero && precision.is_none() && !matches!(conversion, 's' | 'c' | '%');
+        out.push_str(&pad(text, width, left, zero));
+    }
+    Ok(out)
+}
+
+/// Run an expression until its end, and return what's on top of the stack
+pub fn eval(code: &[u8], ctx: &mut dyn Context) -> Result<u64> {
+    run(code, ctx)?.pop()
+}
+
+/// Run an expression only for what it does, like the printing done by
+/// breakpoint commands, which leave nothing on the stack
+pub fn exec(code: &[u8], ctx: &mut dyn Context) -> Result<()> {
+    run(code, ctx).map(|_| ())
+}
+
+fn run<'a>(code: &'a [u8], ctx: &mut dyn Context) -> Result<Machine<'a>> {
+    let mut m = Machine {
+        code,
+        pc: 0,
+        stack: Vec::new(),
+    };
+
+    for _ in 0..MAX_STEPS {
+        let opcode = *code.get(m.pc).ok_or_else(bad)?;
+        m.pc += 1;
+
+        match opcode {
+            ADD => m.binary(|a, b| Some(a.wrapping_add(b)))?,
+            SUB => m.binary(|a, b| Some(a.wrapping_sub(b)))?,
+            MUL => m.binary(|a, b| Some(a.wrapping_mul(b)))?,
+            DIV_SIGNED => m.binary(|a, b| (a as i64).checked_div(b as i64).map(|r| r as u64))?,
+            DIV_UNSIGNED => m.binary(|a, b| a.checked_div(b))?,
+            REM_SIGNED => m.binary(|a, b| (a as i64).checked_rem(b as i64).map(|r| r as u64))?,
+            REM_UNSIGNED => m.binary(|a, b| a.checked_rem(b))?,
+            LSH => m.binary(|a, b| Some(a.checked_shl(b as u32).unwrap_or(0)))?,
+            RSH_SIGNED => m.binary(|a, b| Some(((a as i64) >> b.min(63)) as u64))?,
+            RSH_UNSIGNED => m.binary(|a, b| Some(a.checked_shr(b as u32).unwrap_or(0)))?,
+            LOG_NOT => {
+                let top = m.top()?;
+                *top = (*top == 0) as u64;
+            },
+            BIT_AND => m.binary(|a, b| Some(a & b))?,
+            BIT_OR => m.binary(|a, b| Some(a | b))?,
+            BIT_XOR => m.binary(|a, b| Some(a ^ b))?,
+            BIT_NOT => {
+                let top = m.top()?;
+                *top = !*top;
+            },
+            EQUAL => m.binary(|a, b| Some((a == b) as u64))?,
+            LESS_SIGNED => m.binary(|a, b| Some(((a as i64) < (b as i64)) as u64))?,
+            LESS_UNSIGNED => m.binary(|a, b| Some((a < b) as u64))?,
+            EXT => {
+                let bits = m.operand(1)?;
+                let top = m.top()?;
+                *top = sign_extend(*top, bits);
+            },
+            ZERO_EXT => {
+                let bits = m.operand(1)?;
+                let top = m.top()?;
+                *top = zero_extend(*top, bits);
+            },
+            REF8 => m.read(ctx, 1)?,
+            REF16 => m.read(ctx, 2)?,
+            REF32 => m.read(ctx, 4)?,
+            REF64 => m.read(ctx, 8)?,
+            IF_GOTO => {
+                let target = m.operand(2)?;
+                if m.pop()? != 0 {
+                    m.pc = target as usize;
+                }
+            },
+            GOTO => m.pc = m.operand(2)? as usize,
+            CONST8 => {
+                let value = m.operand(1)?;
+                m.push(value)?;
+            },
+            CONST16 => {
+                let value = m.operand(2)?;
+                m.push(value)?;
+            },
+            CONST32 => {
+                let value = m.operand(4)?;
+                m.push(value)?;
+            },
+            CONST64 => {
+                let value = m.operand(8)?;
+                m.push(value)?;
+            },
+            REG => {
+                let regno = m.operand(2)?;
+                let value = ctx.register(regno)?;
+                m.push(value)?;
+            },
+            END => return Ok(m),
+            DUP => {
+                let top = *m.top()?;
+                m.push(top)?;
+            },
+            POP => {
+                m.pop()?;
+            },
+            SWAP => {
+                let b = m.pop()?;
+                let a = m.pop()?;
+                m.push(b)?;
+                m.push(a)?;
+            },
+            PICK => {
+                let depth = m.operand(1)? as usize;
+                let value = *m.stack.iter().rev().nth(depth).ok_or_else(bad)?;
+                m.push(value)?;
+            },
+            ROT => {
+                let c = m.pop()?;
+                let b = m.pop()?;
+                let a = m.pop()?;
+                m.push(c)?;
+                m.push(a)?;
+                m.push(b)?;
+            },
+            PRINTF => {
+                let nargs = m.operand(1)? as usize;
+                let len = m.operand(2)? as usize;
+                let format = code.get(m.pc..m.pc + len).ok_or_else(bad)?;
+                m.pc += len;
+
+                // The function and channel to print with, which only matter
+                // when printing through the tracee
+                m.pop()?;
+                m.pop()?;
+                let args = (0..nargs).map(|_| m.pop()).collect::<Result<Vec<_>>>()?;
+
+                let format = match format.split_last() {
+                    Some((0, format)) => format,
+                    _ => return Err(bad()),
+                };
+                let text = printf(format, &args, ctx)?;
+                ctx.output(&text);
+            },
+            TRACE => {
+                let size = m.pop()?;
+                let address = m.pop()?;
+                ctx.trace(address, size)?;
+            },
+            TRACE_QUICK | TRACE16 => {
+                let size = m.operand(if opcode == TRACE16 { 2 } else { 1 })?;
+                let address = *m.top()?;
+                ctx.trace(address, size)?;
+            },
+            TRACE_NZ => {
+                let size = m.pop()?;
+                let address = m.pop()?;
+                // Up to and including the terminator, if there's one in time
+                let string = read_string(ctx, address, size.min(MAX_STRING as u64) as usize)?;
+                ctx.trace(address, size.min(string.len() as u64 + 1))?;
+            },
+            // Floating point, trace state variables and anything unknown
+            _ => return Err(bad()),
+        }
+    }
+    Err(bad())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eval, exec, Context};
+    use crate::Result;
+
+    #[derive(Default)]
+    struct Fake {
+        output: String,
+    }
+    impl Context for Fake {
+        fn register(&mut self, regno: u64) -> Result<u64> {
+            Ok(regno * 10)
+        }
+        fn memory(&mut self, address: u64, buf: &mut [u8]) -> Result<()> {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = address as u8 + i as u8;
+            }
+            Ok(())
+        }
+        fn output(&mut self, text: &str) {
+            self.output.push_str(text);
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        // $rcx == 20
+        assert_eq!(eval(&[0x26, 0, 2, 0x22, 20, 0x13, 0x27], &mut Fake::default()).unwrap(), 1);
+        // -3 < 2, with -3 sign extended from a byte
+        assert_eq!(eval(&[0x22, 0xFD, 0x16, 8, 0x22, 2, 0x14, 0x27], &mut Fake::default()).unwrap(), 1);
+        // *(short *) 0x10
+        assert_eq!(eval(&[0x22, 0x10, 0x18, 0x27], &mut Fake::default()).unwrap(), 0x1110);
+        // Division by zero
+        assert!(eval(&[0x22, 1, 0x22, 0, 0x05, 0x27], &mut Fake::default()).is_err());
+    }
+
+    #[test]
+    fn control_flow() {
+        // if (1) 7 else 9
+        let code = [0x22, 1, 0x20, 0, 8, 0x22, 9, 0x27, 0x22, 7, 0x27];
+        assert_eq!(eval(&code, &mut Fake::default()).unwrap(), 7);
+        // Looping forever gives up eventually
+        assert!(eval(&[0x21, 0, 0], &mut Fake::default()).is_err());
+    }
+
+    #[test]
+    fn printf() {
+        let mut fake = Fake::default();
+        let mut code = vec![
+            0x22, 0x41, // "%s" reads "ABC..." from 0x41
+            0x22, 0xFF, 0x16, 8, // -1
+            0x24, 0x12, 0x34, 0xAB, 0xCD, // 0x1234ABCD
+            0x22, 0, 0x22, 0, // function and channel
+            0x34, 3, 0, 19,
+        ];
+        code.extend_from_slice(b"%08x %-3d| %.3s %%\0");
+        code.push(0x27);
+        exec(&code, &mut fake).unwrap();
+        assert_eq!(fake.output, "1234abcd -1 | ABC %");
+    }
+}
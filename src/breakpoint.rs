@@ -0,0 +1,272 @@
+//! Software breakpoints inserted by the server on GDB's behalf, so it can
+//! decide by itself whether hitting one is worth stopping for
+
+use crate::{
+    agent::{self, Context},
+    os::{Registers, Target},
+    x86::INT3,
+    Result, ERROR_NO_BREAKPOINT,
+};
+
+use gdb_remote_protocol::{Error, StopReason};
+use log::debug;
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+struct Inserted {
+    /// The byte replaced by the breakpoint instruction
+    original: u8,
+    /// Agent expressions, any of which being true makes the breakpoint stop
+    conditions: Vec<Vec<u8>>,
+    /// Agent expressions run instead of stopping, like dprintf's
+    commands: Vec<Vec<u8>>,
+    /// Whether GDB asked for this breakpoint, as opposed to only a tracepoint
+    requested: bool,
+    /// Whether a running tracepoint is here
+    traced: bool,
+}
+
+/// Lets agent expressions look at a stopped thread
+struct Stopped<'a, T: Target> {
+    tracee: &'a T,
+    regs: Registers,
+    output: &'a mut dyn FnMut(&str),
+}
+impl<'a, T: Target> Context for Stopped<'a, T> {
+    fn register(&mut self, regno: u64) -> Result<u64> {
+        self.regs.get(regno).ok_or(Error::Unimplemented)
+    }
+    fn memory(&mut self, address: u64, buf: &mut [u8]) -> Result<()> {
+        self.tracee.getmem(address as usize, buf)?;
+        Ok(())
+    }
+    fn output(&mut self, text: &str) {
+        (self.output)(text);
+    }
+}
+
+#[derive(Default)]
+pub struct Breakpoints {
+    inserted: BTreeMap<u64, Inserted>,
+}
+impl Breakpoints {
+    /// Put a breakpoint instruction at the address, unless one is there
+    fn plant<T: Target>(&mut self, tracee: &T, address: u64) -> Result<&mut Inserted> {
+        Ok(match self.inserted.entry(address) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut original = [0];
+                tracee.getmem(address as usize, &mut original)?;
+                tracee.setmem(address as usize, &[INT3])?;
+                entry.insert(Inserted {
+                    original: original[0],
+                    conditions: Vec::new(),
+                    commands: Vec::new(),
+                    requested: false,
+                    traced: false,
+                })
+            },
+        })
+    }
+
+    /// Take the breakpoint instruction away again once nothing uses it
+    fn unplant<T: Target>(&mut self, tracee: &T, address: u64) -> Result<()> {
+        match self.inserted.get(&address) {
+            Some(inserted) if !inserted.requested && !inserted.traced => {
+                tracee.setmem(address as usize, &[inserted.original])?;
+                self.inserted.remove(&address);
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Insert a breakpoint, or replace the conditions and commands of an
+    /// existing one
+    pub fn insert<T: Target>(
+        &mut self,
+        tracee: &T,
+        address: u64,
+        conditions: Vec<Vec<u8>>,
+        commands: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        let inserted = self.plant(tracee, address)?;
+        inserted.conditions = conditions;
+        inserted.commands = commands;
+        inserted.requested = true;
+        Ok(())
+    }
+
+    /// Remove a breakpoint, putting back what it replaced
+    pub fn remove<T: Target>(&mut self, tracee: &T, address: u64) -> Result<()> {
+        if let Some(inserted) = self.inserted.get_mut(&address) {
+            inserted.conditions.clear();
+            inserted.commands.clear();
+            inserted.requested = false;
+        }
+        self.unplant(tracee, address)
+    }
+
+    /// Insert a breakpoint for a tracepoint, which never stops by itself
+    pub fn trace<T: Target>(&mut self, tracee: &T, address: u64) -> Result<()> {
+        self.plant(tracee, address)?.traced = true;
+        Ok(())
+    }
+
+    /// Remove the breakpoints of all tracepoints
+    pub fn untrace<T: Target>(&mut self, tracee: &T) -> Result<()> {
+        let traced: Vec<u64> = self.inserted.iter()
+            .filter(|(_, inserted)| inserted.traced)
+            .map(|(&address, _)| address)
+            .collect();
+        for address in traced {
+            if let Some(inserted) = self.inserted.get_mut(&address) {
+                inserted.traced = false;
+            }
+            self.unplant(tracee, address)?;
+        }
+        Ok(())
+    }
+
+    /// Remove all breakpoints, for when GDB is gone and can't do it
+    pub fn clear<T: Target>(&mut self, tracee: &T) -> Result<()> {
+        while let Some(&address) = self.inserted.keys().next() {
+            if let Some(inserted) = self.inserted.remove(&address) {
+                tracee.setmem(address as usize, &[inserted.original])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put back what every breakpoint replaced in another process, such as a
+    /// copy of the tracee that shouldn't stop at them
+    pub fn remove_from<T: Target>(&self, tracee: &T) -> Result<()> {
+        for (&address, inserted) in &self.inserted {
+            tracee.setmem(address as usize, &[inserted.original])?;
+        }
+        Ok(())
+    }
+
+    /// Insert every breakpoint into another process, such as a copy of the
+    /// tracee about to replace it
+    pub fn insert_into<T: Target>(&self, tracee: &T) -> Result<()> {
+        for &address in self.inserted.keys() {
+            tracee.setmem(address as usize, &[INT3])?;
+        }
+        Ok(())
+    }
+
+    /// Forget all breakpoints, for when the tracee they were in is gone
+    pub fn forget(&mut self) {
+        self.inserted.clear();
+    }
+
+    /// Check whether there's a breakpoint at the address
+    pub fn contains(&self, address: u64) -> bool {
+        self.inserted.contains_key(&address)
+    }
+
+    /// Replace any breakpoint instructions in memory read from the address
+    /// with what they replaced, so GDB sees the original code
+    pub fn hide(&self, address: u64, memory: &mut [u8]) {
+        let end = address + memory.len() as u64;
+        for (&at, inserted) in self.inserted.range(address..end) {
+            memory[(at - address) as usize] = inserted.original;
+        }
+    }
+
+    /// Write to memory without removing any breakpoints in it, but
+    /// updating what they'll put back once removed
+    pub fn write<T: Target>(&mut self, tracee: &T, address: u64, bytes: &[u8]) -> Result<()> {
+        let end = address + bytes.len() as u64;
+        let mut bytes = bytes.to_vec();
+        for (&at, inserted) in self.inserted.range_mut(address..end) {
+            let byte = &mut bytes[(at - address) as usize];
+            inserted.original = *byte;
+            *byte = INT3;
+        }
+        tracee.setmem(address as usize, &bytes)?;
+        Ok(())
+    }
+
+    /// Check whether the current thread stopped by hitting a breakpoint. If
+    /// so, move it back to the breakpoint and return the address.
+    pub fn hit<T: Target>(&self, tracee: &T) -> Result<Option<u64>> {
+        match tracee.status_native() {
+            StopReason::Signal(signal) if libc::c_int::from(signal) == libc::SIGTRAP => (),
+            _ => return Ok(None),
+        }
+        let mut regs = tracee.getregs()?;
+        let address = match regs.rip {
+            Some(rip) if self.inserted.contains_key(&rip.wrapping_sub(1)) => rip - 1,
+            _ => return Ok(None),
+        };
+        regs.rip = Some(address);
+        tracee.setregs(&regs)?;
+        Ok(Some(address))
+    }
+
+    /// Evaluate the conditions of the breakpoint the current thread is at.
+    /// Anything that fails to evaluate counts as true, so GDB gets to see it.
+    /// Breakpoints with commands run them instead of ever stopping, with
+    /// anything they print passed to `output`, and those only there for a
+    /// tracepoint don't stop at all.
+    pub fn should_stop<T: Target>(&self, tracee: &T, address: u64, output: &mut dyn FnMut(&str)) -> Result<bool> {
+        let inserted = match self.inserted.get(&address) {
+            Some(inserted) if !inserted.requested => return Ok(false),
+            Some(inserted) => inserted,
+            None => return Ok(true),
+        };
+        let mut ctx = Stopped {
+            tracee,
+            regs: tracee.getregs()?,
+            output,
+        };
+
+        let mut triggered = inserted.conditions.is_empty();
+        for condition in &inserted.conditions {
+            match agent::eval(condition, &mut ctx) {
+                Ok(0) => (),
+                Ok(_) => triggered = true,
+                Err(err) => {
+                    debug!("Condition at {:#X} failed: {:?}", address, err);
+                    triggered = true;
+                },
+            }
+            if triggered {
+                break;
+            }
+        }
+        if !triggered || inserted.commands.is_empty() {
+            return Ok(triggered);
+        }
+
+        for command in &inserted.commands {
+            if let Err(err) = agent::exec(command, &mut ctx) {
+                debug!("Command at {:#X} failed: {:?}", address, err);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Single-step the current thread past the breakpoint it's at. Returns
+    /// false if it stopped for anything but the step.
+    pub fn step_over<T: Target>(&self, tracee: &T, address: u64, signal: Option<u8>) -> Result<bool> {
+        let original = self.inserted.get(&address).map(|inserted| inserted.original);
+        let original = original.ok_or(Error::Error(ERROR_NO_BREAKPOINT))?;
+
+        tracee.setmem(address as usize, &[original])?;
+        let stepped = tracee.step(signal);
+        tracee.setmem(address as usize, &[INT3])?;
+        Ok(stepped?.is_some())
+    }
+
+    /// Single-step the current thread, past the breakpoint it's at if any.
+    /// Returns false if it stopped for anything but the step.
+    pub fn step<T: Target>(&self, tracee: &T, signal: Option<u8>) -> Result<bool> {
+        match tracee.getregs()?.rip {
+            Some(rip) if self.inserted.contains_key(&rip) => self.step_over(tracee, rip, signal),
+            _ => Ok(tracee.step(signal)?.is_some()),
+        }
+    }
+}
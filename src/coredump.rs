@@ -0,0 +1,310 @@
+//! Core files of the tracee, laid out like the ones Linux dumps so GDB can
+//! load them later. See core(5) and <linux/elfcore.h>.
+
+use crate::{
+    breakpoint::Breakpoints,
+    elf::{self, ProgramHeader, PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE},
+    os::{Mapping, Registers, Target},
+    Result,
+};
+
+use byteorder::{NativeEndian, WriteBytesExt};
+use gdb_remote_protocol::{Error, StopReason};
+use log::debug;
+
+use std::{
+    fs,
+    io::{self, prelude::*},
+};
+
+pub const NT_PRSTATUS: u32 = 1;
+pub const NT_FPREGSET: u32 = 2;
+pub const NT_PRPSINFO: u32 = 3;
+pub const NT_AUXV: u32 = 6;
+pub const NT_FILE: u32 = 0x4649_4C45;
+
+const PAGE_SIZE: u64 = 4096;
+// Sizes of the fixed-length strings in struct elf_prpsinfo
+const FNAME_LEN: usize = 16;
+const PSARGS_LEN: usize = 80;
+
+fn io_error(err: io::Error) -> Error {
+    Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8)
+}
+
+fn page_align(offset: u64) -> u64 {
+    (offset + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Copy as much of the text as fits into a NUL-terminated field
+fn fixed_string(text: &str, len: usize) -> Vec<u8> {
+    let mut field = vec![0; len];
+    let text = &text.as_bytes()[..text.len().min(len - 1)];
+    field[..text.len()].copy_from_slice(text);
+    field
+}
+
+/// struct elf_prstatus of one thread, where the registers are a struct
+/// user_regs_struct. Times and pending signals are left zero.
+pub(crate) fn prstatus(pid: u32, tid: u32, signal: u8, regs: &Registers) -> Vec<u8> {
+    let mut note = Vec::new();
+    // struct elf_siginfo, then the current signal and its padding
+    note.write_i32::<NativeEndian>(i32::from(signal)).unwrap();
+    note.write_all(&[0; 8]).unwrap();
+    note.write_u16::<NativeEndian>(u16::from(signal)).unwrap();
+    note.write_all(&[0; 2 + 8 + 8]).unwrap();
+
+    let pid = pid as libc::pid_t;
+    note.write_i32::<NativeEndian>(tid as i32).unwrap();
+    note.write_i32::<NativeEndian>(0).unwrap();
+    note.write_i32::<NativeEndian>(unsafe { libc::getpgid(pid) }).unwrap();
+    note.write_i32::<NativeEndian>(unsafe { libc::getsid(pid) }).unwrap();
+    note.write_all(&[0; 4 * 16]).unwrap();
+
+    let wide = |reg: Option<u32>| reg.map(u64::from);
+    let user_regs = [
+        regs.r15, regs.r14, regs.r13, regs.r12, regs.rbp, regs.rbx, regs.r11, regs.r10,
+        regs.r9, regs.r8, regs.rax, regs.rcx, regs.rdx, regs.rsi, regs.rdi,
+        // orig_rax, where no system call is being restarted
+        Some(u64::MAX),
+        regs.rip, wide(regs.cs), wide(regs.eflags), regs.rsp, wide(regs.ss),
+        regs.fs_base, regs.gs_base, wide(regs.ds), wide(regs.es), wide(regs.fs), wide(regs.gs),
+    ];
+    for reg in &user_regs {
+        note.write_u64::<NativeEndian>(reg.unwrap_or(0)).unwrap();
+    }
+    // The floating point registers are valid, in their own note
+    note.write_i32::<NativeEndian>(1).unwrap();
+    note.write_all(&[0; 4]).unwrap();
+    note
+}
+
+/// struct user_fpregs_struct, as saved by fxsave
+pub(crate) fn fpregset(regs: &Registers) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.write_u16::<NativeEndian>(regs.fctrl.unwrap_or(0) as u16).unwrap();
+    note.write_u16::<NativeEndian>(regs.fstat.unwrap_or(0) as u16).unwrap();
+    note.write_u16::<NativeEndian>(regs.ftag.unwrap_or(0) as u16).unwrap();
+    note.write_u16::<NativeEndian>(regs.fop.unwrap_or(0) as u16).unwrap();
+    note.write_u64::<NativeEndian>(u64::from(regs.fioff.unwrap_or(0))).unwrap();
+    note.write_u64::<NativeEndian>(u64::from(regs.fooff.unwrap_or(0))).unwrap();
+    note.write_u32::<NativeEndian>(regs.mxcsr.unwrap_or(0)).unwrap();
+    // The mask of supported MXCSR bits, for when the processor doesn't say
+    note.write_u32::<NativeEndian>(0xFFBF).unwrap();
+
+    let st = [regs.st0, regs.st1, regs.st2, regs.st3, regs.st4, regs.st5, regs.st6, regs.st7];
+    let xmm = [
+        regs.xmm0, regs.xmm1, regs.xmm2, regs.xmm3, regs.xmm4, regs.xmm5, regs.xmm6, regs.xmm7,
+        regs.xmm8, regs.xmm9, regs.xmm10, regs.xmm11, regs.xmm12, regs.xmm13, regs.xmm14, regs.xmm15,
+    ];
+    for reg in st.iter().chain(&xmm) {
+        note.write_u128::<NativeEndian>(reg.unwrap_or(0)).unwrap();
+    }
+    note.write_all(&[0; 4 * 24]).unwrap();
+    note
+}
+
+/// The real user and group IDs of the process, from the Uid and Gid lines
+/// of /proc/PID/status. Without those, it's assumed to be the server's own.
+fn owner(pid: u32) -> (u32, u32) {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let id = |field: &str| {
+        status.lines()
+            .find_map(|line| line.strip_prefix(field))
+            .and_then(|ids| ids.split_whitespace().next())
+            .and_then(|id| id.parse().ok())
+    };
+    let uid = id("Uid:").unwrap_or_else(|| unsafe { libc::getuid() });
+    let gid = id("Gid:").unwrap_or_else(|| unsafe { libc::getgid() });
+    (uid, gid)
+}
+
+/// struct elf_prpsinfo, naming the program and its arguments
+fn prpsinfo(pid: u32, command: &str) -> Vec<u8> {
+    let mut note = Vec::new();
+    // Stopped for tracing, see proc(5)
+    note.write_all(&[4, b't', 0, 0]).unwrap();
+    note.write_all(&[0; 4 + 8]).unwrap();
+    let (uid, gid) = owner(pid);
+    note.write_u32::<NativeEndian>(uid).unwrap();
+    note.write_u32::<NativeEndian>(gid).unwrap();
+
+    let pid = pid as libc::pid_t;
+    note.write_i32::<NativeEndian>(pid).unwrap();
+    note.write_i32::<NativeEndian>(0).unwrap();
+    note.write_i32::<NativeEndian>(unsafe { libc::getpgid(pid) }).unwrap();
+    note.write_i32::<NativeEndian>(unsafe { libc::getsid(pid) }).unwrap();
+
+    let program = command.split(' ').next().unwrap_or("");
+    let name = program.rsplit('/').next().unwrap_or(program);
+    note.extend(fixed_string(name, FNAME_LEN));
+    note.extend(fixed_string(command, PSARGS_LEN));
+    note
+}
+
+/// The note listing which files are mapped where, in units of pages
+fn mapped_files(mappings: &[Mapping]) -> Vec<u8> {
+    let files: Vec<&Mapping> = mappings.iter().filter(|mapping| mapping.name.starts_with('/')).collect();
+
+    let mut note = Vec::new();
+    note.write_u64::<NativeEndian>(files.len() as u64).unwrap();
+    note.write_u64::<NativeEndian>(PAGE_SIZE).unwrap();
+    for file in &files {
+        note.write_u64::<NativeEndian>(file.start).unwrap();
+        note.write_u64::<NativeEndian>(file.end).unwrap();
+        note.write_u64::<NativeEndian>(file.offset / PAGE_SIZE).unwrap();
+    }
+    for file in &files {
+        note.write_all(file.name.as_bytes()).unwrap();
+        note.push(0);
+    }
+    note
+}
+
+/// Write a core file of the tracee, which must be stopped, with everything
+/// readable in its address space as GDB sees it. The current thread comes
+/// first, as the one that stopped. `command` is the command line it was
+/// started with.
+pub fn write<T: Target, W: Write>(tracee: &T, breakpoints: &Breakpoints, command: &str, out: &mut W) -> Result<()> {
+    let pid = tracee.pid();
+    let current = tracee.current_thread();
+    let signal = match tracee.status_native() {
+        StopReason::Signal(signal) => signal,
+        _ => 0,
+    };
+    let mappings = tracee.mappings()?;
+
+    let mut threads = tracee.threads();
+    threads.sort_by_key(|&tid| tid != current);
+    let mut registers = Vec::new();
+    for &tid in &threads {
+        let regs = tracee.select_thread(tid).and_then(|()| tracee.getregs());
+        registers.push((tid, regs));
+    }
+    tracee.select_thread(current)?;
+
+    // The same notes in the same order as Linux
+    let mut notes = Vec::new();
+    let mut note = |kind, desc: &[u8]| elf::write_note(&mut notes, "CORE", kind, desc).unwrap();
+    for (i, (tid, regs)) in registers.into_iter().enumerate() {
+        let regs = regs?;
+        let thread_signal = if tid == current { signal } else { 0 };
+        note(NT_PRSTATUS, &prstatus(pid, tid, thread_signal, &regs));
+        if i == 0 {
+            note(NT_PRPSINFO, &prpsinfo(pid, command));
+            // Without it, GDB can still find the libraries through the
+            // dynamic linker, but not where the program itself is
+            match tracee.auxv() {
+                Ok(auxv) => note(NT_AUXV, &auxv),
+                Err(err) => debug!("No auxiliary vector for the core file: {:?}", err),
+            }
+            note(NT_FILE, &mapped_files(&mappings));
+        }
+        note(NT_FPREGSET, &fpregset(&regs));
+    }
+
+    let phnum = 1 + mappings.len();
+    let headers_end = elf::HEADER_SIZE + phnum as u64 * elf::PROGRAM_HEADER_SIZE;
+    // Memory starts on a page of its own, like it does in the tracee
+    let memory_start = page_align(headers_end + notes.len() as u64);
+
+    let mut offset = memory_start;
+    let mut headers = Vec::new();
+    elf::write_core_header(&mut headers, phnum as u16).unwrap();
+    elf::write_program_header(&mut headers, &ProgramHeader {
+        kind: PT_NOTE,
+        offset: headers_end,
+        filesz: notes.len() as u64,
+        ..ProgramHeader::default()
+    }).unwrap();
+    for mapping in &mappings {
+        let len = mapping.end - mapping.start;
+        let mut flags = 0;
+        for &(set, flag) in &[(mapping.read, PF_R), (mapping.write, PF_W), (mapping.execute, PF_X)] {
+            if set {
+                flags |= flag;
+            }
+        }
+        let filesz = if mapping.read { len } else { 0 };
+        elf::write_program_header(&mut headers, &ProgramHeader {
+            kind: PT_LOAD,
+            flags,
+            offset,
+            vaddr: mapping.start,
+            filesz,
+            memsz: len,
+            align: PAGE_SIZE,
+        }).unwrap();
+        offset += filesz;
+    }
+    headers.extend(notes);
+    headers.resize(memory_start as usize, 0);
+    out.write_all(&headers).map_err(io_error)?;
+
+    // Pages that can't be read, such as those of [vvar], are left zero
+    let mut page = vec![0; PAGE_SIZE as usize];
+    for mapping in mappings.iter().filter(|mapping| mapping.read) {
+        let mut address = mapping.start;
+        while address < mapping.end {
+            let page = &mut page[..(mapping.end - address).min(PAGE_SIZE) as usize];
+            match tracee.getmem(address as usize, page) {
+                Ok(_) => breakpoints.hide(address, page),
+                Err(_) => page.iter_mut().for_each(|byte| *byte = 0),
+            }
+            out.write_all(page).map_err(io_error)?;
+            address += page.len() as u64;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        breakpoint::Breakpoints,
+        elf,
+        os::{CoreFile, Launch, Os, Target},
+    };
+
+    use byteorder::{NativeEndian, ReadBytesExt};
+
+    use std::{
+        fs::{self, File},
+        process,
+    };
+
+    #[test]
+    fn core_of_child() {
+        let launch = Launch::new(&["true"]);
+        let tracee = Os::new(&launch).unwrap();
+        let path = std::env::temp_dir().join(format!("gdbserver-core-{}", process::id()));
+        let mut file = File::create(&path).unwrap();
+        super::write(&tracee, &Breakpoints::default(), "true", &mut file).unwrap();
+        drop(file);
+
+        let core = CoreFile::open(&path, "true");
+        let notes = File::open(&path).and_then(|mut file| {
+            let header = elf::program_headers(&mut file)?.into_iter().find(|header| header.kind == elf::PT_NOTE).unwrap();
+            elf::notes(&elf::segment(&mut file, &header)?)
+        });
+        fs::remove_file(&path).unwrap();
+        let core = core.unwrap();
+
+        assert_eq!(core.pid(), tracee.pid());
+        assert_eq!(core.threads(), tracee.threads());
+        let regs = tracee.getregs().unwrap();
+        assert_eq!(core.getregs().unwrap().rip, regs.rip);
+        let (mut dumped, mut live) = ([0; 16], [0; 16]);
+        core.getmem(regs.rip.unwrap() as usize, &mut dumped).unwrap();
+        tracee.getmem(regs.rip.unwrap() as usize, &mut live).unwrap();
+        assert_eq!(dumped, live);
+        assert_eq!(core.auxv().unwrap(), tracee.auxv().unwrap());
+
+        // Owned by whoever started it, which is the user running the tests
+        let notes = notes.unwrap();
+        let prpsinfo = notes.iter().find(|note| note.kind == super::NT_PRPSINFO).unwrap();
+        let mut ids = &prpsinfo.desc[16..24];
+        assert_eq!(ids.read_u32::<NativeEndian>().unwrap(), unsafe { libc::getuid() });
+        assert_eq!(ids.read_u32::<NativeEndian>().unwrap(), unsafe { libc::getgid() });
+    }
+}
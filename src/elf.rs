@@ -0,0 +1,164 @@
+//! Just enough of the ELF format for what the server needs to know about the
+//! tracee's executable and libraries, and to write core files of it
+
+use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+
+use std::io::{self, prelude::*, SeekFrom};
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+
+pub const HEADER_SIZE: u64 = 64;
+pub const PROGRAM_HEADER_SIZE: u64 = 56;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_NOTE: u32 = 4;
+pub const PT_TLS: u32 = 7;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// A segment of a 64-bit ELF file, see elf(5)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+/// Read all program headers of a 64-bit ELF file
+pub fn program_headers<R: Read + Seek>(file: &mut R) -> io::Result<Vec<ProgramHeader>> {
+    let mut ident = [0; 16];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut ident)?;
+    if &ident[..4] != b"\x7FELF" || ident[4] != ELFCLASS64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a 64-bit ELF file"));
+    }
+
+    file.seek(SeekFrom::Start(32))?;
+    let phoff = file.read_u64::<NativeEndian>()?;
+    file.seek(SeekFrom::Start(54))?;
+    let phentsize = file.read_u16::<NativeEndian>()?;
+    let phnum = file.read_u16::<NativeEndian>()?;
+
+    (0..u64::from(phnum))
+        .map(|i| {
+            file.seek(SeekFrom::Start(phoff + i * u64::from(phentsize)))?;
+            let kind = file.read_u32::<NativeEndian>()?;
+            let flags = file.read_u32::<NativeEndian>()?;
+            let offset = file.read_u64::<NativeEndian>()?;
+            let vaddr = file.read_u64::<NativeEndian>()?;
+            let _paddr = file.read_u64::<NativeEndian>()?;
+            Ok(ProgramHeader {
+                kind,
+                flags,
+                offset,
+                vaddr,
+                filesz: file.read_u64::<NativeEndian>()?,
+                memsz: file.read_u64::<NativeEndian>()?,
+                align: file.read_u64::<NativeEndian>()?,
+            })
+        })
+        .collect()
+}
+
+/// Read the contents of a segment
+pub fn segment<R: Read + Seek>(file: &mut R, header: &ProgramHeader) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; header.filesz as usize];
+    file.seek(SeekFrom::Start(header.offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Write the header of a 64-bit core file, with the program headers to
+/// follow right after it
+pub fn write_core_header<W: Write>(out: &mut W, phnum: u16) -> io::Result<()> {
+    let data = if cfg!(target_endian = "little") { ELFDATA2LSB } else { ELFDATA2MSB };
+    out.write_all(b"\x7FELF")?;
+    out.write_all(&[ELFCLASS64, data, EV_CURRENT])?;
+    // The rest of the identification is the System V ABI and padding
+    out.write_all(&[0; 9])?;
+    out.write_u16::<NativeEndian>(ET_CORE)?;
+    out.write_u16::<NativeEndian>(EM_X86_64)?;
+    out.write_u32::<NativeEndian>(u32::from(EV_CURRENT))?;
+    // Entry point, program and section header offsets, flags
+    out.write_u64::<NativeEndian>(0)?;
+    out.write_u64::<NativeEndian>(HEADER_SIZE)?;
+    out.write_u64::<NativeEndian>(0)?;
+    out.write_u32::<NativeEndian>(0)?;
+    out.write_u16::<NativeEndian>(HEADER_SIZE as u16)?;
+    out.write_u16::<NativeEndian>(PROGRAM_HEADER_SIZE as u16)?;
+    out.write_u16::<NativeEndian>(phnum)?;
+    // No sections
+    out.write_all(&[0; 6])
+}
+
+/// Write a program header in the format that `program_headers` reads
+pub fn write_program_header<W: Write>(out: &mut W, header: &ProgramHeader) -> io::Result<()> {
+    out.write_u32::<NativeEndian>(header.kind)?;
+    out.write_u32::<NativeEndian>(header.flags)?;
+    out.write_u64::<NativeEndian>(header.offset)?;
+    out.write_u64::<NativeEndian>(header.vaddr)?;
+    // The physical address, meaningless to anything but embedded systems
+    out.write_u64::<NativeEndian>(0)?;
+    out.write_u64::<NativeEndian>(header.filesz)?;
+    out.write_u64::<NativeEndian>(header.memsz)?;
+    out.write_u64::<NativeEndian>(header.align)
+}
+
+/// Write a note of a PT_NOTE segment, with its name and description padded
+/// to 4 bytes each
+pub fn write_note<W: Write>(out: &mut W, name: &str, kind: u32, desc: &[u8]) -> io::Result<()> {
+    let padding = |len: usize| (4 - len % 4) % 4;
+    // The name is terminated by a NUL byte, which it's counted with
+    let namesz = name.len() + 1;
+    out.write_u32::<NativeEndian>(namesz as u32)?;
+    out.write_u32::<NativeEndian>(desc.len() as u32)?;
+    out.write_u32::<NativeEndian>(kind)?;
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0; 4][..1 + padding(namesz)])?;
+    out.write_all(desc)?;
+    out.write_all(&[0; 4][..padding(desc.len())])
+}
+
+/// A note of a PT_NOTE segment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+    pub name: String,
+    pub kind: u32,
+    pub desc: Vec<u8>,
+}
+
+/// Split a PT_NOTE segment into its notes, the way `write_note` lays them out
+pub fn notes(mut segment: &[u8]) -> io::Result<Vec<Note>> {
+    let align = |len: u64| (len + 3) & !3;
+    let mut notes = Vec::new();
+    while !segment.is_empty() {
+        let namesz = u64::from(segment.read_u32::<NativeEndian>()?);
+        let descsz = u64::from(segment.read_u32::<NativeEndian>()?);
+        let kind = segment.read_u32::<NativeEndian>()?;
+        if align(namesz) + align(descsz) > segment.len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "note out of bounds"));
+        }
+        let name = &segment[..namesz as usize];
+        let name = name.split(|&byte| byte == 0).next().unwrap_or(name);
+        let desc = &segment[align(namesz) as usize..][..descsz as usize];
+        notes.push(Note {
+            name: String::from_utf8_lossy(name).into_owned(),
+            kind,
+            desc: desc.to_vec(),
+        });
+        segment = &segment[(align(namesz) + align(descsz)) as usize..];
+    }
+    Ok(notes)
+}
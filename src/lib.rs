@@ -1,37 +1,130 @@
 use std::{
     borrow::Cow,
+    cell::{Cell, Ref, RefCell},
     cmp::min,
+    collections::{BTreeMap, BTreeSet},
     convert::TryFrom,
+    ffi::OsStr,
+    fs::File,
+    path::{Path, PathBuf},
+    rc::Rc,
     io::{self, prelude::*, BufReader, BufWriter},
+    iter,
     net::TcpListener,
-    os::unix::net::UnixListener,
+    ops::Range,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixListener,
+    },
 };
 
 use gdb_remote_protocol::{
-    Error, FileSystem, Handler, Id, LibcFS, MemoryRegion, ProcessType,
-    Signal, StopReason, ThreadId, VCont, VContFeature,
+    Breakpoint, Error, FileSystem, Handler, Id, LibcFS, MemoryRegion, ProcessType,
+    SetThreadFor, Signal, StopReason, ThreadId, VCont, VContFeature,
 };
 use num_traits::FromPrimitive;
-use log::debug;
+use log::{debug, info, warn};
 use structopt::StructOpt;
 
+mod agent;
+mod breakpoint;
+mod coredump;
+mod elf;
+mod monitor;
 mod os;
+mod packets;
+mod record;
+mod serial;
+mod tls;
+mod trace;
+mod x86;
 
-use os::{Os, Registers, Target};
+pub use trace::FrameQuery;
+
+use breakpoint::Breakpoints;
+use os::{CoreFile, Interrupt, Launch, Os, Registers, Resume, Stdio, Target};
+use packets::{Input, Packets};
+use record::Recording;
+use trace::Trace;
 
 #[allow(unused)]
 const ERROR_PARSE_STRING: u8 = std::u8::MAX;
 #[allow(unused)]
 const ERROR_GET_PATH: u8 = std::u8::MAX - 1;
+const ERROR_NO_THREAD: u8 = std::u8::MAX - 2;
+const ERROR_NO_TLS: u8 = std::u8::MAX - 3;
+const ERROR_BAD_BYTECODE: u8 = std::u8::MAX - 4;
+const ERROR_NO_BREAKPOINT: u8 = std::u8::MAX - 5;
+const ERROR_NOT_COLLECTED: u8 = std::u8::MAX - 6;
+const ERROR_TRACE_LOADED: u8 = std::u8::MAX - 7;
+const ERROR_NO_CHECKPOINT: u8 = std::u8::MAX - 8;
+const ERROR_LAUNCH: u8 = std::u8::MAX - 9;
 
 #[derive(Debug, StructOpt)]
 pub struct Opt {
-    /// The address which to bind the server to
+    /// The address which to bind the server to, or the device path for serial
     #[structopt(short = "a", long = "addr", default_value = "127.0.0.1:64126")]
     pub addr: String,
     /// The type of address specified
-    #[structopt(short = "t", long = "type", default_value = "tcp", possible_values = &["tcp", "unix", "stdio"])]
+    #[structopt(short = "t", long = "type", default_value = "tcp", possible_values = &["tcp", "unix", "stdio", "serial"])]
     pub kind: String,
+    /// The baud rate of the serial device, if the type is serial
+    #[structopt(short = "b", long = "baud", default_value = "115200")]
+    pub baud: u32,
+    /// Exit after the first GDB session instead of waiting for a new one
+    #[structopt(long = "once")]
+    pub once: bool,
+    /// Run the program on this terminal, such as one of another window
+    #[structopt(long = "tty", parse(from_os_str))]
+    pub tty: Option<PathBuf>,
+    /// Run the program on a new pseudo-terminal, connected to the server's
+    /// own stdin and stdout
+    #[structopt(long = "new-tty", conflicts_with = "tty")]
+    pub new_tty: bool,
+    /// Set an environment variable for the program, as in NAME=VALUE
+    #[structopt(long = "env", number_of_values = 1, parse(try_from_str = parse_env))]
+    pub env: Vec<(String, String)>,
+    /// Run the program in this directory
+    #[structopt(long = "cwd", parse(from_os_str))]
+    pub cwd: Option<PathBuf>,
+    /// Launch the program through $SHELL, which expands the arguments
+    #[structopt(long = "startup-with-shell")]
+    pub startup_with_shell: bool,
+    /// Disable address space randomization of the program
+    #[structopt(long = "no-aslr")]
+    pub no_aslr: bool,
+    /// Redirect the program's stdin from this file
+    #[structopt(long = "stdin", parse(from_os_str))]
+    pub stdin: Option<PathBuf>,
+    /// Redirect the program's stdout to this file
+    #[structopt(long = "stdout", parse(from_os_str))]
+    pub stdout: Option<PathBuf>,
+    /// Redirect the program's stderr to this file
+    #[structopt(long = "stderr", parse(from_os_str))]
+    pub stderr: Option<PathBuf>,
+    /// Serve the frames of a trace file saved by `tsave`, for looking
+    /// through with `tfind` instead of collecting new ones
+    #[structopt(long = "trace-file", parse(from_os_str))]
+    pub trace_file: Option<PathBuf>,
+    /// Record everything the program does, one instruction at a time, so GDB
+    /// can run it backwards with `reverse-step` and `reverse-continue`. This
+    /// makes it run very slowly.
+    #[structopt(long = "record", conflicts_with = "trace-file")]
+    pub record: bool,
+    /// Write a core file to this path whenever the program stops for a
+    /// signal that would make it dump core, before GDB hears about it
+    #[structopt(long = "dump-core-on-crash", parse(from_os_str))]
+    pub dump_core_on_crash: Option<PathBuf>,
+    /// Serve this core file of the program instead of running it, for
+    /// looking at how it crashed
+    #[structopt(long = "core", parse(from_os_str), conflicts_with = "record")]
+    pub core: Option<PathBuf>,
+    /// Let the program run on its own, passing it every signal, and only
+    /// wait for GDB once it crashes with SIGSEGV, SIGABRT or SIGBUS. It's
+    /// left stopped where it crashed.
+    #[structopt(long = "wait-for-crash", conflicts_with = "core")]
+    pub wait_for_crash: bool,
     /// The program that should be debugged
     pub program: String,
     /// The arguments of the program
@@ -40,19 +133,686 @@ pub struct Opt {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub struct App {
-    tracee: Os,
+fn parse_env(var: &str) -> Result<(String, String), String> {
+    let eq = var.find('=').ok_or_else(|| format!("expected NAME=VALUE, got {}", var))?;
+    Ok((String::from(&var[..eq]), String::from(&var[eq + 1..])))
+}
+
+fn format_id(id: Id) -> String {
+    match id {
+        Id::Id(id) => format!("{:x}", id),
+        Id::All => String::from("-1"),
+        Id::Any => String::from("0"),
+    }
+}
+
+/// Convert a GDB signal number to the host's
+fn native_signal(signal: u8) -> Option<u8> {
+    Signal::from_u8(signal).and_then(Signal::to_libc).map(|s| s as u8)
+}
+
+/// Returns true if a vCont action for `id` applies to the thread `tid` of
+/// the process `pid`
+fn applies(pid: u32, id: &Option<ThreadId>, tid: u32) -> bool {
+    match id {
+        Some(ThreadId { pid: Id::Id(id), .. }) if *id != pid => false,
+        Some(ThreadId { tid: Id::Id(id), .. }) => *id == tid,
+        _ => true,
+    }
+}
+
+/// Returns true if the tracee stopped for a signal that dumps core by default
+fn crashed(status: StopReason) -> bool {
+    match status {
+        StopReason::Signal(signal) => matches!(
+            libc::c_int::from(signal),
+            libc::SIGQUIT | libc::SIGILL | libc::SIGABRT | libc::SIGBUS | libc::SIGFPE | libc::SIGSEGV | libc::SIGSYS
+        ),
+        _ => false,
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub struct App<T: Target> {
+    /// The process being debugged, which restarting a checkpoint replaces
+    tracee: RefCell<T>,
+    /// How to start the tracee again once it has exited
+    launch: RefCell<Launch>,
+    /// The connection of the current session, watched for interrupts while
+    /// the tracee runs
+    connection: RefCell<Option<Rc<dyn Interrupt>>>,
+    /// Where packets of the current session are written, for sending console
+    /// output while the tracee runs
+    writer: RefCell<Option<SharedWriter>>,
+    /// The qXfer:threads:read document, which is created once it's read from
+    /// the start and kept for the following chunks
+    threads_xml: RefCell<Vec<u8>>,
+    /// Whether GDB asked for non-stop mode, where threads run and stop on
+    /// their own and their stops are sent as notifications
+    non_stop: Cell<bool>,
+    /// Threads resumed in non-stop mode that GDB hasn't been told have
+    /// stopped, with how they were resumed
+    running: RefCell<BTreeMap<u32, Resume>>,
+    /// Running threads GDB asked to stop with `vCont;t`
+    stopping: RefCell<BTreeSet<u32>>,
+    /// Breakpoints inserted for GDB
+    breakpoints: RefCell<Breakpoints>,
+    /// Tracepoints and the frames they collected
+    trace: RefCell<Trace>,
+    /// What the tracee did, if it's being recorded for reverse execution
+    recording: RefCell<Option<Recording>>,
+    /// Stopped copies of the tracee to go back to, by their number
+    checkpoints: RefCell<BTreeMap<usize, T>>,
+    /// Where to write a core file when the tracee crashes, if anywhere
+    core_on_crash: Option<PathBuf>,
+    /// Whether packets are printed to stderr, shared with the connection
+    debug_packets: Rc<Cell<bool>>,
+    /// Set by `monitor exit`, to stop serving once GDB disconnects
+    exit_requested: Cell<bool>,
     fs: LibcFS,
 }
-impl Handler for App {
+impl<T: Target> App<T> {
+    fn new(tracee: T, launch: Launch, trace: Trace, recording: Option<Recording>, core_on_crash: Option<PathBuf>) -> Self {
+        App {
+            tracee: RefCell::new(tracee),
+            launch: RefCell::new(launch),
+            connection: RefCell::new(None),
+            writer: RefCell::new(None),
+            threads_xml: RefCell::new(Vec::new()),
+            non_stop: Cell::new(false),
+            running: RefCell::new(BTreeMap::new()),
+            stopping: RefCell::new(BTreeSet::new()),
+            breakpoints: RefCell::new(Breakpoints::default()),
+            trace: RefCell::new(trace),
+            recording: RefCell::new(recording),
+            checkpoints: RefCell::new(BTreeMap::new()),
+            core_on_crash,
+            debug_packets: Rc::new(Cell::new(false)),
+            exit_requested: Cell::new(false),
+            fs: LibcFS::default(),
+        }
+    }
+
+    fn tracee(&self) -> Ref<'_, T> {
+        self.tracee.borrow()
+    }
+
+    /// Print text to GDB's console with an `O` packet, which it accepts in
+    /// place of the reply to a vCont
+    fn console_output(&self, text: &str) {
+        let mut writer = match &*self.writer.borrow() {
+            Some(writer) => writer.clone(),
+            None => return,
+        };
+        let mut packet = String::from("O");
+        for byte in text.bytes() {
+            packet.push_str(&format!("{:02x}", byte));
+        }
+        if let Err(err) = packets::write_packet(&mut writer, &packet) {
+            warn!("Failed to send console output: {}", err);
+        }
+    }
+    /// Describe all threads in the format of qXfer:threads:read
+    fn threads_xml(&self) -> Result<String> {
+        let mut xml = String::from("<?xml version=\"1.0\"?>\n<threads>\n");
+        for thread in self.thread_list(true)? {
+            xml.push_str(&format!("  <thread id=\"p{}.{}\"", format_id(thread.pid), format_id(thread.tid)));
+            if let Id::Id(tid) = thread.tid {
+                let info = self.tracee().thread_info(tid);
+                if let Some(core) = info.core {
+                    xml.push_str(&format!(" core=\"{}\"", core));
+                }
+                if let Some(name) = info.name {
+                    xml.push_str(&format!(" name=\"{}\"", escape_xml(&name)));
+                }
+            }
+            xml.push_str("/>\n");
+        }
+        xml.push_str("</threads>\n");
+        Ok(xml)
+    }
+
+    /// Handle `QEnvironmentHexEncoded`, setting a variable for the next launch
+    pub fn set_environment_variable(&self, name: &OsStr, value: &OsStr) {
+        self.launch.borrow_mut().env.insert(name.to_owned(), value.to_owned());
+    }
+
+    /// Handle `QEnvironmentUnset`, removing a variable for the next launch
+    pub fn unset_environment_variable(&self, name: &OsStr) {
+        self.launch.borrow_mut().env.remove(name);
+    }
+
+    /// Handle `QEnvironmentReset`, going back to the server's own environment
+    pub fn reset_environment(&self) {
+        self.launch.borrow_mut().env = std::env::vars_os().collect();
+    }
+
+    /// Handle `QStartupWithShell`, for the next launch
+    pub fn set_startup_with_shell(&self, enable: bool) {
+        self.launch.borrow_mut().startup_with_shell = enable;
+    }
+
+    /// Handle `QSetWorkingDir`, where no directory means the server's own
+    pub fn set_working_dir(&self, dir: Option<&Path>) {
+        self.launch.borrow_mut().cwd = dir.map(Path::to_owned);
+    }
+
+    /// Handle `vRun`, killing the tracee if it's still there and launching
+    /// the program again, with all GDB changed about how. The arguments
+    /// replace the old ones, and no program means the same one.
+    pub fn run(&self, program: Option<String>, args: Vec<String>) -> Result<()> {
+        {
+            let mut launch = self.launch.borrow_mut();
+            if let Some(program) = program {
+                launch.program = program;
+            }
+            launch.args = iter::once(launch.program.clone()).chain(args).collect();
+        }
+        if !self.tracee_gone() {
+            self.running.borrow_mut().clear();
+            self.stopping.borrow_mut().clear();
+            self.tracee().kill()?;
+        }
+        self.relaunch().map_err(|err| {
+            warn!("Failed to launch {}: {}", self.launch.borrow().program, err);
+            Error::Error(ERROR_LAUNCH)
+        })
+    }
+
+    /// Launch the program again in place of the tracee that's gone
+    fn relaunch(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.breakpoints.borrow_mut().forget();
+        if let Some(recording) = &mut *self.recording.borrow_mut() {
+            recording.clear();
+        }
+        let tracee = T::new(&self.launch.borrow())?;
+        *self.tracee.borrow_mut() = tracee;
+        Ok(())
+    }
+
+    /// Whether GDB is in non-stop mode
+    pub fn non_stop(&self) -> bool {
+        self.non_stop.get()
+    }
+
+    /// Handle `QNonStop`. Threads still running when it's switched off are
+    /// stopped, to be resumed together again. Recording runs the tracee one
+    /// thread at a time, so it's all-stop only.
+    pub fn set_non_stop(&self, enable: bool) -> Result<()> {
+        if enable && (!self.tracee().non_stop() || self.recording.borrow().is_some()) {
+            return Err(Error::Unimplemented);
+        }
+        if !enable {
+            self.tracee().stop_running()?;
+            self.running.borrow_mut().clear();
+            self.stopping.borrow_mut().clear();
+        }
+        self.non_stop.set(enable);
+        Ok(())
+    }
+
+    /// Handle `vCont` in non-stop mode, which starts and stops threads
+    /// without waiting for them. Threads that are running already keep
+    /// running, and `t` only stops those, reporting the stop like any other.
+    pub fn vcont_non_stop(&self, actions: &[(VCont, Option<ThreadId>)]) -> Result<()> {
+        let pid = self.tracee().pid();
+        let mut start = Vec::new();
+        let mut stop = Vec::new();
+        let mut running = self.running.borrow_mut();
+        for tid in self.tracee().threads() {
+            let action = match actions.iter().find(|(_, id)| applies(pid, id, tid)) {
+                Some((action, _)) => action,
+                None => continue,
+            };
+            debug!("Thread {} gets {:?}", tid, action);
+            if running.contains_key(&tid) {
+                if let VCont::Stop = action {
+                    stop.push(tid);
+                }
+                continue;
+            }
+            start.push((tid, match *action {
+                VCont::Stop => continue,
+                VCont::Continue => Resume::Continue(None),
+                VCont::ContinueWithSignal(signal) => Resume::Continue(native_signal(signal)),
+                // GDB checks the range itself, after each step
+                VCont::Step | VCont::RangeStep(_) => Resume::Step(None),
+                VCont::StepWithSignal(signal) => Resume::Step(native_signal(signal)),
+            }));
+        }
+
+        self.tracee().start_threads(&start)?;
+        self.tracee().stop_threads(&stop)?;
+        running.extend(start.iter().map(|&(tid, action)| (tid, action.without_signal())));
+        self.stopping.borrow_mut().extend(stop);
+        Ok(())
+    }
+
+    /// Check without waiting whether a thread resumed in non-stop mode
+    /// stopped for a reason GDB should know about, and return it with what
+    /// it stopped for. Breakpoints are passed like in vcont, with the thread
+    /// continuing if GDB needn't hear about them.
+    pub fn poll_non_stop(&self) -> Result<Option<(u32, StopReason)>> {
+        // Stops don't change the thread GDB selected
+        let selected = self.tracee().current_thread();
+        let result = self.poll_running();
+        if self.tracee().threads().contains(&selected) {
+            self.tracee().select_thread(selected)?;
+        }
+        result
+    }
+
+    fn poll_running(&self) -> Result<Option<(u32, StopReason)>> {
+        loop {
+            if !self.tracee().poll_stop()? {
+                // Threads that exited aren't waited for anymore
+                let threads = self.tracee().threads();
+                self.running.borrow_mut().retain(|tid, _| threads.contains(tid));
+                self.stopping.borrow_mut().retain(|tid| threads.contains(tid));
+                return Ok(None);
+            }
+            let tid = self.tracee().current_thread();
+            if self.tracee_gone() {
+                self.running.borrow_mut().clear();
+                self.stopping.borrow_mut().clear();
+                return Ok(Some((tid, self.stop_status())));
+            }
+
+            // New threads start out continuing, like their parent
+            let action = self.running.borrow_mut().remove(&tid).unwrap_or(Resume::Continue(None));
+            if matches!(action, Resume::Step(_)) || !self.pass_breakpoint()? {
+                self.stopping.borrow_mut().remove(&tid);
+                return Ok(Some((tid, self.non_stop_status())));
+            }
+            if self.stopping.borrow_mut().remove(&tid) {
+                // Past the breakpoint, and stopped as asked
+                return Ok(Some((tid, StopReason::Signal(0))));
+            }
+            self.tracee().start_threads(&[(tid, action)])?;
+            self.running.borrow_mut().insert(tid, action);
+        }
+    }
+
+    /// The status of the current thread in non-stop mode, where it can have
+    /// stopped without a signal because GDB asked it to
+    fn non_stop_status(&self) -> StopReason {
+        match self.tracee().status_native() {
+            StopReason::Signal(0) => StopReason::Signal(0),
+            _ => self.stop_status(),
+        }
+    }
+
+    /// Handle `?` in non-stop mode: the threads that are stopped, with what
+    /// the current one stopped for, or how the tracee exited
+    pub fn non_stop_statuses(&self) -> Vec<(u32, StopReason)> {
+        let current = self.tracee().current_thread();
+        if self.tracee_gone() {
+            return vec![(current, self.tracee().status())];
+        }
+        let running = self.running.borrow();
+        self.tracee().threads()
+            .into_iter()
+            .filter(|tid| !running.contains_key(tid))
+            .map(|tid| (tid, if tid == current { self.non_stop_status() } else { StopReason::Signal(0) }))
+            .collect()
+    }
+
+    /// Handle `monitor set inferior-tty`, for the next launch. GDB's own `set
+    /// inferior-tty` has no packet of its own.
+    pub fn set_inferior_tty(&self, tty: Option<PathBuf>) {
+        self.launch.borrow_mut().tty = tty;
+    }
+
+    /// Handle `qGetTLSAddr`, finding the thread's copy of the variable at the
+    /// offset within the TLS block of the module with the link map at `lm`
+    pub fn tls_address(&self, thread: ThreadId, offset: u64, lm: u64) -> Result<u64> {
+        let current = self.tracee().current_thread();
+        let tid = match thread.tid {
+            Id::Id(tid) => tid,
+            _ => current,
+        };
+        self.tracee().select_thread(tid)?;
+        let regs = self.tracee().getregs();
+        self.tracee().select_thread(current)?;
+        let tp = regs?.fs_base.ok_or(Error::Error(ERROR_NO_TLS))?;
+
+        let exe = self.tracee().path(self.tracee().pid() as usize)?;
+        tls::address(&*self.tracee(), Path::new(OsStr::from_bytes(&exe)), tp, offset, lm)
+    }
+
+    /// Handle `QTinit`, removing all tracepoints and what they collected
+    pub fn trace_init(&self) -> Result<()> {
+        self.trace.borrow_mut().init()?;
+        self.breakpoints.borrow_mut().untrace(&*self.tracee())
+    }
+
+    /// Handle `QTDP`, given everything after the colon, defining a tracepoint
+    /// or adding actions to one
+    pub fn define_tracepoint(&self, definition: &str) -> Result<()> {
+        self.trace.borrow_mut().define(definition)
+    }
+
+    /// Handle `QTStart`, inserting the tracepoints and starting to collect
+    pub fn start_trace(&self) -> Result<()> {
+        let addresses = self.trace.borrow_mut().start()?;
+        let mut breakpoints = self.breakpoints.borrow_mut();
+        for address in addresses {
+            breakpoints.trace(&*self.tracee(), address)?;
+        }
+        Ok(())
+    }
+
+    /// Handle `QTStop`, keeping what was collected to look at
+    pub fn stop_trace(&self) -> Result<()> {
+        self.trace.borrow_mut().stop();
+        self.breakpoints.borrow_mut().untrace(&*self.tracee())
+    }
+
+    /// Handle `QTSave`, writing the trace to a file on the server's side in
+    /// the format of GDB's `tsave`
+    pub fn save_trace(&self, path: &Path) -> Result<()> {
+        let result = File::create(path).and_then(|file| {
+            let mut file = BufWriter::new(file);
+            self.trace.borrow().save(&mut file)?;
+            file.flush()
+        });
+        result.map_err(|err| Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8))
+    }
+
+    /// Handle `qTStatus`
+    pub fn trace_status(&self) -> String {
+        self.trace.borrow().status()
+    }
+
+    /// Handle `QTFrame`, selecting the frame that registers and memory are
+    /// read from until `FrameQuery::Number(None)` goes back to the tracee.
+    /// Returns the number of the frame and of its tracepoint.
+    pub fn select_trace_frame(&self, query: FrameQuery) -> Option<(usize, u64)> {
+        self.trace.borrow_mut().select(query)
+    }
+
+    /// Deal with the current thread having stopped at a breakpoint, if it
+    /// did. Tracepoints collect what they want, and breakpoints whose
+    /// conditions don't hold, or which only run commands, are stepped over
+    /// without bothering GDB. Returns true if the thread can carry on.
+    fn pass_breakpoint(&self) -> Result<bool> {
+        let mut breakpoints = self.breakpoints.borrow_mut();
+        let address = match breakpoints.hit(&*self.tracee())? {
+            Some(address) => address,
+            None => return Ok(false),
+        };
+        let mut trace = self.trace.borrow_mut();
+        let stepping = trace.hit(&*self.tracee(), address)?;
+        let mut output = |text: &str| self.console_output(text);
+        let stop = breakpoints.should_stop(&*self.tracee(), address, &mut output)?
+            || !breakpoints.step_over(&*self.tracee(), address, None)?
+            || !trace.while_stepping(&*self.tracee(), &breakpoints, &stepping)?;
+        // A trace that just ended has no more use for its breakpoints
+        if !trace.running() {
+            breakpoints.untrace(&*self.tracee())?;
+        }
+        Ok(!stop)
+    }
+
+    /// Handle `bs`, undoing the last recorded instruction
+    pub fn reverse_step(&self) -> Result<StopReason> {
+        let mut recording = self.recording.borrow_mut();
+        let recording = recording.as_mut().ok_or(Error::Unimplemented)?;
+        recording.step_back(&*self.tracee(), &mut self.breakpoints.borrow_mut())?;
+        Ok(StopReason::Signal(Signal::SIGTRAP as u8))
+    }
+
+    /// Handle `bc`, undoing recorded instructions until the current thread
+    /// is back at a breakpoint that stops, or the recording is at its start
+    pub fn reverse_continue(&self) -> Result<StopReason> {
+        let mut recording = self.recording.borrow_mut();
+        let recording = recording.as_mut().ok_or(Error::Unimplemented)?;
+        let mut breakpoints = self.breakpoints.borrow_mut();
+        let mut output = |text: &str| self.console_output(text);
+        while recording.step_back(&*self.tracee(), &mut breakpoints)? {
+            match self.tracee().getregs()?.rip {
+                Some(rip) if breakpoints.contains(rip) && breakpoints.should_stop(&*self.tracee(), rip, &mut output)? => break,
+                _ => (),
+            }
+            if os::interrupted(self.connection.borrow().as_deref()) {
+                return Ok(StopReason::Signal(Signal::SIGINT as u8));
+            }
+        }
+        Ok(StopReason::Signal(Signal::SIGTRAP as u8))
+    }
+
+    /// Run one thread of the tracee as requested by vCont, but one recorded
+    /// instruction at a time. All others stay stopped. Reaching a breakpoint
+    /// stops it like hitting one would, but tracepoints don't collect.
+    fn run_recorded(
+        &self,
+        recording: &mut Recording,
+        resume: &[(u32, Resume)],
+        range_step: Option<(u32, Range<u64>)>,
+    ) -> Result<StopReason> {
+        let current = self.tracee().current_thread();
+        let (tid, mut action, range) = match range_step {
+            Some((tid, range)) => (tid, Resume::Step(None), Some(range)),
+            None => match resume.iter().find(|&&(tid, _)| tid == current).or_else(|| resume.first()) {
+                Some(&(tid, action)) => (tid, action, None),
+                None => return Ok(self.tracee().status()),
+            },
+        };
+        self.tracee().select_thread(tid)?;
+
+        let breakpoints = self.breakpoints.borrow();
+        let mut output = |text: &str| self.console_output(text);
+        loop {
+            let signal = match action {
+                Resume::Continue(signal) | Resume::Step(signal) => signal,
+            };
+            if !recording.step(&*self.tracee(), &breakpoints, signal)? {
+                break;
+            }
+            action = action.without_signal();
+
+            let rip = self.tracee().getregs()?.rip.unwrap_or(0);
+            if breakpoints.contains(rip) && breakpoints.should_stop(&*self.tracee(), rip, &mut output)? {
+                break;
+            }
+            match (action, &range) {
+                (_, Some(range)) if !range.contains(&rip) => break,
+                (Resume::Step(_), None) => break,
+                _ => (),
+            }
+            if os::interrupted(self.connection.borrow().as_deref()) {
+                return Ok(StopReason::Signal(Signal::SIGINT as u8));
+            }
+        }
+
+        Ok(self.stop_status())
+    }
+
+    /// Handle `monitor checkpoint`, keeping a copy of the tracee as it is now
+    /// to restart from later. Returns its number.
+    pub fn checkpoint(&self) -> Result<usize> {
+        let checkpoint = self.tracee().fork()?;
+        // It stays stopped, but breakpoints must not be baked into it
+        self.breakpoints.borrow().remove_from(&checkpoint)?;
+
+        let mut checkpoints = self.checkpoints.borrow_mut();
+        let number = checkpoints.keys().next_back().map_or(1, |last| last + 1);
+        checkpoints.insert(number, checkpoint);
+        Ok(number)
+    }
+
+    /// Handle `monitor restart`, replacing the tracee with a new copy of the
+    /// checkpoint, which is kept to restart from again. The copy goes by the
+    /// IDs of the tracee it replaces, which GDB keeps using.
+    pub fn restart_checkpoint(&self, number: usize) -> Result<()> {
+        let tracee = match self.checkpoints.borrow().get(&number) {
+            Some(checkpoint) => checkpoint.fork()?,
+            None => return Err(Error::Error(ERROR_NO_CHECKPOINT)),
+        };
+        self.breakpoints.borrow().insert_into(&tracee)?;
+        tracee.take_over(&self.tracee());
+
+        if !self.tracee_gone() {
+            self.tracee().kill()?;
+        }
+        *self.tracee.borrow_mut() = tracee;
+        // What was recorded happened to the old tracee
+        if let Some(recording) = &mut *self.recording.borrow_mut() {
+            recording.clear();
+        }
+        Ok(())
+    }
+
+    /// Handle `monitor delete checkpoint`, killing the copy
+    pub fn delete_checkpoint(&self, number: usize) -> Result<()> {
+        let checkpoint = self.checkpoints.borrow_mut().remove(&number);
+        checkpoint.ok_or(Error::Error(ERROR_NO_CHECKPOINT))?.kill()
+    }
+
+    /// Handle `monitor info checkpoints`, describing each checkpoint
+    pub fn checkpoints_info(&self) -> String {
+        let mut info = String::new();
+        for (number, checkpoint) in &*self.checkpoints.borrow() {
+            info.push_str(&format!("{} process {}", number, checkpoint.pid()));
+            if let Ok(Registers { rip: Some(rip), .. }) = checkpoint.getregs() {
+                info.push_str(&format!(" at {:#x}", rip));
+            }
+            info.push('\n');
+        }
+        if info.is_empty() {
+            info.push_str("No checkpoints.\n");
+        }
+        info
+    }
+
+    /// Handle `monitor gcore`, writing a core file of the tracee
+    pub fn dump_core(&self, path: &Path) -> Result<()> {
+        let command = self.launch.borrow().args.join(" ");
+        let result = File::create(path).map(BufWriter::new).map_err(|err| {
+            Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8)
+        });
+        let mut file = result?;
+        coredump::write(&*self.tracee(), &self.breakpoints.borrow(), &command, &mut file)?;
+        file.flush().map_err(|err| Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8))
+    }
+
+    /// The status to report once the tracee stopped, after writing a core
+    /// file if it crashed and one was asked for
+    fn stop_status(&self) -> StopReason {
+        let status = self.tracee().status();
+        debug!("vCont sending status {:?}", status);
+        self.save_crash();
+        status
+    }
+
+    /// Write a core file if the tracee crashed and one was asked for
+    fn save_crash(&self) {
+        if let Some(path) = &self.core_on_crash {
+            if crashed(self.tracee().status_native()) {
+                match self.dump_core(path) {
+                    Ok(()) => info!("Saved core file {}", path.display()),
+                    Err(err) => warn!("Failed to save core file {}: {:?}", path.display(), err),
+                }
+            }
+        }
+    }
+
+    /// Let the tracee run on its own until it crashes, passing it every other
+    /// signal it gets on the way, and leave it stopped where it crashed.
+    /// Returns false if it exited instead.
+    fn run_until_crash(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let tracee = self.tracee();
+        let mut signal = None;
+        let name = loop {
+            // Only the thread that stopped has a signal to pass on
+            let current = tracee.current_thread();
+            let actions: Vec<_> = tracee.threads()
+                .into_iter()
+                .map(|tid| (tid, Resume::Continue(if tid == current { signal } else { None })))
+                .collect();
+            tracee.resume_threads(&actions, None)
+                .map_err(|err| format!("failed to resume the program: {:?}", err))?;
+
+            signal = match tracee.status_native() {
+                StopReason::Signal(signal) => match libc::c_int::from(signal) {
+                    libc::SIGSEGV => break "SIGSEGV",
+                    libc::SIGABRT => break "SIGABRT",
+                    libc::SIGBUS => break "SIGBUS",
+                    _ => Some(signal),
+                },
+                StopReason::ExitedWithSignal(_, signal) => {
+                    eprintln!("Program was killed by signal {} without crashing", signal);
+                    return Ok(false);
+                },
+                _ => {
+                    eprintln!("Program exited without crashing");
+                    return Ok(false);
+                },
+            };
+        };
+        eprintln!("Program crashed with {} in thread {}, waiting for GDB", name, tracee.current_thread());
+        drop(tracee);
+        self.save_crash();
+        Ok(true)
+    }
+
+    /// Returns true if the tracee is no longer alive, or no longer ours
+    fn tracee_gone(&self) -> bool {
+        self.tracee().detached() || matches!(
+            self.tracee().status_native(),
+            StopReason::Exited(..) | StopReason::ExitedWithSignal(..)
+        )
+    }
+}
+// Implemented for a reference so the same App can outlive several GDB sessions
+impl<T: Target> Handler for &App<T> {
     fn attached(&self, _pid: Option<u64>) -> Result<ProcessType> {
         Ok(ProcessType::Created)
     }
+    fn detach(&self, _pid: Option<u64>) -> Result<()> {
+        // Threads left running in non-stop mode are stopped to detach
+        self.set_non_stop(false)?;
+        // There's no removing breakpoints after the session once detached, so
+        // take out any that GDB left in
+        self.breakpoints.borrow_mut().clear(&*self.tracee())?;
+        self.tracee().detach()
+    }
+    fn kill(&self, _pid: Option<u64>) -> Result<()> {
+        self.running.borrow_mut().clear();
+        self.stopping.borrow_mut().clear();
+        self.tracee().kill()
+    }
     fn halt_reason(&self) -> Result<StopReason> {
-        Ok(self.tracee.status())
+        Ok(self.tracee().status())
+    }
+    fn invoke(&self, command: &[u8]) -> Result<String> {
+        // The output is streamed as console output instead of returned
+        monitor::run(self, &String::from_utf8_lossy(command))?;
+        Ok(String::new())
     }
     fn read_general_registers(&self) -> Result<Vec<u8>> {
-        let regs = self.tracee.getregs()?;
+        if let Some(frame) = self.trace.borrow().selected() {
+            if frame.registers.is_empty() {
+                return Err(Error::Error(ERROR_NOT_COLLECTED));
+            }
+            return Ok(frame.registers.clone());
+        }
+
+        let regs = self.tracee().getregs()?;
 
         let mut bytes = Vec::new();
         regs.encode(&mut bytes);
@@ -61,78 +821,193 @@ impl Handler for App {
     }
     fn write_general_registers(&self, content: &[u8]) -> Result<()> {
         let regs = Registers::decode(content);
-        self.tracee.setregs(&regs)?;
+        self.tracee().setregs(&regs)?;
         Ok(())
     }
     fn read_memory(&self, region: MemoryRegion) -> Result<Vec<u8>> {
         let mut buf = vec![0; region.length as usize];
-        self.tracee.getmem(region.address as usize, &mut buf)?;
+        if let Some(frame) = self.trace.borrow().selected() {
+            let len = frame.read(region.address, &mut buf);
+            if len == 0 {
+                return Err(Error::Error(ERROR_NOT_COLLECTED));
+            }
+            buf.truncate(len);
+            return Ok(buf);
+        }
+
+        self.tracee().getmem(region.address as usize, &mut buf)?;
+        self.breakpoints.borrow().hide(region.address, &mut buf);
         Ok(buf)
     }
     fn write_memory(&self, address: u64, bytes: &[u8]) -> Result<()> {
-        self.tracee.setmem(address as usize, bytes)?;
+        self.breakpoints.borrow_mut().write(&*self.tracee(), address, bytes)
+    }
+    fn insert_software_breakpoint(&self, breakpoint: Breakpoint) -> Result<()> {
+        let conditions = breakpoint.conditions
+            .unwrap_or_default()
+            .into_iter()
+            .map(|condition| condition.bytecode)
+            .collect();
+        let commands = breakpoint.commands
+            .unwrap_or_default()
+            .into_iter()
+            .map(|command| command.bytecode)
+            .collect();
+        self.breakpoints.borrow_mut().insert(&*self.tracee(), breakpoint.addr, conditions, commands)
+    }
+    fn remove_software_breakpoint(&self, breakpoint: Breakpoint) -> Result<()> {
+        self.breakpoints.borrow_mut().remove(&*self.tracee(), breakpoint.addr)
+    }
+    fn set_address_randomization(&self, enable: bool) -> Result<()> {
+        // The tracee is already running by the time GDB connects, so this
+        // only affects it once restarted
+        self.launch.borrow_mut().disable_aslr = !enable;
         Ok(())
     }
     fn query_supported_features(&self) -> Vec<String> {
-        vec![
+        let mut features = vec![
             String::from("qXfer:features:read+"),
             String::from("qXfer:exec-file:read+"),
-        ]
+            String::from("qXfer:threads:read+"),
+            String::from("QDisableRandomization+"),
+            String::from("QEnvironmentHexEncoded+"),
+            String::from("QEnvironmentUnset+"),
+            String::from("QEnvironmentReset+"),
+            String::from("QSetWorkingDir+"),
+            String::from("QStartupWithShell+"),
+            String::from("ConditionalBreakpoints+"),
+            String::from("BreakpointCommands+"),
+            String::from("ConditionalTracepoints+"),
+            String::from("tracenz+"),
+        ];
+        if self.recording.borrow().is_some() {
+            features.push(String::from("ReverseStep+"));
+            features.push(String::from("ReverseContinue+"));
+        } else if self.tracee().non_stop() {
+            features.push(String::from("QNonStop+"));
+        }
+        features
     }
     fn query_supported_vcont(&self) -> Result<Cow<'static, [VContFeature]>> {
-        Ok(Cow::Borrowed(&[
+        let mut features = vec![
             VContFeature::Continue,
             VContFeature::ContinueWithSignal,
             VContFeature::Step,
             VContFeature::StepWithSignal,
             VContFeature::RangeStep,
-        ]))
+        ];
+        if self.tracee().non_stop() {
+            features.push(VContFeature::Stop);
+        }
+        Ok(Cow::Owned(features))
     }
     fn thread_list(&self, reset: bool) -> Result<Vec<ThreadId>> {
         if reset {
-            let id = Id::Id(self.tracee.pid());
-            Ok(vec![
-                ThreadId { pid: id, tid: id },
-            ])
+            let pid = Id::Id(self.tracee().pid());
+            Ok(self.tracee().threads()
+                .into_iter()
+                .map(|tid| ThreadId { pid, tid: Id::Id(tid) })
+                .collect())
         } else {
             Ok(Vec::new())
         }
     }
+    fn current_thread(&self) -> Result<Option<ThreadId>> {
+        Ok(Some(ThreadId {
+            pid: Id::Id(self.tracee().pid()),
+            tid: Id::Id(self.tracee().current_thread()),
+        }))
+    }
+    fn set_current_thread(&self, purpose: SetThreadFor, thread: ThreadId) -> Result<()> {
+        // Continuing is always done through vCont, which names its threads
+        match (purpose, thread.tid) {
+            (SetThreadFor::ReadRegister, Id::Id(tid)) => self.tracee().select_thread(tid),
+            _ => Ok(()),
+        }
+    }
+    fn ping_thread(&self, thread: ThreadId) -> Result<()> {
+        match thread.tid {
+            Id::Id(tid) if !self.tracee().threads().contains(&tid) => Err(Error::Error(ERROR_NO_THREAD)),
+            _ => Ok(()),
+        }
+    }
+    fn thread_info(&self, thread: ThreadId) -> Result<String> {
+        let tid = match thread.tid {
+            Id::Id(tid) => tid,
+            _ => return Err(Error::Unimplemented),
+        };
+        let info = self.tracee().thread_info(tid);
+
+        let mut extra = Vec::new();
+        if let Some(state) = info.state {
+            extra.push(String::from(state));
+        }
+        if let Some(core) = info.core {
+            extra.push(format!("core {}", core));
+        }
+        Ok(extra.join(", "))
+    }
     fn vcont(&self, actions: Vec<(VCont, Option<ThreadId>)>) -> Result<StopReason> {
-        for (cmd, id) in &actions {
-            let id = id.unwrap_or(ThreadId { pid: Id::All, tid: Id::All });
-            debug!("Continuing thread: {:?}", id);
-            debug!("Continuing PID: {:?}", self.tracee.pid());
-            match (id.pid, id.tid) {
-                (Id::Id(pid), _) if pid != self.tracee.pid() => continue,
-                (_, Id::Id(tid)) if tid != self.tracee.pid() => continue,
-                (_, _) => (),
-            }
-            match *cmd {
-                VCont::Continue => {
-                    self.tracee.cont(None)?;
-                }
-                VCont::ContinueWithSignal(signal) => {
-                    self.tracee.cont(Signal::from_u8(signal).and_then(Signal::to_libc).map(|s| s as u8))?;
-                }
-                VCont::Step => {
-                    self.tracee.step(None)?;
-                }
-                VCont::StepWithSignal(signal) => {
-                    self.tracee.step(Signal::from_u8(signal).and_then(Signal::to_libc).map(|s| s as u8))?;
-                }
+        let pid = self.tracee().pid();
+
+        // Each thread gets the leftmost action that applies to it, and the
+        // ones without any stay stopped, which is how GDB locks the scheduler
+        let mut resume = Vec::new();
+        let mut range_step = None;
+        for tid in self.tracee().threads() {
+            let action = match actions.iter().find(|(_, id)| applies(pid, id, tid)) {
+                Some((action, _)) => action,
+                None => continue,
+            };
+            debug!("Thread {} gets {:?}", tid, action);
+            resume.push((tid, match *action {
+                VCont::Continue => Resume::Continue(None),
+                VCont::ContinueWithSignal(signal) => Resume::Continue(native_signal(signal)),
+                VCont::Step => Resume::Step(None),
+                VCont::StepWithSignal(signal) => Resume::Step(native_signal(signal)),
                 VCont::RangeStep(ref range) => {
-                    // std::ops::Range<T: Copy> should probably also be Copy, but it isn't.
-                    self.tracee.resume(range.clone())?;
+                    // Other threads stay stopped while range stepping
+                    range_step = Some((tid, range.clone()));
+                    resume.clear();
+                    break;
                 }
                 _ => return Err(Error::Unimplemented),
+            }));
+        }
+
+        if let Some(recording) = &mut *self.recording.borrow_mut() {
+            return self.run_recorded(recording, &resume, range_step);
+        }
+
+        loop {
+            match range_step {
+                Some((tid, ref range)) => {
+                    self.tracee().select_thread(tid)?;
+                    // std::ops::Range<T: Copy> should probably also be Copy, but it isn't.
+                    self.tracee().resume(range.clone(), self.connection.borrow().as_deref())?;
+                },
+                None if resume.is_empty() => break,
+                None => self.tracee().resume_threads(&resume, self.connection.borrow().as_deref())?,
+            }
+
+            // A single-stepped thread can't have run into a breakpoint
+            let stopped = self.tracee().current_thread();
+            if resume.iter().any(|&(tid, action)| tid == stopped && matches!(action, Resume::Step(_))) {
+                break;
+            }
+
+            // Everything continues as requested after a breakpoint that
+            // GDB needn't hear about
+            if !self.pass_breakpoint()? {
+                break;
+            }
+            // Signals were delivered the first time around
+            for (_, action) in &mut resume {
+                *action = action.without_signal();
             }
-            break;
         }
 
-        let status = self.tracee.status();
-        debug!("vCont sending status {:?}", status);
-        Ok(status)
+        Ok(self.stop_status())
     }
     fn read_bytes(&self, object: String, annex: String, offset: u64, length: u64) -> Result<(Vec<u8>, bool)> {
         let transfer_bytes = |source: &[u8]| -> Result<(Vec<u8>, bool)> {
@@ -149,9 +1024,15 @@ impl Handler for App {
                 let target_xml = include_bytes!("../target-desc.xml");
                 transfer_bytes(&target_xml[..])
             },
+            ("threads", "") => {
+                if offset == 0 {
+                    *self.threads_xml.borrow_mut() = self.threads_xml()?.into_bytes();
+                }
+                transfer_bytes(&self.threads_xml.borrow())
+            },
             ("exec-file", pid) => {
                 let pid = usize::from_str_radix(pid, 16).map_err(|_| Error::Error(ERROR_PARSE_STRING))?;
-                let path = self.tracee.path(pid)?;
+                let path = self.tracee().path(pid)?;
                 transfer_bytes(&path[..])
             },
             _ => Err(Error::Unimplemented),
@@ -162,42 +1043,293 @@ impl Handler for App {
     }
 }
 
+/// The writing half of a GDB connection, shared between the packet loop and
+/// console output
+#[derive(Clone)]
+struct SharedWriter(Rc<RefCell<Box<dyn Write>>>);
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// One half of a GDB connection, which copies everything that goes through
+/// it to stderr while `monitor set debug-packets` is on
+struct PacketLog<S> {
+    inner: S,
+    /// Which way the data goes, from the server's point of view
+    arrow: &'static str,
+    enabled: Rc<Cell<bool>>,
+}
+impl<S> PacketLog<S> {
+    fn log(&self, data: &[u8]) {
+        if self.enabled.get() && !data.is_empty() {
+            eprintln!("{} {:?}", self.arrow, String::from_utf8_lossy(data));
+        }
+    }
+}
+impl<S: Read> Read for PacketLog<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.log(&buf[..len]);
+        Ok(len)
+    }
+}
+impl<S: Write> Write for PacketLog<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.log(&buf[..len]);
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Both halves of a GDB connection, and the file descriptor its input arrives on
+type Connection = (Box<dyn Read>, Box<dyn Write>, RawFd);
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Stdio,
+    Serial(File),
+}
+impl Listener {
+    fn bind(kind: &str, addr: &str, baud: u32) -> io::Result<Self> {
+        Ok(match kind {
+            "unix" => Listener::Unix(UnixListener::bind(addr)?),
+            "stdio" => Listener::Stdio,
+            "serial" => Listener::Serial(serial::open(addr, baud)?),
+            kind => {
+                assert_eq!(kind, "tcp");
+                Listener::Tcp(TcpListener::bind(addr)?)
+            },
+        })
+    }
+
+    /// Wait for the next GDB to connect. The reading half isn't buffered, as
+    /// that's left to `Input`.
+    fn accept(&self) -> io::Result<Connection> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let (writer, _addr) = listener.accept()?;
+                let fd = writer.as_raw_fd();
+                (Box::new(writer.try_clone()?), Box::new(BufWriter::new(writer)), fd)
+            },
+            Listener::Unix(listener) => {
+                let (writer, _addr) = listener.accept()?;
+                let fd = writer.as_raw_fd();
+                (Box::new(writer.try_clone()?), Box::new(BufWriter::new(writer)), fd)
+            },
+            Listener::Stdio => {
+                // Not io::stdin(), which would keep what it reads ahead to
+                // itself where interrupts can't be looked for
+                let stdin = unsafe { libc::dup(libc::STDIN_FILENO) };
+                if stdin == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                let stdin = unsafe { File::from_raw_fd(stdin) };
+                let fd = stdin.as_raw_fd();
+                (Box::new(stdin), Box::new(BufWriter::new(io::stdout())), fd)
+            },
+            Listener::Serial(device) => (
+                Box::new(device.try_clone()?),
+                Box::new(BufWriter::new(device.try_clone()?)),
+                device.as_raw_fd(),
+            ),
+        })
+    }
+}
+
+/// Shuffle data between the master side of a pseudo-terminal and the server's
+/// own stdin and stdout, in the background. The server's terminal, if any,
+/// is in raw mode for as long as the returned value is kept.
+#[cfg(target_os = "linux")]
+fn relay_pty(master: File) -> io::Result<Option<serial::RawMode>> {
+    use std::thread;
+
+    let raw_mode = serial::RawMode::new(io::stdin().as_raw_fd())?;
+
+    // SIGCHLD is left for the tracer to wait on
+    let mut output = master.try_clone()?;
+    thread::spawn(move || {
+        os::block_sigchld()?;
+        io::copy(&mut output, &mut io::stdout())
+    });
+
+    let mut input = master;
+    thread::spawn(move || {
+        os::block_sigchld()?;
+        io::copy(&mut io::stdin(), &mut input)
+    });
+
+    Ok(raw_mode)
+}
+#[cfg(not(target_os = "linux"))]
+fn relay_pty(_master: File) -> io::Result<Option<()>> {
+    Ok(None)
+}
+
+/// Allocate a pseudo-terminal for the program, returning the master side to
+/// relay and the path of the slave side. The slave side is kept open in
+/// `slave`, without becoming the server's controlling terminal.
+#[cfg(target_os = "linux")]
+fn new_tty(slave: &mut Option<File>) -> Result<(File, PathBuf), Box<dyn std::error::Error>> {
+    use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
+
+    let (master, path) = serial::openpty()?;
+    eprintln!("Program's terminal: {}", path);
+    *slave = Some(OpenOptions::new().read(true).custom_flags(libc::O_NOCTTY).open(&path)?);
+    Ok((master, PathBuf::from(path)))
+}
+#[cfg(not(target_os = "linux"))]
+fn new_tty(_slave: &mut Option<File>) -> Result<(File, PathBuf), Box<dyn std::error::Error>> {
+    Err("--new-tty isn't supported on this platform".into())
+}
+
 pub fn main(mut opt: Opt) -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
     opt.args.insert(0, opt.program.clone());
 
-    let stdin = io::stdin();
-    let stdout = io::stdout();
+    let bind = {
+        let (kind, addr, baud) = (opt.kind.clone(), opt.addr.clone(), opt.baud);
+        move || Listener::bind(&kind, &addr, baud)
+    };
 
-    let (mut reader, mut writer): (Box<dyn Read>, Box<dyn Write>) = if opt.kind == "unix" {
-        let listener = UnixListener::bind(opt.addr)?;
-        let (writer, _addr) = listener.accept()?;
-        (
-            Box::new(BufReader::new(writer.try_clone()?)),
-            Box::new(BufWriter::new(writer)),
-        )
-    } else if opt.kind == "stdio" {
-        (
-            Box::new(stdin.lock()),
-            Box::new(BufWriter::new(stdout.lock())),
-        )
+    // There's no way to get a second GDB on the other end of our stdio
+    let once = opt.once || opt.kind == "stdio";
+
+    let mut tty = opt.tty;
+    // Keep the slave side open for as long as the server runs, so the relay
+    // survives the program exiting and being restarted
+    let mut _slave = None;
+    let mut master = None;
+    if opt.new_tty {
+        if opt.kind == "stdio" {
+            return Err("--new-tty relays through stdio, which already carries the protocol".into());
+        }
+        let (pty, path) = new_tty(&mut _slave)?;
+        master = Some(pty);
+        tty = Some(path);
+    }
+
+    // When stdio carries the protocol, the program must not touch it. Any
+    // output is moved to stderr instead.
+    let (stdin, stdout) = if opt.kind == "stdio" && tty.is_none() {
+        (Stdio::Null, Stdio::Stderr)
     } else {
-        assert_eq!(opt.kind, "tcp");
-        let listener = TcpListener::bind(opt.addr)?;
-        let (writer, _addr) = listener.accept()?;
-        (
-            Box::new(BufReader::new(writer.try_clone()?)),
-            Box::new(BufWriter::new(writer)),
-        )
+        (Stdio::Inherit, Stdio::Inherit)
+    };
+    let launch = Launch {
+        program: opt.program,
+        args: opt.args,
+        env: std::env::vars_os()
+            .chain(opt.env.into_iter().map(|(name, value)| (name.into(), value.into())))
+            .collect(),
+        cwd: opt.cwd,
+        startup_with_shell: opt.startup_with_shell,
+        disable_aslr: opt.no_aslr,
+        tty,
+        stdin: opt.stdin.map(Stdio::File).unwrap_or(stdin),
+        stdout: opt.stdout.map(Stdio::File).unwrap_or(stdout),
+        stderr: opt.stderr.map(Stdio::File).unwrap_or(Stdio::Inherit),
     };
 
-    let tracee = Os::new(opt.program, opt.args)?;
+    let trace = match opt.trace_file {
+        Some(path) => Trace::load(&mut BufReader::new(File::open(path)?))?,
+        None => Trace::default(),
+    };
 
-    gdb_remote_protocol::process_packets_from(&mut reader, &mut writer, App {
-        tracee,
-        fs: LibcFS::default(),
-    });
+    let recording = if opt.record { Some(Recording::default()) } else { None };
+
+    match opt.core {
+        Some(path) => {
+            let tracee = CoreFile::open(&path, &launch.program)?;
+            let _raw_mode = master.map(relay_pty).transpose()?;
+            serve(App::new(tracee, launch, trace, recording, opt.dump_core_on_crash), bind, once, false)
+        },
+        None => {
+            let tracee = Os::new(&launch)?;
+            // Only relayed once the program is launched, as a fork with the
+            // relay's threads running could leave the child stuck on a lock
+            // one of them held
+            let _raw_mode = master.map(relay_pty).transpose()?;
+            let app = App::new(tracee, launch, trace, recording, opt.dump_core_on_crash);
+            serve(app, bind, once, opt.wait_for_crash)
+        },
+    }
+}
+
+/// Serve one GDB session after another, until there should be no more. The
+/// listener is only bound once there's something to debug.
+fn serve<T, B>(app: App<T>, bind: B, once: bool, wait_for_crash: bool) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Target,
+    B: FnOnce() -> io::Result<Listener>,
+{
+    if wait_for_crash && !app.run_until_crash()? {
+        return Ok(());
+    }
+    let listener = bind()?;
+
+    loop {
+        let (reader, writer, fd) = listener.accept()?;
+        let reader = Box::new(PacketLog {
+            inner: reader,
+            arrow: "<-",
+            enabled: Rc::clone(&app.debug_packets),
+        });
+        let writer: Box<dyn Write> = Box::new(PacketLog {
+            inner: writer,
+            arrow: "->",
+            enabled: Rc::clone(&app.debug_packets),
+        });
+        let writer = SharedWriter(Rc::new(RefCell::new(writer)));
+
+        let input = Rc::new(Input::new(reader, fd));
+        *app.connection.borrow_mut() = Some(Rc::clone(&input) as Rc<dyn Interrupt>);
+        *app.writer.borrow_mut() = Some(writer.clone());
+        let mut reader = Packets::new(&app, &input, writer.clone());
+        gdb_remote_protocol::process_packets_from(&mut reader, writer, &app);
+        *app.connection.borrow_mut() = None;
+        *app.writer.borrow_mut() = None;
+
+        // Threads aren't left running without a GDB to tell about stops
+        if app.non_stop() {
+            if let Err(err) = app.set_non_stop(false) {
+                warn!("Failed to stop running threads: {:?}", err);
+            }
+        }
+
+        // GDB would have removed its breakpoints if it had the chance
+        if !app.tracee_gone() {
+            if let Err(err) = app.breakpoints.borrow_mut().clear(&*app.tracee()) {
+                warn!("Failed to remove breakpoints: {:?}", err);
+            }
+        }
+        // Tracing doesn't outlive the session either, unlike a loaded trace
+        if !app.trace.borrow().read_only() {
+            *app.trace.borrow_mut() = Trace::default();
+        }
+
+        if once || app.exit_requested.get() {
+            break;
+        }
+
+        if app.tracee_gone() {
+            info!("Tracee is gone, restarting {}", app.launch.borrow().program);
+            app.relaunch()?;
+            if wait_for_crash && !app.run_until_crash()? {
+                break;
+            }
+        }
+        info!("GDB disconnected, waiting for a new connection");
+    }
 
     Ok(())
 }
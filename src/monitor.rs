@@ -0,0 +1,442 @@
+//! Commands that GDB runs in the server with `monitor`, through qRcmd
+//! packets. What they print goes to GDB's console as it's produced.
+
+use crate::{
+    os::{SignalMasks, Target},
+    App, Result, ERROR_PARSE_STRING,
+};
+
+use gdb_remote_protocol::Error;
+
+use std::path::{Path, PathBuf};
+
+/// A monitor command, which is run with the words following its name
+struct Command<T: Target> {
+    name: &'static [&'static str],
+    /// The arguments it takes, as shown by `monitor help`
+    args: &'static str,
+    help: &'static str,
+    run: fn(&App<T>, &[&str]) -> Result<()>,
+}
+
+fn commands<T: Target>() -> Vec<Command<T>> {
+    vec![
+        Command {
+            name: &["help"],
+            args: "",
+            help: "List the monitor commands",
+            run: help,
+        },
+        Command {
+            name: &["set", "debug-packets"],
+            args: "on|off",
+            help: "Print every packet to the server's stderr",
+            run: set_debug_packets,
+        },
+        Command {
+            name: &["set", "inferior-tty"],
+            args: "[TTY]",
+            help: "Use TTY as the terminal of the program once it's restarted, or none",
+            run: set_inferior_tty,
+        },
+        Command {
+            name: &["info", "proc", "mappings"],
+            args: "",
+            help: "List the regions mapped into the program's address space",
+            run: info_proc_mappings,
+        },
+        Command {
+            name: &["info", "sigactions"],
+            args: "",
+            help: "List the signals the current thread doesn't leave alone",
+            run: info_sigactions,
+        },
+        Command {
+            name: &["checkpoint"],
+            args: "",
+            help: "Keep a copy of the program as it is now, to restart later",
+            run: checkpoint,
+        },
+        Command {
+            name: &["restart"],
+            args: "N",
+            help: "Go back to checkpoint N",
+            run: restart,
+        },
+        Command {
+            name: &["delete", "checkpoint"],
+            args: "N",
+            help: "Forget checkpoint N",
+            run: delete_checkpoint,
+        },
+        Command {
+            name: &["info", "checkpoints"],
+            args: "",
+            help: "List the checkpoints",
+            run: info_checkpoints,
+        },
+        Command {
+            name: &["gcore"],
+            args: "[FILE]",
+            help: "Write a core file of the program, to core.PID by default",
+            run: gcore,
+        },
+        Command {
+            name: &["exit"],
+            args: "",
+            help: "Stop the server, and the program with it, once GDB disconnects",
+            run: exit,
+        },
+    ]
+}
+
+/// Run the command line of a qRcmd packet
+pub fn run<T: Target>(app: &App<T>, line: &str) -> Result<()> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    // The longest name wins, so `info checkpoints` isn't taken for `info`
+    let command = commands().into_iter()
+        .filter(|command| words.starts_with(command.name))
+        .max_by_key(|command| command.name.len());
+    let command = match command {
+        Some(command) => command,
+        None => {
+            app.console_output(&format!("Unknown monitor command \"{}\", see \"monitor help\"\n", line.trim()));
+            return Ok(());
+        },
+    };
+
+    match (command.run)(app, &words[command.name.len()..]) {
+        Err(Error::Error(ERROR_PARSE_STRING)) => {
+            app.console_output(&format!("Usage: monitor {}\n", usage(&command)));
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+fn usage<T: Target>(command: &Command<T>) -> String {
+    let mut usage = command.name.join(" ");
+    if !command.args.is_empty() {
+        usage.push(' ');
+        usage.push_str(command.args);
+    }
+    usage
+}
+
+fn no_args(args: &[&str]) -> Result<()> {
+    if !args.is_empty() {
+        return Err(Error::Error(ERROR_PARSE_STRING));
+    }
+    Ok(())
+}
+
+fn number(args: &[&str]) -> Result<usize> {
+    match args {
+        [number] => number.parse().map_err(|_| Error::Error(ERROR_PARSE_STRING)),
+        _ => Err(Error::Error(ERROR_PARSE_STRING)),
+    }
+}
+
+fn help<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    no_args(args)?;
+    let commands = commands::<T>();
+    let usages: Vec<String> = commands.iter().map(usage).collect();
+    let width = usages.iter().map(String::len).max().unwrap_or(0);
+
+    let mut text = String::from("The following monitor commands are supported:\n");
+    for (command, usage) in commands.iter().zip(&usages) {
+        text.push_str(&format!("  {:width$}  {}\n", usage, command.help, width = width));
+    }
+    app.console_output(&text);
+    Ok(())
+}
+
+fn set_debug_packets<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    let enabled = match args {
+        ["on"] => true,
+        ["off"] => false,
+        _ => return Err(Error::Error(ERROR_PARSE_STRING)),
+    };
+    app.debug_packets.set(enabled);
+    app.console_output(&format!("Packet debugging output {}\n", if enabled { "enabled" } else { "disabled" }));
+    Ok(())
+}
+
+fn set_inferior_tty<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    let tty = match args {
+        [] => None,
+        [tty] => Some(PathBuf::from(tty)),
+        _ => return Err(Error::Error(ERROR_PARSE_STRING)),
+    };
+    app.set_inferior_tty(tty);
+    Ok(())
+}
+
+fn info_proc_mappings<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    no_args(args)?;
+    let tracee = app.tracee();
+    let mut text = format!("process {}\nMapped address spaces:\n\n", tracee.pid());
+    text.push_str(&format!(
+        "{:>18} {:>18} {:>10} {:>10} {:5} {}\n",
+        "Start Addr", "End Addr", "Size", "Offset", "Perms", "objfile"
+    ));
+    for mapping in tracee.mappings()? {
+        let perms: String = [
+            (mapping.read, 'r'),
+            (mapping.write, 'w'),
+            (mapping.execute, 'x'),
+        ]
+        .iter()
+        .map(|&(set, flag)| if set { flag } else { '-' })
+        .chain(Some(if mapping.shared { 's' } else { 'p' }))
+        .collect();
+        text.push_str(&format!(
+            "{:>#18x} {:>#18x} {:>#10x} {:>#10x} {:5} {}\n",
+            mapping.start,
+            mapping.end,
+            mapping.end - mapping.start,
+            mapping.offset,
+            perms,
+            mapping.name
+        ));
+    }
+    app.console_output(&text);
+    Ok(())
+}
+
+/// The name of a signal, by its number on the host
+fn signal_name(signal: libc::c_int) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGIO => "SIGIO",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("SIG{}", signal),
+    };
+    String::from(name)
+}
+
+fn info_sigactions<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    no_args(args)?;
+    let SignalMasks { pending, blocked, ignored, caught } = app.tracee().signal_masks()?;
+    let yes_no = |mask: u64, bit: u64| if mask & bit != 0 { "yes" } else { "no" };
+
+    let mut text = format!("{:10} {:8} {:8} {}\n", "Signal", "Action", "Blocked", "Pending");
+    let mut any = false;
+    for signal in 1..=64 {
+        let bit = 1 << (signal - 1);
+        if (pending | blocked | ignored | caught) & bit == 0 {
+            continue;
+        }
+        let action = if caught & bit != 0 {
+            "handled"
+        } else if ignored & bit != 0 {
+            "ignored"
+        } else {
+            "default"
+        };
+        text.push_str(&format!(
+            "{:10} {:8} {:8} {}\n",
+            signal_name(signal),
+            action,
+            yes_no(blocked, bit),
+            yes_no(pending, bit)
+        ));
+        any = true;
+    }
+    if !any {
+        text = String::from("All signals have their default action, and none are blocked or pending\n");
+    }
+    app.console_output(&text);
+    Ok(())
+}
+
+fn checkpoint<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    no_args(args)?;
+    let number = app.checkpoint()?;
+    app.console_output(&format!("Checkpoint {}\n", number));
+    Ok(())
+}
+
+fn restart<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    let number = number(args)?;
+    app.restart_checkpoint(number)?;
+    // The copy goes by the same IDs, so GDB has no idea anything changed
+    app.console_output(&format!(
+        "Back at checkpoint {}, run \"maintenance flush register-cache\" for GDB to see it\n",
+        number
+    ));
+    Ok(())
+}
+
+fn delete_checkpoint<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    app.delete_checkpoint(number(args)?)
+}
+
+fn info_checkpoints<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    no_args(args)?;
+    app.console_output(&app.checkpoints_info());
+    Ok(())
+}
+
+fn gcore<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    let path = match args {
+        [] => format!("core.{}", app.tracee().pid()),
+        [path] => String::from(*path),
+        _ => return Err(Error::Error(ERROR_PARSE_STRING)),
+    };
+    app.dump_core(Path::new(&path))?;
+    app.console_output(&format!("Saved corefile {}\n", path));
+    Ok(())
+}
+
+fn exit<T: Target>(app: &App<T>, args: &[&str]) -> Result<()> {
+    no_args(args)?;
+    app.exit_requested.set(true);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use crate::{
+        os::{Os, Target},
+        packets::tests::{app, Output},
+        App, SharedWriter, ERROR_NO_CHECKPOINT,
+    };
+
+    use gdb_remote_protocol::{Error, Handler, Id, MemoryRegion, StopReason, ThreadId, VCont};
+
+    use std::{cell::RefCell, path::Path, rc::Rc};
+
+    /// Run a command, returning what it printed to GDB's console
+    fn output(app: &App<Os>, line: &str) -> String {
+        let output = Output::default();
+        *app.writer.borrow_mut() = Some(SharedWriter(Rc::new(RefCell::new(Box::new(output.clone())))));
+        run(app, line).unwrap();
+
+        let packets = String::from_utf8(output.0.borrow().clone()).unwrap();
+        let mut text = Vec::new();
+        for packet in packets.split('$').skip(1) {
+            let hex = &packet[1..packet.find('#').unwrap()];
+            for i in (0..hex.len()).step_by(2) {
+                text.push(u8::from_str_radix(&hex[i..i + 2], 16).unwrap());
+            }
+        }
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn dispatch() {
+        let app = app();
+        assert_eq!(output(&app, "set debug-packets on"), "Packet debugging output enabled\n");
+        assert!(app.debug_packets.get());
+        assert_eq!(output(&app, "  set   debug-packets off "), "Packet debugging output disabled\n");
+        assert!(!app.debug_packets.get());
+
+        output(&app, "set inferior-tty /dev/null");
+        assert_eq!(app.launch.borrow().tty.as_deref(), Some(Path::new("/dev/null")));
+        output(&app, "set inferior-tty");
+        assert_eq!(app.launch.borrow().tty, None);
+
+        // The longest name that matches
+        assert_eq!(output(&app, "info checkpoints"), "No checkpoints.\n");
+        assert_eq!(output(&app, "checkpoint"), "Checkpoint 1\n");
+        assert!(output(&app, "info checkpoints").starts_with("1 process "));
+        assert_eq!(output(&app, "delete checkpoint 1"), "");
+        assert!(matches!(run(&app, "delete checkpoint 1"), Err(Error::Error(ERROR_NO_CHECKPOINT))));
+
+        let help = output(&app, "help");
+        assert!(help.contains("  restart N "));
+        assert!(help.contains("  info proc mappings "));
+
+        assert!(!app.exit_requested.get());
+        output(&app, "exit");
+        assert!(app.exit_requested.get());
+    }
+
+    #[test]
+    fn restart() {
+        let app = app();
+        let pid = app.tracee().pid();
+        let thread = Some(ThreadId { pid: Id::Id(pid), tid: Id::Id(pid) });
+        let step = || (&app).vcont(vec![(VCont::Step, thread)]).unwrap();
+        // Out of the execve, and into the dynamic loader
+        step();
+        step();
+
+        let regs = (&app).read_general_registers().unwrap();
+        let address = app.tracee().getregs().unwrap().rsp.unwrap() - 256;
+        let region = MemoryRegion { address, length: 6 };
+        (&app).write_memory(address, b"before").unwrap();
+        assert_eq!(output(&app, "checkpoint"), "Checkpoint 1\n");
+        step();
+        (&app).write_memory(address, b"after!").unwrap();
+
+        for _ in 0..2 {
+            let restored = output(&app, "restart 1");
+            assert!(restored.starts_with("Back at checkpoint 1, "), "{}", restored);
+            // Under the IDs GDB knows
+            assert_eq!(app.tracee().pid(), pid);
+            assert_eq!(app.tracee().threads(), [pid]);
+            assert_eq!(app.tracee().current_thread(), pid);
+            assert_eq!((&app).read_general_registers().unwrap(), regs);
+            assert_eq!((&app).read_memory(region).unwrap(), b"before");
+
+            assert_eq!(step(), StopReason::Signal(5));
+            assert_ne!((&app).read_general_registers().unwrap(), regs);
+            (&app).write_memory(address, b"after!").unwrap();
+        }
+
+        assert_eq!(output(&app, "delete checkpoint 1"), "");
+        assert_eq!(output(&app, "info checkpoints"), "No checkpoints.\n");
+        assert!(matches!(run(&app, "restart 1"), Err(Error::Error(ERROR_NO_CHECKPOINT))));
+        let status = (&app).vcont(vec![(VCont::Continue, None)]).unwrap();
+        assert_eq!(status, StopReason::Exited(u64::from(pid), 0));
+    }
+
+    #[test]
+    fn usage() {
+        let app = app();
+        assert_eq!(output(&app, "set debug-packets maybe"), "Usage: monitor set debug-packets on|off\n");
+        assert_eq!(output(&app, "restart"), "Usage: monitor restart N\n");
+        assert_eq!(output(&app, "delete checkpoint one"), "Usage: monitor delete checkpoint N\n");
+        assert_eq!(output(&app, "help me"), "Usage: monitor help\n");
+        assert_eq!(output(&app, "gcore a b"), "Usage: monitor gcore [FILE]\n");
+    }
+
+    #[test]
+    fn unknown() {
+        let app = app();
+        let unknown = "Unknown monitor command \"frobnicate  it\", see \"monitor help\"\n";
+        assert_eq!(output(&app, " frobnicate  it "), unknown);
+        // Only the start of a command
+        assert_eq!(output(&app, "info"), "Unknown monitor command \"info\", see \"monitor help\"\n");
+        assert_eq!(output(&app, "set"), "Unknown monitor command \"set\", see \"monitor help\"\n");
+    }
+}
@@ -0,0 +1,364 @@
+//! A core file served as if it were a tracee that can never run again, so
+//! GDB can look at how a program crashed without having the core locally
+
+use super::{Interrupt, Launch, Mapping, Registers, Resume, SignalMasks, Target, ThreadInfo};
+use crate::{
+    coredump::{NT_AUXV, NT_FILE, NT_FPREGSET, NT_PRPSINFO, NT_PRSTATUS},
+    elf::{self, ProgramHeader, PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE},
+    Result,
+};
+
+use std::{
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{self, prelude::*, SeekFrom},
+    path::Path,
+};
+
+use byteorder::{NativeEndian, ReadBytesExt};
+use gdb_remote_protocol::{Error, StopReason};
+
+// Where things are in struct elf_prstatus and struct elf_prpsinfo
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+const PRPSINFO_PID: usize = 24;
+
+fn io_error(err: io::Error) -> Error {
+    Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// One thread of the core file, with its notes as they were dumped
+struct Thread {
+    tid: u32,
+    prstatus: Vec<u8>,
+    fpregset: Option<Vec<u8>>,
+}
+
+pub struct CoreFile {
+    file: RefCell<File>,
+    program: String,
+    pid: u32,
+    signal: u8,
+    /// The thread that stopped comes first
+    threads: Vec<Thread>,
+    current: Cell<u32>,
+    segments: Vec<ProgramHeader>,
+    auxv: Option<Vec<u8>>,
+    /// Names of the mapped files by where they start
+    files: Vec<(u64, String)>,
+}
+
+/// Decode the registers from struct elf_prstatus, and struct
+/// user_fpregs_struct if there is one
+fn registers(prstatus: &[u8], fpregset: Option<&[u8]>) -> io::Result<Registers> {
+    let mut input = prstatus.get(PRSTATUS_REGS..).ok_or_else(|| invalid("prstatus note too short"))?;
+    let mut user_regs = [0; 27];
+    input.read_u64_into::<NativeEndian>(&mut user_regs)?;
+    let narrow = |reg: u64| Some(reg as u32);
+    // The same order that they're dumped in
+    let [
+        r15, r14, r13, r12, rbp, rbx, r11, r10, r9, r8, rax, rcx, rdx, rsi, rdi, _orig_rax,
+        rip, cs, eflags, rsp, ss, fs_base, gs_base, ds, es, fs, gs,
+    ] = user_regs;
+    let mut regs = Registers {
+        rax: Some(rax),
+        rbx: Some(rbx),
+        rcx: Some(rcx),
+        rdx: Some(rdx),
+        rsi: Some(rsi),
+        rdi: Some(rdi),
+        rbp: Some(rbp),
+        rsp: Some(rsp),
+        r8: Some(r8),
+        r9: Some(r9),
+        r10: Some(r10),
+        r11: Some(r11),
+        r12: Some(r12),
+        r13: Some(r13),
+        r14: Some(r14),
+        r15: Some(r15),
+        rip: Some(rip),
+        eflags: narrow(eflags),
+        cs: narrow(cs),
+        ss: narrow(ss),
+        ds: narrow(ds),
+        es: narrow(es),
+        fs: narrow(fs),
+        gs: narrow(gs),
+        fs_base: Some(fs_base),
+        gs_base: Some(gs_base),
+        ..Registers::default()
+    };
+
+    if let Some(mut input) = fpregset {
+        regs.fctrl = Some(u32::from(input.read_u16::<NativeEndian>()?));
+        regs.fstat = Some(u32::from(input.read_u16::<NativeEndian>()?));
+        regs.ftag = Some(u32::from(input.read_u16::<NativeEndian>()?));
+        regs.fop = Some(u32::from(input.read_u16::<NativeEndian>()?));
+        regs.fioff = Some(input.read_u64::<NativeEndian>()? as u32);
+        regs.fooff = Some(input.read_u64::<NativeEndian>()? as u32);
+        regs.mxcsr = Some(input.read_u32::<NativeEndian>()?);
+        let _mxcsr_mask = input.read_u32::<NativeEndian>()?;
+
+        let mut vector = [0; 8 + 16];
+        input.read_u128_into::<NativeEndian>(&mut vector)?;
+        let (st, xmm) = vector.split_at(8);
+        regs.st0 = Some(st[0]);
+        regs.st1 = Some(st[1]);
+        regs.st2 = Some(st[2]);
+        regs.st3 = Some(st[3]);
+        regs.st4 = Some(st[4]);
+        regs.st5 = Some(st[5]);
+        regs.st6 = Some(st[6]);
+        regs.st7 = Some(st[7]);
+        regs.xmm0 = Some(xmm[0]);
+        regs.xmm1 = Some(xmm[1]);
+        regs.xmm2 = Some(xmm[2]);
+        regs.xmm3 = Some(xmm[3]);
+        regs.xmm4 = Some(xmm[4]);
+        regs.xmm5 = Some(xmm[5]);
+        regs.xmm6 = Some(xmm[6]);
+        regs.xmm7 = Some(xmm[7]);
+        regs.xmm8 = Some(xmm[8]);
+        regs.xmm9 = Some(xmm[9]);
+        regs.xmm10 = Some(xmm[10]);
+        regs.xmm11 = Some(xmm[11]);
+        regs.xmm12 = Some(xmm[12]);
+        regs.xmm13 = Some(xmm[13]);
+        regs.xmm14 = Some(xmm[14]);
+        regs.xmm15 = Some(xmm[15]);
+    }
+    Ok(regs)
+}
+
+/// Read the note of mapped files, as where each starts and its name
+fn mapped_files(mut desc: &[u8]) -> io::Result<Vec<(u64, String)>> {
+    let count = desc.read_u64::<NativeEndian>()?;
+    let _page_size = desc.read_u64::<NativeEndian>()?;
+    let mut starts = Vec::new();
+    for _ in 0..count {
+        starts.push(desc.read_u64::<NativeEndian>()?);
+        let _end = desc.read_u64::<NativeEndian>()?;
+        let _offset = desc.read_u64::<NativeEndian>()?;
+    }
+    let names = desc.split(|&byte| byte == 0).map(|name| String::from_utf8_lossy(name).into_owned());
+    Ok(starts.into_iter().zip(names).collect())
+}
+
+impl CoreFile {
+    /// Load a core file that `program` dumped
+    pub fn open(path: &Path, program: &str) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let headers = elf::program_headers(&mut file)?;
+
+        let mut notes = Vec::new();
+        for header in headers.iter().filter(|header| header.kind == PT_NOTE) {
+            notes.extend(elf::notes(&elf::segment(&mut file, header)?)?);
+        }
+
+        let mut threads: Vec<Thread> = Vec::new();
+        let mut pid = None;
+        let mut auxv = None;
+        let mut files = Vec::new();
+        for note in notes.into_iter().filter(|note| note.name == "CORE") {
+            let field = |offset: usize| note.desc.get(offset..offset + 4).map(|mut field| field.read_u32::<NativeEndian>().unwrap());
+            match note.kind {
+                NT_PRSTATUS => threads.push(Thread {
+                    tid: field(PRSTATUS_PID).ok_or_else(|| invalid("prstatus note too short"))?,
+                    prstatus: note.desc,
+                    fpregset: None,
+                }),
+                // The floating point registers of the thread right before
+                NT_FPREGSET => {
+                    if let Some(thread) = threads.last_mut() {
+                        thread.fpregset = Some(note.desc);
+                    }
+                },
+                NT_PRPSINFO => pid = field(PRPSINFO_PID),
+                NT_AUXV => auxv = Some(note.desc),
+                NT_FILE => files = mapped_files(&note.desc)?,
+                _ => (),
+            }
+        }
+
+        let first = threads.first().ok_or_else(|| invalid("no threads in core file"))?;
+        let signal = first.prstatus[PRSTATUS_CURSIG];
+        let current = first.tid;
+        Ok(CoreFile {
+            file: RefCell::new(file),
+            program: program.to_string(),
+            pid: pid.unwrap_or(current),
+            signal,
+            current: Cell::new(current),
+            threads,
+            segments: headers.into_iter().filter(|header| header.kind == PT_LOAD).collect(),
+            auxv,
+            files,
+        })
+    }
+
+    fn thread(&self) -> &Thread {
+        let current = self.current.get();
+        self.threads.iter().find(|thread| thread.tid == current).unwrap()
+    }
+}
+
+impl Target for CoreFile {
+    fn new(_launch: &Launch) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("a core file can't be started again".into())
+    }
+
+    fn status_native(&self) -> StopReason {
+        // Cores dumped without a signal, such as by gcore, stopped somehow
+        StopReason::Signal(if self.signal == 0 { libc::SIGSTOP as u8 } else { self.signal })
+    }
+
+    fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    fn getregs(&self) -> Result<Registers> {
+        let thread = self.thread();
+        registers(&thread.prstatus, thread.fpregset.as_deref()).map_err(io_error)
+    }
+
+    fn setregs(&self, _regs: &Registers) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    fn getmem(&self, address: usize, memory: &mut [u8]) -> Result<usize> {
+        let mut file = self.file.borrow_mut();
+        let mut done = 0;
+        while done < memory.len() {
+            let at = (address + done) as u64;
+            // Pages that weren't readable have no data in the file
+            let segment = self.segments.iter()
+                .find(|segment| segment.vaddr <= at && at < segment.vaddr + segment.filesz)
+                .ok_or(Error::Error(libc::EIO as u8))?;
+            let len = ((segment.vaddr + segment.filesz - at) as usize).min(memory.len() - done);
+            file.seek(SeekFrom::Start(segment.offset + at - segment.vaddr)).map_err(io_error)?;
+            file.read_exact(&mut memory[done..done + len]).map_err(io_error)?;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn setmem(&self, _address: usize, _memory: &[u8]) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    fn step(&self, _signal: Option<u8>) -> Result<Option<u64>> {
+        Err(Error::Unimplemented)
+    }
+
+    fn threads(&self) -> Vec<u32> {
+        self.threads.iter().map(|thread| thread.tid).collect()
+    }
+
+    fn select_thread(&self, tid: u32) -> Result<()> {
+        if !self.threads.iter().any(|thread| thread.tid == tid) {
+            return Err(Error::Error(libc::ESRCH as u8));
+        }
+        self.current.set(tid);
+        Ok(())
+    }
+
+    fn current_thread(&self) -> u32 {
+        self.current.get()
+    }
+
+    fn resume_threads(&self, _actions: &[(u32, Resume)], _interrupt: Option<&dyn Interrupt>) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    fn path(&self, _pid: usize) -> Result<Vec<u8>> {
+        Ok(self.program.as_bytes().to_vec())
+    }
+
+    fn thread_info(&self, _tid: u32) -> ThreadInfo {
+        ThreadInfo::default()
+    }
+
+    fn mappings(&self) -> Result<Vec<Mapping>> {
+        Ok(self.segments.iter()
+            .map(|segment| {
+                let file = self.files.iter().find(|&&(start, _)| start == segment.vaddr);
+                Mapping {
+                    start: segment.vaddr,
+                    end: segment.vaddr + segment.memsz,
+                    read: segment.flags & PF_R != 0,
+                    write: segment.flags & PF_W != 0,
+                    execute: segment.flags & PF_X != 0,
+                    shared: false,
+                    offset: 0,
+                    name: file.map(|(_, name)| name.clone()).unwrap_or_default(),
+                }
+            })
+            .collect())
+    }
+
+    fn auxv(&self) -> Result<Vec<u8>> {
+        self.auxv.clone().ok_or(Error::Unimplemented)
+    }
+
+    fn signal_masks(&self) -> Result<SignalMasks> {
+        // Core files only keep which signals were pending and blocked
+        Err(Error::Unimplemented)
+    }
+
+    fn detach(&self) -> Result<()> {
+        // Nothing is running, so the same core is there for the next session
+        Ok(())
+    }
+
+    fn detached(&self) -> bool {
+        false
+    }
+
+    fn fork(&self) -> Result<Self> {
+        Err(Error::Unimplemented)
+    }
+
+    fn kill(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coredump;
+
+    #[test]
+    fn registers() {
+        let regs = Registers {
+            rax: Some(1),
+            rip: Some(0x40_1000),
+            rsp: Some(0x7FFF_0000),
+            eflags: Some(0x246),
+            fs_base: Some(0x1234),
+            mxcsr: Some(0x1F80),
+            st3: Some(3),
+            xmm15: Some(!0),
+            ..Registers::default()
+        };
+        let prstatus = coredump::prstatus(1, 2, 11, &regs);
+        let fpregset = coredump::fpregset(&regs);
+        assert_eq!(prstatus[PRSTATUS_CURSIG], 11);
+
+        let decoded = super::registers(&prstatus, Some(&fpregset)).unwrap();
+        assert_eq!(decoded.rax, Some(1));
+        assert_eq!(decoded.rip, Some(0x40_1000));
+        assert_eq!(decoded.fs_base, Some(0x1234));
+        assert_eq!(decoded.mxcsr, Some(0x1F80));
+        assert_eq!(decoded.st3, Some(3));
+        assert_eq!(decoded.xmm15, Some(!0));
+        // Everything else was dumped as zero
+        assert_eq!(decoded.rbx, Some(0));
+    }
+}
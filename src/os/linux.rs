@@ -1,24 +1,75 @@
-use super::Registers;
+use super::{Interrupt, Launch, Mapping, Registers, Resume, SignalMasks, Stdio, ThreadInfo};
 use crate::Result;
 
-use log::{error, warn};
+use log::{debug, error, warn};
 
 use std::{
-    cell::Cell,
-    ffi::CString,
+    cell::{Cell, RefCell},
+    collections::{btree_map::Entry, BTreeMap, VecDeque},
+    ffi::{CStr, CString},
+    fs,
     io,
     iter,
     mem::{self, MaybeUninit},
     os::unix::ffi::OsStrExt,
     path::PathBuf,
+    os::unix::io::RawFd,
     ptr,
 };
 
 use gdb_remote_protocol::{Error, StopReason};
 
+// Not exported by libc, see <linux/personality.h>
+const ADDR_NO_RANDOMIZE: libc::c_ulong = 0x0040000;
+// Makes personality(...) just return the current persona
+const PERSONALITY_QUERY: libc::c_ulong = 0xffff_ffff;
+// The wait status of a thread stopped without a signal, which is how a stop
+// GDB asked for is reported
+const STOPPED_AS_ASKED: libc::c_int = 0x7F;
+
+// Longest time to wait for SIGCHLD before checking on the tracee anyway, in
+// ms. It only takes that long if a thread that doesn't block the signal took
+// it, which the server's own don't, but the test harness's do all the time.
+#[cfg(not(test))]
+const MAX_WAIT: libc::c_int = 1000;
+#[cfg(test)]
+const MAX_WAIT: libc::c_int = 10;
+// Report execs as events, so they can't be confused with anything raising
+// SIGTRAP, and follow new threads
+const OPTIONS: libc::c_int = libc::PTRACE_O_TRACEEXEC | libc::PTRACE_O_TRACECLONE;
+// The instruction that makes a system call
+const SYSCALL: [u8; 2] = [0x0F, 0x05];
+
+/// What the tracer keeps track of for each thread of the tracee
+#[derive(Debug, Default)]
+struct Thread {
+    /// How the thread was last resumed, if it's still running
+    running: Option<Resume>,
+    /// A stop that happened while stopping all threads, yet to be reported
+    pending: Option<libc::c_int>,
+    /// Whether a SIGSTOP sent by us has yet to be swallowed
+    stop_expected: bool,
+    /// Whether GDB asked for the thread to stop in non-stop mode, and hasn't
+    /// been told about it stopping since
+    stop_requested: bool,
+}
+
 pub struct Os {
     pid: libc::pid_t,
     last_status: Cell<libc::c_int>,
+    detached: Cell<bool>,
+    threads: RefCell<BTreeMap<libc::pid_t, Thread>>,
+    /// The thread selected by GDB, or the one that last stopped
+    current: Cell<libc::pid_t>,
+    /// Stops that were pending when GDB resumed their threads in non-stop
+    /// mode, to be reported by poll_stop as if they just happened
+    events: RefCell<VecDeque<(libc::pid_t, libc::c_int)>>,
+    /// The ID GDB knows the process and its main thread by, if it's a copy
+    /// that took over from another process
+    alias: Cell<Option<libc::pid_t>>,
+    /// A signalfd that's readable once SIGCHLD arrives, which is when any
+    /// thread of the tracee stops or exits
+    sigchld: RawFd,
 }
 
 trait FromOsError: Sized {
@@ -51,6 +102,60 @@ macro_rules! e {
     }};
 }
 
+/// SIGCHLD on its own
+fn sigchld_mask() -> libc::sigset_t {
+    unsafe {
+        let mut mask = MaybeUninit::uninit();
+        libc::sigemptyset(mask.as_mut_ptr());
+        libc::sigaddset(mask.as_mut_ptr(), libc::SIGCHLD);
+        mask.assume_init()
+    }
+}
+
+/// Block SIGCHLD in the calling thread. The tracer waits for it on a
+/// signalfd, and any thread of the server that doesn't block it can take it
+/// first, so they all should.
+pub fn block_sigchld() -> io::Result<()> {
+    if unsafe { libc::sigprocmask(libc::SIG_BLOCK, &sigchld_mask(), ptr::null_mut()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Block SIGCHLD, and open a signalfd to wait for it on
+fn sigchld_fd() -> io::Result<RawFd> {
+    block_sigchld()?;
+    match unsafe { libc::signalfd(-1, &sigchld_mask(), libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(fd),
+    }
+}
+
+/// Read the thread's registers as ptrace has them, including the ones GDB
+/// doesn't know about like orig_rax
+unsafe fn raw_regs(tid: libc::pid_t) -> Result<libc::user_regs_struct> {
+    let mut regs: MaybeUninit<libc::user_regs_struct> = MaybeUninit::uninit();
+    e!(libc::ptrace(libc::PTRACE_GETREGS, tid, 0, regs.as_mut_ptr()));
+    Ok(regs.assume_init())
+}
+
+/// Write back registers read by raw_regs
+unsafe fn set_raw_regs(tid: libc::pid_t, regs: &libc::user_regs_struct) -> Result<()> {
+    e!(libc::ptrace(libc::PTRACE_SETREGS, tid, 0, regs));
+    Ok(())
+}
+
+/// Read a word of the thread's memory. PTRACE_PEEKDATA returns -1 both for
+/// errors and for words with all bits set, which only errno tells apart.
+unsafe fn peek(tid: libc::pid_t, addr: usize) -> Result<usize> {
+    *libc::__errno_location() = 0;
+    let word = libc::ptrace(libc::PTRACE_PEEKDATA, tid, addr);
+    if word == -1 && *libc::__errno_location() != 0 {
+        return Err(FromOsError::from_os_error(*libc::__errno_location()));
+    }
+    Ok(word as usize)
+}
+
 fn getmem<G, E>(mut src: usize, dest: &mut [u8], mut get: G) -> Result<usize, E>
 where
     G: FnMut(usize) -> Result<usize, E>,
@@ -88,29 +193,499 @@ where
     Ok(())
 }
 
+/// Where a standard stream of the child goes, worked out before forking so
+/// the child needn't allocate
+enum Redirect {
+    Inherit,
+    /// Duplicate one of the server's own descriptors
+    Dup(libc::c_int),
+    /// Open a file with these flags
+    Open(CString, libc::c_int),
+}
+
+impl Redirect {
+    /// Where the launch configuration wants the stream `fd` to go
+    fn new(fd: libc::c_int, stdio: &Stdio) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(match stdio {
+            Stdio::Inherit => Redirect::Inherit,
+            Stdio::Stderr => Redirect::Dup(libc::STDERR_FILENO),
+            Stdio::Null => Redirect::Open(CString::new("/dev/null")?, libc::O_RDWR),
+            Stdio::File(path) if fd == libc::STDIN_FILENO => {
+                Redirect::Open(CString::new(path.as_os_str().as_bytes())?, libc::O_RDONLY)
+            },
+            Stdio::File(path) => Redirect::Open(
+                CString::new(path.as_os_str().as_bytes())?,
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+            ),
+        })
+    }
+
+    /// Connect the stream `fd` of the forked child
+    unsafe fn apply(&self, fd: libc::c_int) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Redirect::Inherit => (),
+            Redirect::Dup(old) => {
+                e!(libc::dup2(*old, fd));
+            },
+            Redirect::Open(path, flags) => {
+                let file = e!(libc::open(path.as_ptr(), *flags, 0o666 as libc::c_uint));
+                if file != fd {
+                    e!(libc::dup2(file, fd));
+                    e!(libc::close(file));
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Make the specified terminal the controlling terminal and standard streams
+/// of the forked child
+unsafe fn set_tty(tty: &CStr) -> Result<(), Box<dyn std::error::Error>> {
+    e!(libc::setsid());
+    let file = e!(libc::open(tty.as_ptr(), libc::O_RDWR));
+    e!(libc::ioctl(file, libc::TIOCSCTTY, 0));
+
+    for &fd in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        e!(libc::dup2(file, fd));
+    }
+    if file > libc::STDERR_FILENO {
+        e!(libc::close(file));
+    }
+    Ok(())
+}
+
+/// Resume a single thread, without waiting for it
+unsafe fn resume_thread(tid: libc::pid_t, resume: Resume) -> Result<()> {
+    let (request, signal) = match resume {
+        Resume::Continue(signal) => (libc::PTRACE_CONT, signal),
+        Resume::Step(signal) => (libc::PTRACE_SINGLESTEP, signal),
+    };
+    e!(libc::ptrace(request, tid, 0, signal.unwrap_or(0) as libc::c_uint));
+    Ok(())
+}
+
+/// Parse a line of /proc/<pid>/maps, see proc(5)
+fn parse_mapping(line: &str) -> Option<Mapping> {
+    let mut fields = line.splitn(6, ' ');
+    let mut range = fields.next()?.split('-');
+    let start = u64::from_str_radix(range.next()?, 16).ok()?;
+    let end = u64::from_str_radix(range.next()?, 16).ok()?;
+    let perms = fields.next()?.as_bytes();
+    let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+    // Skip the device and inode
+    let name = fields.nth(2).unwrap_or("").trim_start();
+
+    Some(Mapping {
+        start,
+        end,
+        read: perms.first() == Some(&b'r'),
+        write: perms.get(1) == Some(&b'w'),
+        execute: perms.get(2) == Some(&b'x'),
+        shared: perms.get(3) == Some(&b's'),
+        offset,
+        name: String::from(name),
+    })
+}
+
+/// Read the scheduling state and last CPU core from the contents of
+/// /proc/pid/task/tid/stat
+fn parse_stat(stat: &str) -> (Option<&'static str>, Option<u32>) {
+    // The name may contain anything, so start after its last parenthesis
+    let fields = stat
+        .rfind(')')
+        .map(|end| stat[end + 1..].split_whitespace().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    // Field 3 and 39 in proc(5), with the first two skipped
+    let state = fields.first().and_then(|state| {
+        Some(match *state {
+            "R" => "running",
+            "S" => "sleeping",
+            "D" => "waiting for disk",
+            "T" => "stopped",
+            "t" => "tracing stop",
+            "Z" => "zombie",
+            "X" => "dead",
+            "I" => "idle",
+            _ => return None,
+        })
+    });
+    let core = fields.get(36).and_then(|core| core.parse().ok());
+    (state, core)
+}
+
+/// Read the signal masks from the contents of /proc/pid/task/tid/status
+fn parse_signal_masks(status: &str) -> SignalMasks {
+    let mut masks = SignalMasks::default();
+    for line in status.lines() {
+        let mut fields = line.split(':');
+        let (name, value) = match (fields.next(), fields.next()) {
+            (Some(name), Some(value)) => (name, value.trim()),
+            _ => continue,
+        };
+        let mask = match name {
+            "SigPnd" | "ShdPnd" => &mut masks.pending,
+            "SigBlk" => &mut masks.blocked,
+            "SigIgn" => &mut masks.ignored,
+            "SigCgt" => &mut masks.caught,
+            _ => continue,
+        };
+        *mask |= u64::from_str_radix(value, 16).unwrap_or(0);
+    }
+    masks
+}
+
+fn is_event(status: libc::c_int, event: libc::c_int) -> bool {
+    status >> 8 == libc::SIGTRAP | (event << 8)
+}
+
+/// The signal a thread stopped with `status` would get if resumed, leaving
+/// out the ones ptrace and the tracer cause
+fn pending_signal(status: libc::c_int) -> libc::c_int {
+    let (stopped, signal) = unsafe { (libc::WIFSTOPPED(status), libc::WSTOPSIG(status)) };
+    if !stopped || status >> 16 != 0 || signal == libc::SIGTRAP || signal == libc::SIGSTOP {
+        0
+    } else {
+        signal
+    }
+}
+
+impl Os {
+    /// The ID GDB knows a thread by
+    fn shown(&self, tid: libc::pid_t) -> u32 {
+        match self.alias.get() {
+            Some(alias) if tid == self.pid => alias as u32,
+            _ => tid as u32,
+        }
+    }
+
+    /// The thread GDB means by an ID
+    fn real(&self, tid: u32) -> libc::pid_t {
+        match self.alias.get() {
+            Some(alias) if tid as libc::pid_t == alias => self.pid,
+            _ => tid as libc::pid_t,
+        }
+    }
+
+    /// The threads of the tracee as the kernel knows them, which can include
+    /// new ones whose parent hasn't reported the clone event yet
+    fn tasks(&self) -> Vec<libc::pid_t> {
+        let mut tids: Vec<libc::pid_t> = self.threads.borrow().keys().copied().collect();
+        if let Ok(entries) = fs::read_dir(format!("/proc/{}/task", self.pid)) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                    Some(tid) if !tids.contains(&tid) => tids.push(tid),
+                    _ => (),
+                }
+            }
+        }
+        tids
+    }
+
+    /// Wait for any thread of the tracee to change state, while watching the
+    /// connection for interrupts if there is one. Returns the thread and its
+    /// status. Other children, like forked checkpoints, are left alone, so
+    /// only the tracee's own threads are waited for.
+    unsafe fn wait_interruptible(&self, mut interrupt: Option<&dyn Interrupt>) -> Result<(libc::pid_t, libc::c_int)> {
+        let mut status = 0;
+        loop {
+            // Any SIGCHLD from here on is for a change the sweep might miss
+            let mut info: MaybeUninit<libc::signalfd_siginfo> = MaybeUninit::uninit();
+            while libc::read(self.sigchld, info.as_mut_ptr() as *mut libc::c_void, mem::size_of_val(&info)) > 0 {}
+
+            for tid in self.tasks() {
+                if e!(libc::waitpid(tid, &mut status, libc::WNOHANG | libc::__WALL)) != 0 {
+                    return Ok((tid, status));
+                }
+            }
+
+            // What's already arrived could have the interrupt in it
+            if let Some(connection) = interrupt {
+                if connection.interrupted() {
+                    debug!("Interrupting tracee");
+                    e!(libc::kill(self.pid, libc::SIGINT));
+                    interrupt = None;
+                    continue;
+                }
+            }
+
+            let mut pollfds = vec![libc::pollfd {
+                fd: self.sigchld,
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            if let Some(connection) = interrupt {
+                pollfds.push(libc::pollfd {
+                    fd: connection.fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+            let ready = libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, MAX_WAIT);
+            if ready == -1 && *libc::__errno_location() != libc::EINTR {
+                return Err(FromOsError::from_os_error(*libc::__errno_location()));
+            }
+        }
+    }
+
+    /// Start tracking the thread created by a clone event of the parent.
+    /// The new thread is left stopped.
+    unsafe fn add_clone(&self, threads: &mut BTreeMap<libc::pid_t, Thread>, parent: libc::pid_t) -> Result<libc::pid_t> {
+        let mut tid: libc::c_ulong = 0;
+        e!(libc::ptrace(libc::PTRACE_GETEVENTMSG, parent, 0, &mut tid));
+        let tid = tid as libc::pid_t;
+
+        // Its initial stop may already have been seen, see wait_event
+        if let Entry::Vacant(entry) = threads.entry(tid) {
+            let mut status = 0;
+            e!(libc::waitpid(tid, &mut status, libc::__WALL));
+            entry.insert(Thread::default());
+        }
+        debug!("New thread {}", tid);
+        Ok(tid)
+    }
+
+    /// Deal with a change of state of one of the tracee's threads, resuming
+    /// it if it's nothing GDB should know about. Otherwise the thread becomes
+    /// the current one with its stop as the last status, and true is returned.
+    unsafe fn handle_status(&self, tid: libc::pid_t, mut status: libc::c_int) -> Result<bool> {
+        let mut threads = self.threads.borrow_mut();
+
+        if !libc::WIFSTOPPED(status) {
+            threads.remove(&tid);
+            debug!("Thread {} exited", tid);
+            if tid == self.pid || threads.is_empty() {
+                // The whole process is gone
+                threads.clear();
+                self.last_status.set(status);
+                self.current.set(self.pid);
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+
+        // A new thread can stop before the clone event of its parent. It's
+        // one of the tracee's, as nothing else is waited for.
+        let thread = threads.entry(tid).or_default();
+        let resume = match thread.running {
+            Some(resume) => resume,
+            None => return Ok(false),
+        };
+
+        if is_event(status, libc::PTRACE_EVENT_CLONE) {
+            let new = self.add_clone(&mut threads, tid)?;
+            // A stepping thread shouldn't let any others run
+            if let Resume::Continue(_) = resume {
+                resume_thread(new, resume)?;
+                threads.get_mut(&new).unwrap().running = Some(resume);
+            }
+            resume_thread(tid, resume)?;
+            return Ok(false);
+        }
+        if libc::WSTOPSIG(status) == libc::SIGSTOP && thread.stop_expected {
+            thread.stop_expected = false;
+            if !thread.stop_requested {
+                resume_thread(tid, resume)?;
+                return Ok(false);
+            }
+            status = STOPPED_AS_ASKED;
+        }
+        // Anything else it stopped for first is the stop GDB asked for, and
+        // the SIGSTOP is swallowed once it's resumed
+        thread.stop_requested = false;
+
+        thread.running = None;
+        drop(threads);
+
+        self.last_status.set(status);
+        self.current.set(tid);
+        Ok(true)
+    }
+
+    /// Wait until any thread stops for a reason GDB should know about, and
+    /// stop all the other threads too
+    unsafe fn wait_event(&self, interrupt: Option<&dyn Interrupt>) -> Result<()> {
+        loop {
+            let (tid, status) = self.wait_interruptible(interrupt)?;
+            if self.handle_status(tid, status)? {
+                return self.stop_all();
+            }
+        }
+    }
+
+    /// Single-step the thread while others keep running in non-stop mode,
+    /// waiting for it alone. Returns false if the thread exited instead.
+    unsafe fn step_alone(&self, tid: libc::pid_t, signal: Option<u8>) -> Result<bool> {
+        resume_thread(tid, Resume::Step(signal))?;
+        if let Some(thread) = self.threads.borrow_mut().get_mut(&tid) {
+            thread.running = Some(Resume::Step(None));
+        }
+        loop {
+            let mut status = 0;
+            e!(libc::waitpid(tid, &mut status, libc::__WALL));
+            if self.handle_status(tid, status)? {
+                return Ok(true);
+            }
+            if !self.threads.borrow().contains_key(&tid) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Make the thread run the fork system call at its instruction pointer
+    /// with the given registers, and return the child once it's stopped and
+    /// traced
+    unsafe fn follow_fork(&self, tid: libc::pid_t, call: &libc::user_regs_struct) -> Result<libc::pid_t> {
+        set_raw_regs(tid, call)?;
+        let mut status = 0;
+        loop {
+            e!(libc::ptrace(libc::PTRACE_SINGLESTEP, tid, 0, 0));
+            e!(libc::waitpid(tid, &mut status, libc::__WALL));
+            if !libc::WIFSTOPPED(status) {
+                self.last_status.set(status);
+                return Err(Error::Error(libc::ESRCH as u8));
+            }
+            if is_event(status, libc::PTRACE_EVENT_FORK) {
+                break;
+            }
+            // Stepped past without forking, so the system call failed.
+            // Stopped in a system call like the execve at launch, the first
+            // step only finishes that one, without moving but setting rax.
+            if libc::WSTOPSIG(status) == libc::SIGTRAP {
+                if raw_regs(tid)?.rip == call.rip {
+                    set_raw_regs(tid, call)?;
+                    continue;
+                }
+                return Err(Error::Error(libc::EAGAIN as u8));
+            }
+            // Signals that came first are reported once it's resumed again,
+            // like the SIGCHLD a checkpoint gets when a copy of it exits
+            if let Some(thread) = self.threads.borrow_mut().get_mut(&tid) {
+                thread.pending = Some(status);
+            }
+        }
+
+        let mut child: libc::c_ulong = 0;
+        e!(libc::ptrace(libc::PTRACE_GETEVENTMSG, tid, 0, &mut child));
+        let child = child as libc::pid_t;
+        // The child starts out stopped, with the options of its parent
+        e!(libc::waitpid(child, &mut status, libc::__WALL));
+        e!(libc::ptrace(libc::PTRACE_SETOPTIONS, child, 0, OPTIONS));
+
+        e!(libc::ptrace(libc::PTRACE_SINGLESTEP, tid, 0, 0));
+        e!(libc::waitpid(tid, &mut status, libc::__WALL));
+        Ok(child)
+    }
+
+    /// Stop all threads that are still running, remembering anything else
+    /// they stopped for until they're resumed
+    unsafe fn stop_all(&self) -> Result<()> {
+        let mut threads = self.threads.borrow_mut();
+
+        let running = threads.iter()
+            .filter(|(_, thread)| thread.running.is_some())
+            .map(|(&tid, _)| tid)
+            .collect::<Vec<_>>();
+
+        for &tid in &running {
+            let thread = threads.get_mut(&tid).unwrap();
+            if !thread.stop_expected {
+                e!(libc::syscall(libc::SYS_tgkill, self.pid, tid, libc::SIGSTOP));
+                thread.stop_expected = true;
+            }
+        }
+
+        for tid in running {
+            let mut status = 0;
+            e!(libc::waitpid(tid, &mut status, libc::__WALL));
+
+            if !libc::WIFSTOPPED(status) {
+                threads.remove(&tid);
+                continue;
+            }
+            if is_event(status, libc::PTRACE_EVENT_CLONE) {
+                // Our SIGSTOP is still queued for the parent
+                self.add_clone(&mut threads, tid)?;
+            }
+
+            let thread = threads.get_mut(&tid).unwrap();
+            thread.running = None;
+            thread.stop_requested = false;
+            if libc::WSTOPSIG(status) == libc::SIGSTOP && thread.stop_expected {
+                thread.stop_expected = false;
+            } else if !is_event(status, libc::PTRACE_EVENT_CLONE) {
+                thread.pending = Some(status);
+            }
+        }
+        Ok(())
+    }
+}
+
 impl super::Target for Os {
-    fn new(program: String, args: Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+    fn new(launch: &Launch) -> Result<Self, Box<dyn std::error::Error>> {
+        let (program, args, execs) = launch.command();
+        let sigchld = sigchld_fd()?;
+
+        // Everything the child needs is allocated here, as a fork of a
+        // process with other threads mustn't
+        let program = CString::new(program)?;
+        let args = args.into_iter().map(CString::new).collect::<Result<Vec<_>, _>>()?;
+        let args: Vec<*const libc::c_char> =
+            args.iter().map(|arg| arg.as_ptr()).chain(iter::once(ptr::null())).collect();
+        let env = launch.env.iter()
+            .map(|(key, value)| {
+                let mut var = Vec::from(key.as_bytes());
+                var.push(b'=');
+                var.extend_from_slice(value.as_bytes());
+                CString::new(var)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let env: Vec<*const libc::c_char> =
+            env.iter().map(|var| var.as_ptr()).chain(iter::once(ptr::null())).collect();
+        let cwd = launch.cwd.as_ref().map(|cwd| CString::new(cwd.as_os_str().as_bytes())).transpose()?;
+        let tty = launch.tty.as_ref().map(|tty| CString::new(tty.as_os_str().as_bytes())).transpose()?;
+        let stdin = Redirect::new(libc::STDIN_FILENO, &launch.stdin)?;
+        let stdout = Redirect::new(libc::STDOUT_FILENO, &launch.stdout)?;
+        let stderr = match (&launch.stdout, &launch.stderr) {
+            // Opened once, so neither overwrites what the other wrote
+            (Stdio::File(stdout), Stdio::File(stderr)) if stdout == stderr => {
+                Redirect::Dup(libc::STDOUT_FILENO)
+            },
+            (_, stderr) => Redirect::new(libc::STDERR_FILENO, stderr)?,
+        };
+
         unsafe {
             let pid = libc::fork();
 
             if pid == 0 {
                 // Must not drop any memory, not unwind (panic).
                 let result = (|| -> Result<(), Box<dyn std::error::Error>> {
-                    let program = CString::new(program)?.into_raw();
-                    let args = args.into_iter()
-                        .map(|s| CString::new(s).map(|s| s.into_raw() as *const _))
-                        .chain(iter::once(Ok(ptr::null())))
-                        .collect::<Result<Vec<*const libc::c_char>, _>>()?;
+                    if launch.disable_aslr {
+                        let persona = e!(libc::personality(PERSONALITY_QUERY));
+                        e!(libc::personality(persona as libc::c_ulong | ADDR_NO_RANDOMIZE));
+                    }
+                    if let Some(cwd) = &cwd {
+                        e!(libc::chdir(cwd.as_ptr()));
+                    }
+                    if let Some(tty) = &tty {
+                        set_tty(tty)?;
+                    }
+                    stdin.apply(libc::STDIN_FILENO)?;
+                    stdout.apply(libc::STDOUT_FILENO)?;
+                    stderr.apply(libc::STDERR_FILENO)?;
+                    // Signal masks survive execve
+                    e!(libc::sigprocmask(libc::SIG_UNBLOCK, &sigchld_mask(), ptr::null_mut()));
 
                     e!(libc::ptrace(libc::PTRACE_TRACEME));
                     e!(libc::raise(libc::SIGSTOP));
-                    e!(libc::execvp(program, args.as_ptr()));
+                    e!(libc::execvpe(program.as_ptr(), args.as_ptr(), env.as_ptr()));
                     Ok(())
                 })();
 
                 match result {
                     Ok(()) => {
-                        error!("execvp(...) should not be able to succeed");
+                        error!("execvpe(...) should not be able to succeed");
                         libc::exit(1);
                     },
                     Err(err) => {
@@ -123,13 +698,39 @@ impl super::Target for Os {
                 let mut status = 0;
                 e!(libc::waitpid(pid, &mut status, 0));
 
-                // Skip until post-execve
-                e!(libc::ptrace(libc::PTRACE_CONT, pid, 0, 0));
-                e!(libc::waitpid(pid, &mut status, 0));
+                e!(libc::ptrace(libc::PTRACE_SETOPTIONS, pid, 0, OPTIONS));
+
+                // Skip until post-execve of the actual program, passing on any
+                // other signals to the shell. The tracee is then stopped before
+                // its first instruction, be it in the program or the dynamic
+                // loader.
+                let mut signal = 0;
+                for _ in 0..execs {
+                    loop {
+                        e!(libc::ptrace(libc::PTRACE_CONT, pid, 0, signal));
+                        e!(libc::waitpid(pid, &mut status, 0));
+
+                        if !libc::WIFSTOPPED(status) {
+                            // Never made it to the program, but let GDB see how it exited
+                            break;
+                        } else if is_event(status, libc::PTRACE_EVENT_EXEC) {
+                            signal = 0;
+                            break;
+                        } else {
+                            signal = libc::WSTOPSIG(status);
+                        }
+                    }
+                }
 
                 Ok(Os {
                     pid,
                     last_status: Cell::from(status),
+                    detached: Cell::new(false),
+                    threads: RefCell::new(iter::once((pid, Thread::default())).collect()),
+                    current: Cell::new(pid),
+                    events: RefCell::new(VecDeque::new()),
+                    alias: Cell::new(None),
+                    sigchld,
                 })
             }
         }
@@ -139,12 +740,12 @@ impl super::Target for Os {
         unsafe {
             if libc::WIFEXITED(self.last_status.get()) {
                 StopReason::Exited(
-                    self.pid as _,
+                    self.shown(self.pid) as _,
                     libc::WEXITSTATUS(self.last_status.get()) as _,
                 )
             } else if libc::WIFSIGNALED(self.last_status.get()) {
                 StopReason::ExitedWithSignal(
-                    self.pid as _,
+                    self.shown(self.pid) as _,
                     libc::WTERMSIG(self.last_status.get()) as _,
                 )
             } else if libc::WIFSTOPPED(self.last_status.get()) {
@@ -156,7 +757,7 @@ impl super::Target for Os {
     }
 
     fn pid(&self) -> u32 {
-        self.pid as _
+        self.shown(self.pid)
     }
 
     fn getregs(&self) -> Result<Registers> {
@@ -164,7 +765,7 @@ impl super::Target for Os {
             let mut int: MaybeUninit<libc::user_regs_struct> = MaybeUninit::uninit();
             e!(libc::ptrace(
                 libc::PTRACE_GETREGS,
-                self.pid,
+                self.current.get(),
                 0,
                 int.as_mut_ptr()
             ));
@@ -175,7 +776,7 @@ impl super::Target for Os {
             let mut float: MaybeUninit<libc::user_fpregs_struct> = MaybeUninit::uninit();
             e!(libc::ptrace(
                 libc::PTRACE_GETFPREGS,
-                self.pid,
+                self.current.get(),
                 0,
                 float.as_mut_ptr()
             ));
@@ -325,8 +926,8 @@ impl super::Target for Os {
         int.gs_base = registers.gs_base.unwrap_or(int.gs_base);
 
         unsafe {
-            e!(libc::ptrace(libc::PTRACE_SETREGS, self.pid, 0, &int));
-            e!(libc::ptrace(libc::PTRACE_SETFPREGS, self.pid, 0, &float));
+            e!(libc::ptrace(libc::PTRACE_SETREGS, self.current.get(), 0, &int));
+            e!(libc::ptrace(libc::PTRACE_SETFPREGS, self.current.get(), 0, &float));
         }
 
         Ok(())
@@ -335,42 +936,39 @@ impl super::Target for Os {
     fn getmem(&self, address: usize, memory: &mut [u8]) -> Result<usize> {
         // TODO: Don't report errors when able to read part of requested?
         // Also implement this in the Redox kernel perhaps
-        getmem(address, memory, |addr| unsafe {
-            Ok(e!(libc::ptrace(libc::PTRACE_PEEKDATA, self.pid, addr)) as usize)
-        })
+        getmem(address, memory, |addr| unsafe { peek(self.current.get(), addr) })
     }
 
     fn setmem(&self, address: usize, memory: &[u8]) -> Result<()> {
         setmem(
             memory,
             address,
-            |addr| unsafe { Ok(e!(libc::ptrace(libc::PTRACE_PEEKDATA, self.pid, addr)) as usize) },
+            |addr| unsafe { peek(self.current.get(), addr) },
             |addr, word| unsafe {
-                e!(libc::ptrace(libc::PTRACE_POKEDATA, self.pid, addr, word));
+                e!(libc::ptrace(libc::PTRACE_POKEDATA, self.current.get(), addr, word));
                 Ok(())
             },
         )
     }
 
     fn step(&self, signal: Option<u8>) -> Result<Option<u64>> {
-        unsafe {
-            e!(libc::ptrace(
-                libc::PTRACE_SINGLESTEP,
-                self.pid,
-                0,
-                signal.unwrap_or(0) as libc::c_uint
-            ));
-            let mut status = 0;
-            e!(libc::waitpid(self.pid, &mut status, 0));
-            self.last_status.set(status);
+        let tid = self.current.get();
+        let others_running = self.threads.borrow()
+            .iter()
+            .any(|(&other, thread)| other != tid && thread.running.is_some());
+        if !others_running {
+            self.resume_threads(&[(tid as u32, Resume::Step(signal))], None)?;
+        } else if !unsafe { self.step_alone(tid, signal)? } {
+            return Ok(None);
+        }
 
+        let status = self.last_status.get();
+        unsafe {
             Ok(
-                if libc::WIFSTOPPED(self.last_status.get())
-                    && libc::WSTOPSIG(self.last_status.get()) == libc::SIGTRAP
-                {
+                if libc::WIFSTOPPED(status) && libc::WSTOPSIG(status) == libc::SIGTRAP {
                     let rip = e!(libc::ptrace(
                         libc::PTRACE_PEEKUSER,
-                        self.pid,
+                        self.current.get(),
                         libc::RIP as usize * mem::size_of::<usize>()
                     ));
                     Some(rip as u64)
@@ -381,24 +979,139 @@ impl super::Target for Os {
         }
     }
 
-    fn cont(&self, signal: Option<u8>) -> Result<()> {
-        unsafe {
-            e!(libc::ptrace(
-                libc::PTRACE_CONT,
-                self.pid,
-                0,
-                signal.unwrap_or(0) as libc::c_uint
-            ));
-            let mut status = 0;
-            e!(libc::waitpid(self.pid, &mut status, 0));
+    fn threads(&self) -> Vec<u32> {
+        self.threads.borrow().keys().map(|&tid| self.shown(tid)).collect()
+    }
+
+    fn select_thread(&self, tid: u32) -> Result<()> {
+        let tid = self.real(tid);
+        if !self.threads.borrow().contains_key(&tid) {
+            return Err(Error::Error(libc::ESRCH as u8));
+        }
+        self.current.set(tid);
+        Ok(())
+    }
+
+    fn current_thread(&self) -> u32 {
+        self.shown(self.current.get())
+    }
+
+    fn resume_threads(&self, actions: &[(u32, Resume)], interrupt: Option<&dyn Interrupt>) -> Result<()> {
+        let mut threads = self.threads.borrow_mut();
+
+        // Anything that happened while stopping the threads last time gets
+        // reported before letting them run again
+        for &(tid, _) in actions {
+            let tid = self.real(tid);
+            if let Some(status) = threads.get_mut(&tid).and_then(|thread| thread.pending.take()) {
+                debug!("Reporting pending status {:#X} of thread {}", status, tid);
+                self.last_status.set(status);
+                self.current.set(tid);
+                return Ok(());
+            }
+        }
+
+        let mut resumed = false;
+        for &(tid, resume) in actions {
+            let tid = self.real(tid);
+            let thread = match threads.get_mut(&tid) {
+                Some(thread) if thread.running.is_none() => thread,
+                _ => continue,
+            };
+            unsafe {
+                resume_thread(tid, resume)?;
+            }
+            thread.running = Some(resume.without_signal());
+            resumed = true;
+        }
+        drop(threads);
+
+        if resumed {
+            unsafe {
+                self.wait_event(interrupt)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn non_stop(&self) -> bool {
+        true
+    }
+
+    fn start_threads(&self, actions: &[(u32, Resume)]) -> Result<()> {
+        let mut threads = self.threads.borrow_mut();
+        for &(tid, resume) in actions {
+            let tid = self.real(tid);
+            let thread = match threads.get_mut(&tid) {
+                Some(thread) if thread.running.is_none() => thread,
+                _ => continue,
+            };
+            if let Some(status) = thread.pending.take() {
+                debug!("Thread {} resumed with pending status {:#X}", tid, status);
+                self.events.borrow_mut().push_back((tid, status));
+                continue;
+            }
+            unsafe {
+                resume_thread(tid, resume)?;
+            }
+            thread.running = Some(resume.without_signal());
+        }
+        Ok(())
+    }
+
+    fn stop_threads(&self, tids: &[u32]) -> Result<()> {
+        let mut threads = self.threads.borrow_mut();
+        for &tid in tids {
+            let tid = self.real(tid);
+            let thread = match threads.get_mut(&tid) {
+                Some(thread) if thread.running.is_some() => thread,
+                _ => continue,
+            };
+            if !thread.stop_expected {
+                unsafe {
+                    e!(libc::syscall(libc::SYS_tgkill, self.pid, tid, libc::SIGSTOP));
+                }
+                thread.stop_expected = true;
+            }
+            thread.stop_requested = true;
+        }
+        Ok(())
+    }
+
+    fn poll_stop(&self) -> Result<bool> {
+        if let Some((tid, status)) = self.events.borrow_mut().pop_front() {
             self.last_status.set(status);
+            self.current.set(tid);
+            return Ok(true);
+        }
+        for tid in self.tasks() {
+            let mut status = 0;
+            unsafe {
+                if e!(libc::waitpid(tid, &mut status, libc::WNOHANG | libc::__WALL)) != 0 && self.handle_status(tid, status)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn stop_running(&self) -> Result<()> {
+        unsafe {
+            self.stop_all()?;
+        }
+        // Back to being reported the next time they're resumed
+        let mut threads = self.threads.borrow_mut();
+        for (tid, status) in self.events.borrow_mut().drain(..) {
+            if let Some(thread) = threads.get_mut(&tid) {
+                thread.pending = Some(status);
+            }
         }
         Ok(())
     }
 
     fn path(&self, pid: usize) -> Result<Vec<u8>> {
         let mut path = PathBuf::from("/proc");
-        path.push(pid.to_string());
+        path.push(self.real(pid as u32).to_string());
         path.push("exe");
 
         let link = path.read_link().map_err(|err| {
@@ -407,10 +1120,184 @@ impl super::Target for Os {
         })?;
         Ok(Vec::from(link.as_os_str().as_bytes()))
     }
+
+    fn thread_info(&self, tid: u32) -> ThreadInfo {
+        let mut dir = PathBuf::from("/proc");
+        dir.push(self.pid.to_string());
+        dir.push("task");
+        dir.push(self.real(tid).to_string());
+
+        let name = fs::read_to_string(dir.join("comm"))
+            .ok()
+            .map(|name| String::from(name.trim_end_matches('\n')));
+
+        let (state, core) = parse_stat(&fs::read_to_string(dir.join("stat")).unwrap_or_default());
+        ThreadInfo { name, state, core }
+    }
+
+    fn mappings(&self) -> Result<Vec<Mapping>> {
+        let path = format!("/proc/{}/maps", self.pid);
+        let maps = fs::read_to_string(&path).map_err(|err| {
+            warn!("failed to read {}: {}", path, err);
+            Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8)
+        })?;
+        Ok(maps.lines().filter_map(parse_mapping).collect())
+    }
+
+    fn auxv(&self) -> Result<Vec<u8>> {
+        let path = format!("/proc/{}/auxv", self.pid);
+        fs::read(&path).map_err(|err| {
+            warn!("failed to read {}: {}", path, err);
+            Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8)
+        })
+    }
+
+    fn signal_masks(&self) -> Result<SignalMasks> {
+        let path = format!("/proc/{}/task/{}/status", self.pid, self.current.get());
+        let status = fs::read_to_string(&path).map_err(|err| {
+            warn!("failed to read {}: {}", path, err);
+            Error::Error(err.raw_os_error().unwrap_or(libc::EIO) as u8)
+        })?;
+        Ok(parse_signal_masks(&status))
+    }
+
+    fn detach(&self) -> Result<()> {
+        let mut threads = self.threads.borrow_mut();
+
+        // Signals the threads stopped for and weren't resumed with yet, which
+        // they get once detached
+        let mut signals = BTreeMap::new();
+        signals.insert(self.current.get(), pending_signal(self.last_status.get()));
+        for (&tid, thread) in threads.iter() {
+            if let Some(status) = thread.pending {
+                signals.insert(tid, pending_signal(status));
+            }
+        }
+        for (tid, status) in self.events.borrow_mut().drain(..) {
+            signals.insert(tid, pending_signal(status));
+        }
+
+        let mut tids: Vec<libc::pid_t> = threads.keys().copied().collect();
+        unsafe {
+            while let Some(tid) = tids.pop() {
+                // A SIGSTOP of ours still queued would stop the thread once
+                // detached, so it's let through first
+                if threads[&tid].stop_expected {
+                    let mut status = 0;
+                    loop {
+                        e!(libc::ptrace(libc::PTRACE_CONT, tid, 0, 0));
+                        e!(libc::waitpid(tid, &mut status, libc::__WALL));
+                        if !libc::WIFSTOPPED(status) || libc::WSTOPSIG(status) == libc::SIGSTOP {
+                            break;
+                        }
+                        if is_event(status, libc::PTRACE_EVENT_CLONE) {
+                            tids.push(self.add_clone(&mut threads, tid)?);
+                        } else if pending_signal(status) != 0 {
+                            signals.insert(tid, pending_signal(status));
+                        }
+                    }
+                    if !libc::WIFSTOPPED(status) {
+                        continue;
+                    }
+                }
+                let signal = signals.get(&tid).copied().unwrap_or(0);
+                e!(libc::ptrace(libc::PTRACE_DETACH, tid, 0, signal));
+            }
+        }
+        threads.clear();
+        self.detached.set(true);
+        Ok(())
+    }
+
+    fn detached(&self) -> bool {
+        self.detached.get()
+    }
+
+    fn take_over(&self, old: &Self) {
+        self.alias.set(Some(old.pid() as libc::pid_t));
+    }
+
+    fn fork(&self) -> Result<Self> {
+        let tid = self.current.get();
+        // All of them, as setregs would lose orig_rax, which decides whether
+        // a system call the thread is stopped in gets restarted
+        let regs = unsafe { raw_regs(tid)? };
+        let rip = regs.rip as usize;
+        let mut code = [0; SYSCALL.len()];
+        self.getmem(rip, &mut code)?;
+
+        let mut call = regs;
+        call.rax = libc::SYS_fork as u64;
+        // Not in a system call, so there's nothing to restart instead
+        call.orig_rax = u64::MAX;
+        self.setmem(rip, &SYSCALL)?;
+
+        let child = unsafe {
+            e!(libc::ptrace(libc::PTRACE_SETOPTIONS, tid, 0, OPTIONS | libc::PTRACE_O_TRACEFORK));
+            let child = self.follow_fork(tid, &call);
+            e!(libc::ptrace(libc::PTRACE_SETOPTIONS, tid, 0, OPTIONS));
+            child
+        };
+
+        // Both end up where the thread was before the system call
+        self.setmem(rip, &code)?;
+        unsafe { set_raw_regs(tid, &regs)? };
+        let child = child?;
+        debug!("Forked thread {} into {}", tid, child);
+        let copy = Os {
+            pid: child,
+            last_status: Cell::new(self.last_status.get()),
+            detached: Cell::new(false),
+            threads: RefCell::new(iter::once((child, Thread::default())).collect()),
+            current: Cell::new(child),
+            events: RefCell::new(VecDeque::new()),
+            alias: Cell::new(None),
+            sigchld: unsafe { e!(libc::dup(self.sigchld)) },
+        };
+        copy.setmem(rip, &code)?;
+        unsafe { set_raw_regs(child, &regs)? };
+        Ok(copy)
+    }
+
+    fn kill(&self) -> Result<()> {
+        unsafe {
+            e!(libc::kill(self.pid, libc::SIGKILL));
+
+            // Skip any stops that were already pending, and reap the other
+            // threads, as the main thread is only reported after them. No new
+            // ones can come along once it's killed.
+            let mut tids: Vec<libc::pid_t> = self.tasks().into_iter().filter(|&tid| tid != self.pid).collect();
+            tids.push(self.pid);
+            let mut status = 0;
+            for tid in tids {
+                loop {
+                    let result = libc::waitpid(tid, &mut status, libc::__WALL);
+                    if result == -1 && tid != self.pid {
+                        // Reaped already
+                        break;
+                    }
+                    e!(result);
+                    if !libc::WIFSTOPPED(status) {
+                        break;
+                    }
+                }
+            }
+            self.last_status.set(status);
+        }
+        self.threads.borrow_mut().clear();
+        self.current.set(self.pid);
+        Ok(())
+    }
 }
 impl Drop for Os {
     fn drop(&mut self) {
         unsafe {
+            libc::close(self.sigchld);
+            let status = self.last_status.get();
+            if self.detached.get() || libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                // Not ours to kill, and the PID might even be reused by now
+                return;
+            }
             libc::kill(self.pid, libc::SIGTERM);
         }
     }
@@ -418,7 +1305,76 @@ impl Drop for Os {
 
 #[cfg(test)]
 mod tests {
-    use std::{cell::Cell, mem};
+    use super::{raw_regs, Launch, Mapping, Os, Resume, SignalMasks, Stdio};
+    use crate::os::{Interrupt, Target, INTERRUPT};
+
+    use gdb_remote_protocol::StopReason;
+    use std::{
+        cell::Cell,
+        fs::{self, File},
+        io::Write,
+        mem,
+        os::unix::io::{AsRawFd, FromRawFd, RawFd},
+        process, ptr, thread,
+        time::{Duration, Instant},
+    };
+
+    /// GDB's side of the connection as a pipe, where anything arriving is an
+    /// interrupt
+    struct Pipe(File);
+    impl Interrupt for Pipe {
+        fn fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+
+        fn interrupted(&self) -> bool {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut pollfd, 1, 0) > 0 }
+        }
+    }
+
+    #[test]
+    fn parse_mapping() {
+        let line = "7f1c2a400000-7f1c2a428000 r-xp 00002000 fd:01 1835232                    /usr/lib/libc.so.6";
+        assert_eq!(super::parse_mapping(line), Some(Mapping {
+            start: 0x7f1c2a400000,
+            end: 0x7f1c2a428000,
+            read: true,
+            write: false,
+            execute: true,
+            shared: false,
+            offset: 0x2000,
+            name: String::from("/usr/lib/libc.so.6"),
+        }));
+        let anonymous = super::parse_mapping("7ffd1c3e0000-7ffd1c401000 rw-p 00000000 00:00 0").unwrap();
+        assert_eq!((anonymous.write, &*anonymous.name), (true, ""));
+    }
+
+    #[test]
+    fn parse_stat() {
+        // Named "a) b", last run on core 3
+        let stat = "1234 (a) b) t 1 1234 1234 0 -1 4194304 105 0 0 0 0 0 0 0 20 0 1 0 8140 2568192 128 \
+                    18446744073709551615 1 1 0 0 0 0 0 0 2 0 0 0 17 3 0 0 0 0 0 0 0 0 0 0 0 0 0\n";
+        assert_eq!(super::parse_stat(stat), (Some("tracing stop"), Some(3)));
+        assert_eq!(super::parse_stat("1234 (a) b) W 1"), (None, None));
+        assert_eq!(super::parse_stat(""), (None, None));
+    }
+
+    #[test]
+    fn parse_signal_masks() {
+        let status = "Name:\tsleep\nSigQ:\t0/63448\nSigPnd:\t0000000000000000\nShdPnd:\t0000000000000100\n\
+                      SigBlk:\t0000000000010000\nSigIgn:\t0000000000000004\nSigCgt:\t0000000180000200\n";
+        assert_eq!(super::parse_signal_masks(status), SignalMasks {
+            pending: 0x100,
+            blocked: 0x1_0000,
+            ignored: 0x4,
+            caught: 0x1_8000_0200,
+        });
+    }
 
     #[test]
     fn getmem() {
@@ -454,4 +1410,107 @@ mod tests {
         .unwrap();
         assert_eq!(&source.get(), b"tesXXXXXXXXXtwo three");
     }
+
+    #[test]
+    fn redirect() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("gdbserver-stdin-{}", process::id()));
+        let output = dir.join(format!("gdbserver-output-{}", process::id()));
+        fs::write(&input, "in\n").unwrap();
+        let mut launch = Launch::new(&["sh", "-c", "read line; echo $line; echo err >&2"]);
+        launch.stdin = Stdio::File(input.clone());
+        launch.stdout = Stdio::File(output.clone());
+        launch.stderr = Stdio::File(output.clone());
+        let tracee = Os::new(&launch).unwrap();
+
+        for (fd, path) in [(0, &input), (1, &output), (2, &output)] {
+            assert_eq!(&fs::read_link(format!("/proc/{}/fd/{}", tracee.pid, fd)).unwrap(), path);
+        }
+        tracee.resume_threads(&[(tracee.current_thread(), Resume::Continue(None))], None).unwrap();
+        assert_eq!(tracee.status(), StopReason::Exited(tracee.pid as u64, 0));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "in\nerr\n");
+
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+
+    /// Detach while the tracee is stopped for `signal`, with a SIGSTOP of
+    /// ours still queued behind it, and return how it ends up on its own
+    fn detach_stopped_for(signal: libc::c_int) -> libc::c_int {
+        let tracee = Os::new(&Launch::new(&["sleep", "0.1"])).unwrap();
+        let tid = tracee.current_thread();
+        tracee.start_threads(&[(tid, Resume::Continue(None))]).unwrap();
+        // Signals below SIGSTOP sent to the thread are delivered ahead of it
+        unsafe { libc::syscall(libc::SYS_tgkill, tracee.pid, tracee.pid, signal) };
+        tracee.stop_threads(&[tid]).unwrap();
+        while !tracee.poll_stop().unwrap() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(tracee.status_native(), StopReason::Signal(signal as u8));
+
+        tracee.detach().unwrap();
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(tracee.pid, &mut status, libc::WUNTRACED) }, tracee.pid);
+        status
+    }
+
+    #[test]
+    fn detach() {
+        // Runs to the end untraced, without stopping for the SIGSTOP
+        let status = detach_stopped_for(libc::SIGCHLD);
+        assert!(unsafe { libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 }, "{:#x}", status);
+        // The signal it stopped for is passed on
+        let status = detach_stopped_for(libc::SIGUSR1);
+        assert!(unsafe { libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGUSR1 }, "{:#x}", status);
+    }
+
+    #[test]
+    fn kill() {
+        let tracee = Os::new(&Launch::new(&["sleep", "10"])).unwrap();
+        tracee.kill().unwrap();
+        assert_eq!(tracee.status_native(), StopReason::ExitedWithSignal(tracee.pid as u64, libc::SIGKILL as u8));
+        assert!(tracee.threads().is_empty());
+        // Reaped already
+        assert_eq!(unsafe { libc::waitpid(tracee.pid, ptr::null_mut(), libc::WNOHANG) }, -1);
+    }
+
+    #[test]
+    fn checkpoint_left_alone() {
+        let launch = Launch::new(&["sleep", "0.1"]);
+        let tracee = Os::new(&launch).unwrap();
+        // Still in the execve, which orig_rax says
+        let regs = unsafe { raw_regs(tracee.pid) }.unwrap();
+        let checkpoint = tracee.fork().unwrap();
+        for pid in &[tracee.pid, checkpoint.pid] {
+            let copy = unsafe { raw_regs(*pid) }.unwrap();
+            assert_eq!((copy.rip, copy.rax, copy.orig_rax), (regs.rip, regs.rax, regs.orig_rax));
+        }
+
+        // A checkpoint dying while the tracee runs isn't one of its threads
+        unsafe { libc::kill(checkpoint.pid, libc::SIGKILL) };
+        tracee.resume_threads(&[(tracee.current_thread(), Resume::Continue(None))], None).unwrap();
+        assert_eq!(tracee.status(), StopReason::Exited(tracee.pid as u64, 0));
+        assert!(tracee.threads().is_empty());
+    }
+
+    #[test]
+    fn interrupt() {
+        let launch = Launch::new(&["sleep", "10"]);
+        let tracee = Os::new(&launch).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, mut writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        // The wait for the tracee wakes up for the connection too
+        let start = Instant::now();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            writer.write_all(&[INTERRUPT]).unwrap();
+        });
+        let resume = [(tracee.current_thread(), Resume::Continue(None))];
+        tracee.resume_threads(&resume, Some(&Pipe(reader))).unwrap();
+        sender.join().unwrap();
+        assert_eq!(tracee.status_native(), StopReason::Signal(libc::SIGINT as u8));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
 }
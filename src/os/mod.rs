@@ -1,9 +1,25 @@
-use crate::Result;
+use crate::{
+    x86::{self, Flow, INT3},
+    Result,
+};
 
-use std::ops::RangeBounds;
+use std::{
+    cmp::min,
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    ops::{Bound, RangeBounds},
+    os::unix::io::RawFd,
+    path::PathBuf,
+};
 
-use gdb_remote_protocol::{StopReason, Signal};
+use gdb_remote_protocol::{Error, StopReason, Signal};
 
+// Most bytes to decode ahead before running to where decoding stopped
+const MAX_SCAN: usize = 4096;
+// The byte GDB sends to stop the tracee, when the user presses Ctrl-C
+pub const INTERRUPT: u8 = 0x03;
+
+mod corefile;
 mod regs;
 
 #[cfg(target_os = "linux")]
@@ -13,12 +29,148 @@ mod sys;
 #[path = "redox.rs"]
 mod sys;
 
+pub use corefile::CoreFile;
 pub use regs::Registers;
 pub use sys::Os;
+#[cfg(target_os = "linux")]
+pub use sys::block_sigchld;
+
+/// Where to connect one of the standard streams of a new tracee
+#[derive(Clone, Debug)]
+pub enum Stdio {
+    /// Share the stream with the server
+    Inherit,
+    /// Connect the stream to the null device
+    Null,
+    /// Connect the stream to the server's stderr
+    Stderr,
+    /// Connect the stream to the specified file, truncating it if it's an output
+    File(PathBuf),
+}
+
+/// Everything needed to spawn a new tracee
+#[derive(Clone, Debug)]
+pub struct Launch {
+    pub program: String,
+    pub args: Vec<String>,
+    /// The complete environment of the tracee
+    pub env: BTreeMap<OsString, OsString>,
+    /// Directory to run the tracee in, instead of the server's
+    pub cwd: Option<PathBuf>,
+    /// Launch the program through `$SHELL -c exec ...`, so the arguments may
+    /// use globs and redirections
+    pub startup_with_shell: bool,
+    /// Disable address space randomization, so addresses are the same on
+    /// every run. Not all systems randomize in the first place.
+    pub disable_aslr: bool,
+    /// Terminal to run the tracee on, in a session of its own. Individual
+    /// streams may still be redirected elsewhere.
+    pub tty: Option<PathBuf>,
+    pub stdin: Stdio,
+    pub stdout: Stdio,
+    pub stderr: Stdio,
+}
+
+impl Launch {
+    /// Return the program and arguments to execute, and how many times the
+    /// tracee will exec before it's running the actual program
+    pub fn command(&self) -> (String, Vec<String>, usize) {
+        if !self.startup_with_shell {
+            return (self.program.clone(), self.args.clone(), 1);
+        }
+
+        let shell = self.env.get(OsStr::new("SHELL"))
+            .and_then(|shell| shell.to_str())
+            .filter(|shell| !shell.is_empty())
+            .unwrap_or("/bin/sh")
+            .to_string();
+
+        // Only the program is quoted, the arguments are meant for the shell
+        let mut line = format!("exec '{}'", self.program.replace('\'', "'\\''"));
+        for arg in self.args.iter().skip(1) {
+            line.push(' ');
+            line.push_str(arg);
+        }
+
+        (shell.clone(), vec![shell, String::from("-c"), line], 2)
+    }
+
+    /// Run `args` with the server's environment and nothing on stdin, as the
+    /// tests do
+    #[cfg(test)]
+    pub fn new(args: &[&str]) -> Self {
+        Self {
+            program: String::from(args[0]),
+            args: args.iter().map(|&arg| String::from(arg)).collect(),
+            env: std::env::vars_os().collect(),
+            cwd: None,
+            startup_with_shell: false,
+            disable_aslr: false,
+            tty: None,
+            stdin: Stdio::Null,
+            stdout: Stdio::Inherit,
+            stderr: Stdio::Inherit,
+        }
+    }
+}
+
+/// What the system knows about a thread
+#[derive(Debug, Default)]
+pub struct ThreadInfo {
+    pub name: Option<String>,
+    /// Human-readable scheduling state, such as "sleeping"
+    pub state: Option<&'static str>,
+    /// The CPU core the thread last ran on
+    pub core: Option<u32>,
+}
+
+/// A region of the tracee's address space
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// Whether it's shared with other processes instead of private
+    pub shared: bool,
+    /// Where in the file it starts
+    pub offset: u64,
+    /// The file mapped, or a description such as "[stack]", if any
+    pub name: String,
+}
+
+/// What a thread does with each signal, as masks where bit N - 1 stands for
+/// signal N
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SignalMasks {
+    /// Signals sent to the thread or its process, but not delivered yet
+    pub pending: u64,
+    pub blocked: u64,
+    pub ignored: u64,
+    /// Signals with a handler installed
+    pub caught: u64,
+}
+
+/// How to resume a thread, optionally delivering a signal to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resume {
+    Continue(Option<u8>),
+    Step(Option<u8>),
+}
+impl Resume {
+    /// The same action, for resuming again after the signal was delivered
+    pub fn without_signal(self) -> Self {
+        match self {
+            Resume::Continue(_) => Resume::Continue(None),
+            Resume::Step(_) => Resume::Step(None),
+        }
+    }
+}
 
 pub trait Target: Sized {
     /// Spawn a new tracee and return a tracer for it
-    fn new(program: String, args: Vec<String>) -> Result<Os, Box<dyn std::error::Error>>;
+    fn new(launch: &Launch) -> Result<Self, Box<dyn std::error::Error>>;
 
     /// Get the last status of the tracee
     fn status_native(&self) -> StopReason;
@@ -55,24 +207,252 @@ pub trait Target: Sized {
     /// Single-step one instruction, return instruction pointer
     fn step(&self, signal: Option<u8>) -> Result<Option<u64>>;
 
-    /// Resume execution while instruction pointer is inside the range
-    fn resume<R>(&self, range: R) -> Result<()>
+    /// Resume execution while instruction pointer is inside the range. The
+    /// current thread runs from one way out of each straight run of
+    /// instructions to the next using temporary breakpoints, and is only
+    /// single-stepped where the way out isn't known in advance.
+    fn resume<R>(&self, range: R, interrupt: Option<&dyn Interrupt>) -> Result<()>
     where
         R: RangeBounds<u64>,
     {
         loop {
-            let rip = self.step(None)?;
-            //println!("{:X?}", rip);
-            if rip.map(|rip| !range.contains(&rip)).unwrap_or(true) {
-                break;
+            let rip = match self.getregs()?.rip {
+                Some(rip) if range.contains(&rip) => rip,
+                _ => break,
+            };
+
+            match range_exits(self, &range, rip) {
+                // It can't run to where it already is
+                Some(exits) if !exits.contains(&rip) => {
+                    if !run_to(self, &exits, interrupt)? {
+                        break;
+                    }
+                },
+                _ => {
+                    if self.step(None)?.is_none() {
+                        break;
+                    }
+                },
             }
         }
         Ok(())
     }
 
-    /// Continue execution until signal or other breakpoint
-    fn cont(&self, signal: Option<u8>) -> Result<()>;
+    /// Return the IDs of all threads of the tracee
+    fn threads(&self) -> Vec<u32>;
+
+    /// Select the thread that registers and memory are accessed through
+    fn select_thread(&self, tid: u32) -> Result<()>;
+
+    /// Return the selected thread, which is the one that last stopped
+    /// unless GDB has selected another one since
+    fn current_thread(&self) -> u32;
+
+    /// Resume each of the specified threads as requested, leaving all others
+    /// stopped, and wait until one of them stops. The others are then stopped
+    /// too. If there's a connection to watch for interrupts, GDB asking for
+    /// one stops the tracee with SIGINT.
+    fn resume_threads(&self, actions: &[(u32, Resume)], interrupt: Option<&dyn Interrupt>) -> Result<()>;
+
+    /// Whether threads can be run and stopped on their own, while others
+    /// keep running or stay stopped, for GDB's non-stop mode
+    fn non_stop(&self) -> bool {
+        false
+    }
+
+    /// Resume each of the specified threads as requested without waiting for
+    /// them, leaving all others as they are. Threads that are running
+    /// already keep running.
+    fn start_threads(&self, _actions: &[(u32, Resume)]) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Ask the specified threads to stop, if they're running. The stops are
+    /// reported by `poll_stop` without a signal, unless the threads stop for
+    /// something else first.
+    fn stop_threads(&self, _tids: &[u32]) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Check without waiting whether a thread started by `start_threads`
+    /// stopped for a reason GDB should know about, leaving the others
+    /// running. If one did, it becomes the current thread, the status is what
+    /// it stopped for, and true is returned.
+    fn poll_stop(&self) -> Result<bool> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Stop all threads still running from `start_threads`, for when they
+    /// have to be stopped together again. What they stopped for is reported
+    /// once they're resumed.
+    fn stop_running(&self) -> Result<()> {
+        Ok(())
+    }
 
     /// Return the executable that's being run for specified PID
     fn path(&self, pid: usize) -> Result<Vec<u8>>;
+
+    /// Return what's known about the specified thread of the tracee
+    fn thread_info(&self, tid: u32) -> ThreadInfo;
+
+    /// Return all regions mapped into the tracee's address space, in order
+    fn mappings(&self) -> Result<Vec<Mapping>>;
+
+    /// Return the auxiliary vector the tracee was started with
+    fn auxv(&self) -> Result<Vec<u8>>;
+
+    /// Return what the selected thread does with each signal
+    fn signal_masks(&self) -> Result<SignalMasks>;
+
+    /// Stop tracing the tracee, and let it continue running on its own
+    fn detach(&self) -> Result<()>;
+
+    /// Returns true if the tracee has been detached
+    fn detached(&self) -> bool;
+
+    /// Fork the tracee by making its current thread call fork, which leaves
+    /// the child as a stopped copy of it to go back to later. Only the current
+    /// thread is copied, like in any fork.
+    fn fork(&self) -> Result<Self>;
+
+    /// Make a copy from `fork` stand in for `old`, answering to its process
+    /// ID and to its ID for the main thread, as GDB can't be told they
+    /// changed. Backends that can't fork have nothing to do.
+    fn take_over(&self, _old: &Self) {}
+
+    /// Kill the tracee and wait for it to exit
+    fn kill(&self) -> Result<()>;
+}
+
+/// Find the addresses where execution may leave the straight run of
+/// instructions at the instruction pointer, or None if the instruction there
+/// can't be decoded
+fn range_exits<T, R>(tracee: &T, range: &R, rip: u64) -> Option<Vec<u64>>
+where
+    T: Target,
+    R: RangeBounds<u64>,
+{
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end.saturating_add(1),
+        Bound::Excluded(&end) => end,
+        Bound::Unbounded => u64::MAX,
+    };
+    let scan = min(end.saturating_sub(rip), MAX_SCAN as u64) as usize;
+    let mut code = vec![0; scan + x86::MAX_LEN];
+    tracee.getmem(rip as usize, &mut code).ok()?;
+
+    let mut address = rip;
+    let mut offset = 0;
+    while offset < scan {
+        if !range.contains(&address) {
+            break;
+        }
+        let instruction = match x86::decode(&code[offset..], address) {
+            Some(instruction) => instruction,
+            // Run to it, and single-step it from there
+            None if offset > 0 => break,
+            None => return None,
+        };
+        let next = address + instruction.len as u64;
+
+        match instruction.flow {
+            Flow::Next => (),
+            Flow::Branch(target) => return Some(vec![target, next]),
+            Flow::Jump(target) | Flow::Call(target) => return Some(vec![target]),
+            Flow::Indirect => return Some(vec![address]),
+            // Stops the tracee without any help
+            Flow::Trap => return Some(Vec::new()),
+        }
+
+        address = next;
+        offset += instruction.len;
+    }
+    Some(vec![address])
+}
+
+/// GDB's side of the connection while the tracee runs, where it can ask for
+/// the tracee to be interrupted
+pub trait Interrupt {
+    /// Where more input arrives, to wait on
+    fn fd(&self) -> RawFd;
+
+    /// Take in whatever input has arrived without waiting for more, and
+    /// check it for GDB's interrupt byte. Anything else is kept for the packet
+    /// loop. A hangup counts as an interrupt too.
+    fn interrupted(&self) -> bool;
+}
+
+/// Check without waiting whether GDB asked for an interrupt, for when the
+/// tracee is run in small steps
+pub fn interrupted(interrupt: Option<&dyn Interrupt>) -> bool {
+    match interrupt {
+        Some(interrupt) => interrupt.interrupted(),
+        None => false,
+    }
+}
+
+/// Continue the current thread until it reaches one of the addresses, using
+/// temporary breakpoints. Returns false if it stopped for any other reason.
+fn run_to<T: Target>(tracee: &T, addresses: &[u64], interrupt: Option<&dyn Interrupt>) -> Result<bool> {
+    let mut planted: Vec<(u64, u8)> = Vec::new();
+    for &address in addresses {
+        let mut byte = [0];
+        // Anything unreadable faults, and a breakpoint that's already there
+        // stops it regardless
+        if planted.iter().any(|&(planted, _)| planted == address)
+            || tracee.getmem(address as usize, &mut byte).is_err()
+            || byte[0] == INT3
+            || tracee.setmem(address as usize, &[INT3]).is_err()
+        {
+            continue;
+        }
+        planted.push((address, byte[0]));
+    }
+
+    let result = tracee.resume_threads(&[(tracee.current_thread(), Resume::Continue(None))], interrupt);
+    for &(address, byte) in planted.iter().rev() {
+        tracee.setmem(address as usize, &[byte])?;
+    }
+    result?;
+
+    let trapped = match tracee.status_native() {
+        StopReason::Signal(signal) => libc::c_int::from(signal) == libc::SIGTRAP,
+        _ => false,
+    };
+    let mut regs = tracee.getregs()?;
+    let hit = regs.rip
+        .map(|rip| rip.wrapping_sub(1))
+        .filter(|&rip| trapped && planted.iter().any(|&(address, _)| address == rip));
+    match hit {
+        Some(address) => {
+            // Back to the instruction that was replaced
+            regs.rip = Some(address);
+            tracee.setregs(&regs)?;
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Launch;
+
+    #[test]
+    fn startup_with_shell() {
+        let mut launch = Launch::new(&["it's", "*.c", ">out"]);
+        launch.env = vec![("SHELL".into(), "/bin/bash".into())].into_iter().collect();
+        assert_eq!(launch.command(), (launch.program.clone(), launch.args.clone(), 1));
+
+        launch.startup_with_shell = true;
+        assert_eq!(launch.command(), (
+            String::from("/bin/bash"),
+            vec![
+                String::from("/bin/bash"),
+                String::from("-c"),
+                String::from("exec 'it'\\''s' *.c >out"),
+            ],
+            2,
+        ));
+    }
 }
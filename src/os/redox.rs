@@ -1,23 +1,31 @@
-use super::{Target, Registers};
+use super::{Interrupt, Launch, Mapping, Registers, Resume, SignalMasks, Stdio, Target, ThreadInfo};
 use crate::Result;
 
 use std::{
-    cell::{Cell, RefCell},
+    cell::{Cell, RefCell, RefMut},
+    ffi::OsStr,
     fs,
-    io,
+    io::{self, prelude::*},
     mem,
     os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
 };
 
+use byteorder::{NativeEndian, ReadBytesExt};
 use gdb_remote_protocol::{Error, StopReason};
-use log::error;
-use strace::{EventData, Flags, Tracer};
+use log::{debug, error};
+use strace::{Event, EventData, Flags, Tracer};
 use syscall::flag::*;
 
 pub struct Os {
     pid: usize,
     last_status: Cell<usize>,
-    tracer: RefCell<Tracer>,
+    /// The tracer, until the tracee is released by detaching
+    tracer: RefCell<Option<Tracer>>,
+    detached: Cell<bool>,
 }
 
 trait FromOsError<T>: Sized {
@@ -59,6 +67,9 @@ macro_rules! e {
     }};
 }
 
+// How often to check for interrupts while the tracee runs, in ms
+const INTERRUPT_POLL: u64 = 50;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum ProcessState {
     Running,
@@ -66,12 +77,54 @@ enum ProcessState {
 }
 
 impl Os {
+    fn tracer(&self) -> RefMut<'_, Tracer> {
+        RefMut::map(self.tracer.borrow_mut(), |tracer| tracer.as_mut().expect("tracee was detached"))
+    }
+
+    /// Continue the tracer until its next event, like `Tracer::next`. If
+    /// there's a connection to watch for interrupts, the tracer waits in
+    /// another thread meanwhile, and GDB asking for one sends SIGINT to the
+    /// tracee.
+    fn wait_next(&self, flags: Flags, interrupt: Option<&dyn Interrupt>) -> io::Result<Event> {
+        let interrupt = match interrupt {
+            Some(interrupt) => interrupt,
+            None => return self.tracer().next(flags),
+        };
+
+        let mut tracer = self.tracer.borrow_mut().take().expect("tracee was detached");
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let event = tracer.next(flags);
+            let _ = sender.send((tracer, event));
+        });
+
+        let mut interrupted = false;
+        loop {
+            if !interrupted && interrupt.interrupted() {
+                debug!("Interrupting tracee");
+                // The tracer has to be waited for regardless
+                if let Err(err) = syscall::kill(self.pid, SIGINT) {
+                    error!("Failed to interrupt tracee: {}", err);
+                }
+                interrupted = true;
+            }
+            match receiver.recv_timeout(Duration::from_millis(INTERRUPT_POLL)) {
+                Ok((tracer, event)) => {
+                    *self.tracer.borrow_mut() = Some(tracer);
+                    return event;
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => panic!("tracer thread panicked"),
+            }
+        }
+    }
+
     /// Continues the tracer with the specified flags, and the breakpoint
     /// flag. If the process exits, we waitpid the child and set the status. We
     /// also return `Exited` to signal that the process is no longer
     /// alive. Returns `Running` and sets status to SIGTRAP if the process does
     /// not exit.
-    fn next(&self, signal: Option<u8>, mut flags: Flags) -> Result<ProcessState> {
+    fn next(&self, signal: Option<u8>, mut flags: Flags, interrupt: Option<&dyn Interrupt>) -> Result<ProcessState> {
         match signal {
             Some(signal) => {
                 let prev = match self.status_native() {
@@ -91,9 +144,10 @@ impl Os {
             },
         }
 
-        let mut tracer = self.tracer.borrow_mut();
+        let event = self.wait_next(flags | Flags::STOP_BREAKPOINT | Flags::STOP_SIGNAL, interrupt);
+        let mut tracer = self.tracer();
 
-        match tracer.next(flags | Flags::STOP_BREAKPOINT | Flags::STOP_SIGNAL) {
+        match event {
             Ok(event) => {
                 let signal = if event.cause == Flags::STOP_SIGNAL {
                     let (signal, handler) = match event.data {
@@ -131,8 +185,68 @@ impl Os {
     }
 }
 
+/// Connect a standard stream of the cloned child to where the launch
+/// configuration wants it
+fn redirect(fd: usize, stdio: &Stdio) -> io::Result<()> {
+    let (path, flags) = match stdio {
+        Stdio::Inherit => return Ok(()),
+        Stdio::Stderr => {
+            e!(syscall::dup2(2, fd, &[]));
+            return Ok(());
+        },
+        Stdio::Null => ("null:".as_bytes(), O_RDWR),
+        Stdio::File(path) if fd == 0 => (path.as_os_str().as_bytes(), O_RDONLY),
+        Stdio::File(path) => (path.as_os_str().as_bytes(), O_WRONLY | O_CREAT | O_TRUNC | 0o666),
+    };
+    let file = e!(syscall::open(path, flags));
+    if file != fd {
+        e!(syscall::dup2(file, fd, &[]));
+        e!(syscall::close(file));
+    }
+    Ok(())
+}
+
+/// Read the entry point out of the ELF header of an executable
+fn elf_entry(path: &Path) -> io::Result<usize> {
+    let mut header = [0; 32];
+    fs::File::open(path)?.read_exact(&mut header)?;
+    Ok((&header[24..]).read_u64::<NativeEndian>()? as usize)
+}
+
+/// Find the entry point the program was really given, relocated if it's
+/// position independent, in the auxiliary vector fexec put on the new stack
+/// after argc, argv and the environment.
+fn auxv_entry(tracer: &mut Tracer) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    const AT_NULL: usize = 0;
+    const AT_ENTRY: usize = 9;
+
+    let rsp = e!(tracer.regs.get_int()).rsp;
+    let mut word = |address: usize| -> Result<usize, Box<dyn std::error::Error>> {
+        let mut bytes = [0; 8];
+        e!(tracer.mem.read(address as *const u8, &mut bytes));
+        Ok((&bytes[..]).read_u64::<NativeEndian>()? as usize)
+    };
+
+    // Skip argc, then argv and envp along with the null ending each
+    let mut address = rsp + (word(rsp)? + 2) * 8;
+    while word(address)? != 0 {
+        address += 8;
+    }
+    address += 8;
+
+    loop {
+        match word(address)? {
+            AT_NULL => return Ok(None),
+            AT_ENTRY => return Ok(Some(word(address + 8)?)),
+            _ => address += 16,
+        }
+    }
+}
+
 impl Target for Os {
-    fn new(program: String, args: Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+    fn new(launch: &Launch) -> Result<Self, Box<dyn std::error::Error>> {
+        let (program, args, execs) = launch.command();
+
         unsafe {
             let pid = e!(syscall::clone(CloneFlags::empty()));
 
@@ -142,20 +256,36 @@ impl Target for Os {
                     let args = args.iter()
                         .map(|s| [s.as_ptr() as usize, s.len()])
                         .collect::<Vec<[usize; 2]>>();
-                    let vars = std::env::vars_os()
-                        .map(|(mut key, value)| {
-                            key.push("=");
-                            key.push(&value);
+                    let vars = launch.env.iter()
+                        .map(|(key, value)| {
+                            let mut var = key.clone();
+                            var.push("=");
+                            var.push(value);
 
-                            let slice = key.as_bytes();
+                            let slice = var.as_bytes();
                             let res = [slice.as_ptr() as usize, slice.len()];
-                            mem::forget(key);
+                            mem::forget(var);
                             res
                         })
                         .collect::<Vec<[usize; 2]>>();
 
+                    if let Some(cwd) = &launch.cwd {
+                        e!(syscall::chdir(cwd.as_os_str().as_bytes()));
+                    }
                     let program = e!(syscall::open(program.as_bytes(), O_RDONLY | O_CLOEXEC));
 
+                    if let Some(tty) = &launch.tty {
+                        // There are no sessions to speak of, just share the terminal
+                        let file = e!(syscall::open(tty.as_os_str().as_bytes(), O_RDWR));
+                        for fd in 0..=2 {
+                            e!(syscall::dup2(file, fd, &[]));
+                        }
+                        e!(syscall::close(file));
+                    }
+                    redirect(0, &launch.stdin)?;
+                    redirect(1, &launch.stdout)?;
+                    redirect(2, &launch.stderr)?;
+
                     let pid = e!(syscall::getpid());
                     e!(syscall::kill(pid, SIGSTOP));
 
@@ -183,19 +313,48 @@ impl Target for Os {
                 // Attach tracer
                 let mut tracer = e!(Tracer::attach(pid));
 
-                // Step past fexec
+                // Step past fexec, and the one of the shell if there is one
                 e!(syscall::kill(pid, SIGCONT));
-                e!(tracer.next(Flags::STOP_PRE_SYSCALL));
-                assert_eq!(e!(tracer.regs.get_int()).return_value(), syscall::SYS_FEXEC);
+                for _ in 0..execs {
+                    loop {
+                        e!(tracer.next(Flags::STOP_PRE_SYSCALL));
+                        if e!(tracer.regs.get_int()).return_value() == syscall::SYS_FEXEC {
+                            break;
+                        }
+                    }
+                }
 
-                // TODO: Don't stop only on syscall, stop on first instruction.
-                // Single-stepping doesn't work across fexec yet for some reason.
+                // Single-stepping doesn't work across fexec yet for some
+                // reason, and only stops at the syscall. So run up to the
+                // first instruction with a breakpoint instead.
                 e!(tracer.next(Flags::STOP_SINGLESTEP));
 
+                // The header's entry point is only right if the program
+                // wasn't relocated, as a fallback for when fexec leaves it out
+                let entry = match auxv_entry(&mut tracer)? {
+                    Some(entry) => entry,
+                    None => {
+                        let exe = e!(fs::read(format!("proc:{}/exe", pid)));
+                        e!(elf_entry(Path::new(OsStr::from_bytes(&exe))))
+                    },
+                };
+                if e!(tracer.regs.get_int()).rip != entry {
+                    let mut original = [0];
+                    e!(tracer.mem.read(entry as *const u8, &mut original));
+                    e!(tracer.mem.write(entry as *mut u8, &[0xCC]));
+                    e!(tracer.next(Flags::STOP_BREAKPOINT));
+                    e!(tracer.mem.write(entry as *mut u8, &original));
+
+                    let mut regs = e!(tracer.regs.get_int());
+                    regs.0.rip = entry;
+                    e!(tracer.regs.set_int(&regs));
+                }
+
                 Ok(Os {
                     pid,
                     last_status: Cell::new(status),
-                    tracer: RefCell::new(tracer),
+                    tracer: RefCell::new(Some(tracer)),
+                    detached: Cell::new(false),
                 })
             }
         }
@@ -224,7 +383,7 @@ impl Target for Os {
     }
 
     fn getregs(&self) -> Result<Registers> {
-        let mut tracer = self.tracer.borrow_mut();
+        let mut tracer = self.tracer();
         let int = e!(tracer.regs.get_int()).0;
         let float = e!(tracer.regs.get_float()).0;
 
@@ -363,7 +522,7 @@ impl Target for Os {
             .unwrap_or(float.xmm_space[15]);
         float.mxcsr = registers.mxcsr.unwrap_or(float.mxcsr);
 
-        let mut tracer = self.tracer.borrow_mut();
+        let mut tracer = self.tracer();
         e!(tracer.regs.set_int(&strace::IntRegisters(int)));
         e!(tracer.regs.set_float(&strace::FloatRegisters(float)));
 
@@ -373,20 +532,20 @@ impl Target for Os {
     fn getmem(&self, address: usize, memory: &mut [u8]) -> Result<usize> {
         // TODO: Don't report errors when able to read part of requested?
         // Also implement this in the Redox kernel perhaps
-        let mut tracer = self.tracer.borrow_mut();
+        let mut tracer = self.tracer();
         e!(tracer.mem.read(address as *const u8, memory));
         Ok(memory.len())
     }
 
     fn setmem(&self, address: usize, memory: &[u8]) -> Result<()> {
-        let mut tracer = self.tracer.borrow_mut();
+        let mut tracer = self.tracer();
         e!(tracer.mem.write(address as *mut u8, memory));
         Ok(())
     }
 
     fn step(&self, signal: Option<u8>) -> Result<Option<u64>> {
-        if self.next(signal, Flags::STOP_SINGLESTEP)? == ProcessState::Running {
-            let mut tracer = self.tracer.borrow_mut();
+        if self.next(signal, Flags::STOP_SINGLESTEP, None)? == ProcessState::Running {
+            let mut tracer = self.tracer();
 
             let rip = e!(tracer.regs.get_int()).rip;
             Ok(Some(rip as _))
@@ -395,11 +554,33 @@ impl Target for Os {
         }
     }
 
-    fn cont(&self, signal: Option<u8>) -> Result<()> {
-        self.next(signal, Flags::empty())?;
+    fn threads(&self) -> Vec<u32> {
+        // Threads can't be traced separately yet
+        vec![self.pid()]
+    }
+
+    fn select_thread(&self, tid: u32) -> Result<()> {
+        if tid != self.pid() {
+            return Err(Error::Error(syscall::ESRCH as u8));
+        }
         Ok(())
     }
 
+    fn current_thread(&self) -> u32 {
+        self.pid()
+    }
+
+    fn resume_threads(&self, actions: &[(u32, Resume)], interrupt: Option<&dyn Interrupt>) -> Result<()> {
+        match actions.iter().find(|(tid, _)| *tid == self.pid()) {
+            Some((_, Resume::Continue(signal))) => {
+                self.next(*signal, Flags::empty(), interrupt)?;
+                Ok(())
+            },
+            Some((_, Resume::Step(signal))) => self.step(*signal).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
     fn path(&self, pid: usize) -> Result<Vec<u8>> {
         let mut path = e!(fs::read(format!("proc:{}/exe", pid)));
 
@@ -410,9 +591,57 @@ impl Target for Os {
 
         Ok(path)
     }
+
+    fn thread_info(&self, _tid: u32) -> ThreadInfo {
+        // Nothing more than the ID is exposed per thread yet
+        ThreadInfo::default()
+    }
+
+    fn mappings(&self) -> Result<Vec<Mapping>> {
+        // TODO: Read the address space from the proc: scheme
+        Err(Error::Unimplemented)
+    }
+
+    fn auxv(&self) -> Result<Vec<u8>> {
+        Err(Error::Unimplemented)
+    }
+
+    fn signal_masks(&self) -> Result<SignalMasks> {
+        Err(Error::Unimplemented)
+    }
+
+    fn detach(&self) -> Result<()> {
+        // Closing the tracer is what releases the tracee
+        self.tracer.borrow_mut().take();
+        self.detached.set(true);
+        Ok(())
+    }
+
+    fn detached(&self) -> bool {
+        self.detached.get()
+    }
+
+    fn fork(&self) -> Result<Self> {
+        // TODO: Make the tracee call fork, once the tracer can follow it
+        Err(Error::Unimplemented)
+    }
+
+    fn kill(&self) -> Result<()> {
+        e!(syscall::kill(self.pid, SIGKILL));
+
+        let mut status = 0;
+        e!(syscall::waitpid(self.pid, &mut status, WaitFlags::empty()));
+        self.last_status.set(status);
+        Ok(())
+    }
 }
 impl Drop for Os {
     fn drop(&mut self) {
+        let status = self.last_status.get();
+        if self.detached.get() || syscall::wifexited(status) || syscall::wifsignaled(status) {
+            // Not ours to kill, and the PID might even be reused by now
+            return;
+        }
         let _ = syscall::kill(self.pid, SIGTERM);
     }
 }
@@ -65,6 +65,73 @@ pub struct Registers {
     pub gs_base: Option<u64>,
 }
 impl Registers {
+    /// Get a register by its number in target-desc.xml, truncated to 64 bits
+    pub fn get(&self, regno: u64) -> Option<u64> {
+        let wide = |reg: Option<u128>| reg.map(|reg| reg as u64);
+        match regno {
+            0 => self.rax,
+            1 => self.rbx,
+            2 => self.rcx,
+            3 => self.rdx,
+            4 => self.rsi,
+            5 => self.rdi,
+            6 => self.rbp,
+            7 => self.rsp,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            16 => self.rip,
+            17 => self.eflags.map(u64::from),
+            18 => self.cs.map(u64::from),
+            19 => self.ss.map(u64::from),
+            20 => self.ds.map(u64::from),
+            21 => self.es.map(u64::from),
+            22 => self.fs.map(u64::from),
+            23 => self.gs.map(u64::from),
+            24 => wide(self.st0),
+            25 => wide(self.st1),
+            26 => wide(self.st2),
+            27 => wide(self.st3),
+            28 => wide(self.st4),
+            29 => wide(self.st5),
+            30 => wide(self.st6),
+            31 => wide(self.st7),
+            32 => self.fctrl.map(u64::from),
+            33 => self.fstat.map(u64::from),
+            34 => self.ftag.map(u64::from),
+            35 => self.fiseg.map(u64::from),
+            36 => self.fioff.map(u64::from),
+            37 => self.foseg.map(u64::from),
+            38 => self.fooff.map(u64::from),
+            39 => self.fop.map(u64::from),
+            40 => wide(self.xmm0),
+            41 => wide(self.xmm1),
+            42 => wide(self.xmm2),
+            43 => wide(self.xmm3),
+            44 => wide(self.xmm4),
+            45 => wide(self.xmm5),
+            46 => wide(self.xmm6),
+            47 => wide(self.xmm7),
+            48 => wide(self.xmm8),
+            49 => wide(self.xmm9),
+            50 => wide(self.xmm10),
+            51 => wide(self.xmm11),
+            52 => wide(self.xmm12),
+            53 => wide(self.xmm13),
+            54 => wide(self.xmm14),
+            55 => wide(self.xmm15),
+            56 => self.mxcsr.map(u64::from),
+            57 => self.fs_base,
+            58 => self.gs_base,
+            _ => None,
+        }
+    }
+
     // The following sadly assume the endianness in order to only read
     // 10 bits in the st* stuff instead of the full 16.
     #[rustfmt::skip] // formatting can only make this horrible code look worse
@@ -0,0 +1,866 @@
+//! Packets the server answers itself, before the protocol library gets to see
+//! them. These are the ones its `Handler` trait has no hook for, and the ones
+//! replied to with stops, which it can't name the thread in. They're picked
+//! out of the connection's input and replied to directly.
+
+use crate::{
+    os::{Interrupt, Target, INTERRUPT},
+    trace::FrameQuery,
+    format_id, App, Result, SharedWriter, ERROR_PARSE_STRING,
+};
+
+use gdb_remote_protocol::{Error, Handler, Id, StopReason, ThreadId, VCont};
+use log::warn;
+
+use std::{
+    cell::RefCell,
+    cmp::min,
+    collections::VecDeque,
+    ffi::OsStr,
+    io::{self, prelude::*},
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::Path,
+};
+
+// How long to wait for input before checking on running threads again, in
+// milliseconds, while in non-stop mode
+const STOP_POLL: libc::c_int = 10;
+
+/// Write a packet, framed and with its checksum
+pub fn write_packet<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    write_framed(writer, '$', data)
+}
+
+/// Write a notification, which is framed like a packet but starts with `%`
+/// and isn't acknowledged
+pub fn write_notification<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    write_framed(writer, '%', data)
+}
+
+fn write_framed<W: Write>(writer: &mut W, start: char, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    write!(writer, "{}{}#{:02x}", start, data, checksum)?;
+    // Flushing sends any acknowledgement still buffered before the packet
+    writer.flush()
+}
+
+pub(crate) fn parse_error() -> Error {
+    Error::Error(ERROR_PARSE_STRING)
+}
+
+/// Decode a hex number, as packet arguments often are
+pub(crate) fn parse_hex(text: &str) -> Result<u64> {
+    u64::from_str_radix(text, 16).map_err(|_| parse_error())
+}
+
+/// Decode hex-encoded bytes, as packet arguments often are
+pub(crate) fn parse_bytes(text: &str) -> Result<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = text.get(i..i + 2).ok_or_else(parse_error)?;
+            u8::from_str_radix(byte, 16).map_err(|_| parse_error())
+        })
+        .collect()
+}
+
+/// The reading half of a GDB connection, with everything that has arrived on
+/// it but not been handled yet
+pub struct Input {
+    reader: RefCell<Box<dyn Read>>,
+    /// Where the input arrives, which `reader` must not buffer from
+    fd: RawFd,
+    buffer: RefCell<VecDeque<u8>>,
+}
+impl Input {
+    pub fn new(reader: Box<dyn Read>, fd: RawFd) -> Self {
+        Input {
+            reader: RefCell::new(reader),
+            fd,
+            buffer: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// Wait for more input and add it to the buffer. Returns false once the
+    /// connection is closed.
+    fn fill(&self) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        let len = loop {
+            match self.reader.borrow_mut().read(&mut buf) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.buffer.borrow_mut().extend(&buf[..len]);
+        Ok(len > 0)
+    }
+
+    /// Wait up to `timeout` milliseconds for input. Returns true if `fill`
+    /// won't block, which includes the connection being closed.
+    fn wait(&self, timeout: libc::c_int) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Errors are left for reading to report
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            -1 => io::Error::last_os_error().kind() != io::ErrorKind::Interrupted,
+            ready => ready > 0,
+        }
+    }
+}
+
+impl Interrupt for Input {
+    fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    fn interrupted(&self) -> bool {
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pollfd, 1, 0) } <= 0 {
+                break;
+            }
+            match self.fill() {
+                Ok(true) => (),
+                // Nobody's left to stop the tracee for GDB
+                Ok(false) | Err(_) => return true,
+            }
+        }
+        take_interrupt(&mut self.buffer.borrow_mut())
+    }
+}
+
+/// Take GDB's interrupt byte out of the buffer if it's there between packets,
+/// where it isn't part of their data
+fn take_interrupt(buffer: &mut VecDeque<u8>) -> bool {
+    let mut in_packet = false;
+    let mut checksum = 0;
+    for (i, &byte) in buffer.iter().enumerate() {
+        if checksum > 0 {
+            checksum -= 1;
+        } else if in_packet {
+            if byte == b'#' {
+                in_packet = false;
+                checksum = 2;
+            }
+        } else if byte == b'$' {
+            in_packet = true;
+        } else if byte == INTERRUPT {
+            buffer.remove(i);
+            return true;
+        }
+    }
+    false
+}
+
+/// The length of the packet at the start of `buffer`, including the `$` and
+/// the checksum, once all of it has arrived
+fn packet_len(buffer: &VecDeque<u8>) -> Option<usize> {
+    let end = buffer.iter().position(|&byte| byte == b'#')?;
+    if buffer.len() < end + 3 {
+        return None;
+    }
+    Some(end + 3)
+}
+
+/// The contents of a framed packet, if its checksum is right
+fn packet_data(packet: &[u8]) -> Option<&[u8]> {
+    let (data, checksum) = packet[1..].split_at(packet.len() - 4);
+    let checksum = u8::from_str_radix(std::str::from_utf8(&checksum[1..]).ok()?, 16).ok()?;
+    if data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != checksum {
+        return None;
+    }
+    Some(data)
+}
+
+/// The connection's input, as the protocol library reads it. Packets the
+/// server handles itself are answered on the way and left out.
+pub struct Packets<'a, T: Target> {
+    app: &'a App<T>,
+    input: &'a Input,
+    writer: SharedWriter,
+    /// What goes on to the library, in order
+    passed: VecDeque<u8>,
+    /// Whether GDB asked to stop acknowledging packets
+    no_ack: bool,
+    /// Stop replies in non-stop mode that GDB hasn't taken with `vStopped`
+    /// yet. It's been notified of the first.
+    stops: VecDeque<String>,
+}
+impl<'a, T: Target> Packets<'a, T> {
+    pub fn new(app: &'a App<T>, input: &'a Input, writer: SharedWriter) -> Self {
+        Packets {
+            app,
+            input,
+            writer,
+            passed: VecDeque::new(),
+            no_ack: false,
+            stops: VecDeque::new(),
+        }
+    }
+
+    /// Wait for more input and add it to the buffer. In non-stop mode,
+    /// threads that stop meanwhile are queued for GDB, which is notified
+    /// when the queue was empty. Returns false once the connection is closed.
+    fn fill(&mut self) -> io::Result<bool> {
+        while self.app.non_stop() && !self.input.wait(STOP_POLL) {
+            match self.app.poll_non_stop() {
+                Ok(Some((tid, reason))) => {
+                    let reply = thread_stop_reply(self.app.tracee().pid(), tid, reason);
+                    if self.stops.is_empty() {
+                        write_notification(&mut self.writer, &format!("Stop:{}", reply))?;
+                    }
+                    self.stops.push_back(reply);
+                },
+                Ok(None) => (),
+                Err(err) => {
+                    warn!("Failed to check on running threads: {:?}", err);
+                    break;
+                },
+            }
+        }
+        self.input.fill()
+    }
+
+    /// Handle or pass on what's next in the input. Returns false once there's
+    /// nothing more to come.
+    fn next(&mut self) -> io::Result<bool> {
+        loop {
+            let mut buffer = self.input.buffer.borrow_mut();
+            match buffer.front() {
+                None => {
+                    drop(buffer);
+                    if !self.fill()? {
+                        return Ok(false);
+                    }
+                },
+                Some(b'$') => match packet_len(&buffer) {
+                    Some(len) => {
+                        let packet: Vec<u8> = buffer.drain(..len).collect();
+                        drop(buffer);
+                        self.packet(packet)?;
+                        return Ok(true);
+                    },
+                    None => {
+                        drop(buffer);
+                        if !self.fill()? {
+                            // Let the library make what it will of the rest
+                            self.passed.extend(self.input.buffer.borrow_mut().drain(..));
+                            return Ok(true);
+                        }
+                    },
+                },
+                Some(_) => {
+                    // Acknowledgements and interrupts between packets
+                    let len = buffer.iter().position(|&byte| byte == b'$').unwrap_or_else(|| buffer.len());
+                    self.passed.extend(buffer.drain(..len));
+                    return Ok(true);
+                },
+            }
+        }
+    }
+
+    fn packet(&mut self, packet: Vec<u8>) -> io::Result<()> {
+        let data = packet_data(&packet).and_then(|data| std::str::from_utf8(data).ok());
+        if let Some(actions) = data.filter(|_| !self.app.non_stop()).and_then(|data| data.strip_prefix("vCont;")) {
+            // The tracee can print to GDB's console while it's waited for,
+            // which GDB only takes once the packet is acknowledged
+            self.ack()?;
+            let result = parse_vcont(actions)
+                .and_then(|actions| self.app.vcont(actions))
+                .map(|reason| current_stop_reply(self.app, reason));
+            return write_packet(&mut self.writer, &reply(result));
+        }
+
+        let reply = data.and_then(|data| self.non_stop_packet(data).or_else(|| handle(self.app, data)));
+        match reply {
+            Some(reply) => {
+                self.ack()?;
+                write_packet(&mut self.writer, &reply)
+            },
+            None => {
+                if packet_data(&packet) == Some(b"QStartNoAckMode") {
+                    self.no_ack = true;
+                }
+                self.passed.extend(packet);
+                Ok(())
+            },
+        }
+    }
+
+    fn ack(&mut self) -> io::Result<()> {
+        if !self.no_ack {
+            self.writer.write_all(b"+")?;
+        }
+        Ok(())
+    }
+
+    /// Answer a packet that works differently in non-stop mode, if it's on
+    fn non_stop_packet(&mut self, data: &str) -> Option<String> {
+        if !self.app.non_stop() {
+            return None;
+        }
+        match data {
+            "?" => {
+                let pid = self.app.tracee().pid();
+                self.stops = self.app.non_stop_statuses()
+                    .into_iter()
+                    .map(|(tid, reason)| thread_stop_reply(pid, tid, reason))
+                    .collect();
+            },
+            // GDB took the first stop, and asks for the next
+            "vStopped" => {
+                self.stops.pop_front();
+            },
+            _ => {
+                let actions = data.strip_prefix("vCont;")?;
+                let result = parse_vcont(actions).and_then(|actions| self.app.vcont_non_stop(&actions));
+                return Some(reply(result.map(|()| String::from("OK"))));
+            },
+        }
+        Some(self.stops.front().cloned().unwrap_or_else(|| String::from("OK")))
+    }
+}
+impl<'a, T: Target> Read for Packets<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.passed.is_empty() {
+            if !self.next()? {
+                return Ok(0);
+            }
+        }
+        let len = min(buf.len(), self.passed.len());
+        for (byte, passed) in buf.iter_mut().zip(self.passed.drain(..len)) {
+            *byte = passed;
+        }
+        Ok(len)
+    }
+}
+
+/// Answer a packet if it's one the server handles itself
+fn handle<T: Target>(app: &App<T>, packet: &str) -> Option<String> {
+    let (name, args) = match packet.find([':', ';']) {
+        Some(colon) => (&packet[..colon], &packet[colon + 1..]),
+        None => (packet, ""),
+    };
+    let result = match name {
+        "QEnvironmentHexEncoded" => set_environment_variable(app, args),
+        "QEnvironmentUnset" => parse_bytes(args).map(|name| {
+            app.unset_environment_variable(OsStr::from_bytes(&name));
+            String::from("OK")
+        }),
+        "QEnvironmentReset" => {
+            app.reset_environment();
+            Ok(String::from("OK"))
+        },
+        "QStartupWithShell" => match args {
+            "0" | "1" => {
+                app.set_startup_with_shell(args == "1");
+                Ok(String::from("OK"))
+            },
+            _ => Err(Error::Error(ERROR_PARSE_STRING)),
+        },
+        "QSetWorkingDir" => parse_bytes(args).map(|dir| {
+            // No directory at all means the server's own
+            let dir = Path::new(OsStr::from_bytes(&dir));
+            app.set_working_dir(if dir.as_os_str().is_empty() { None } else { Some(dir) });
+            String::from("OK")
+        }),
+        // Extended mode, which GDB needs for vRun
+        "!" => Ok(String::from("OK")),
+        "vRun" => run(app, args),
+        "QNonStop" => match args {
+            "0" | "1" => app.set_non_stop(args == "1").map(|()| String::from("OK")),
+            _ => Err(parse_error()),
+        },
+        "?" => app.halt_reason().map(|reason| current_stop_reply(app, reason)),
+        "qGetTLSAddr" => tls_address(app, args),
+        "bs" => app.reverse_step().map(|reason| current_stop_reply(app, reason)),
+        "bc" => app.reverse_continue().map(|reason| current_stop_reply(app, reason)),
+        "QTinit" => app.trace_init().map(|()| String::from("OK")),
+        "QTDP" => app.define_tracepoint(args).map(|()| String::from("OK")),
+        "QTStart" => app.start_trace().map(|()| String::from("OK")),
+        "QTStop" => app.stop_trace().map(|()| String::from("OK")),
+        "QTSave" => parse_bytes(args).and_then(|path| {
+            app.save_trace(Path::new(OsStr::from_bytes(&path)))?;
+            Ok(String::from("OK"))
+        }),
+        "qTStatus" => Ok(app.trace_status()),
+        "QTFrame" => parse_frame_query(args).map(|query| match app.select_trace_frame(query) {
+            Some((frame, tracepoint)) => format!("F{:x}T{:x}", frame, tracepoint),
+            None => String::from("F-1"),
+        }),
+        _ => return None,
+    };
+    Some(reply(result))
+}
+
+/// The reply to a packet, with errors as `E` and their code, or empty for
+/// ones that aren't supported
+fn reply(result: Result<String>) -> String {
+    match result {
+        Ok(reply) => reply,
+        Err(Error::Error(code)) => format!("E{:02x}", code),
+        Err(_) => String::new(),
+    }
+}
+
+fn set_environment_variable<T: Target>(app: &App<T>, args: &str) -> Result<String> {
+    let var = parse_bytes(args)?;
+    let equals = var.iter().position(|&byte| byte == b'=').ok_or_else(parse_error)?;
+    app.set_environment_variable(OsStr::from_bytes(&var[..equals]), OsStr::from_bytes(&var[equals + 1..]));
+    Ok(String::from("OK"))
+}
+
+/// Handle `vRun;PROGRAM[;ARG]...`, all hex encoded, where an empty program is
+/// the one the server was started with
+fn run<T: Target>(app: &App<T>, args: &str) -> Result<String> {
+    let mut args = args
+        .split(';')
+        .map(|arg| parse_bytes(arg).and_then(|arg| String::from_utf8(arg).map_err(|_| parse_error())));
+    let program = args.next().transpose()?.filter(|program| !program.is_empty());
+    app.run(program, args.collect::<Result<_>>()?)?;
+    Ok(current_stop_reply(app, app.tracee().status()))
+}
+
+/// Describe why the tracee stopped, as a stop reply packet
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Signal(signal) => format!("S{:02x}", signal),
+        StopReason::Exited(pid, status) => format!("W{:02x};process:{:x}", status, pid),
+        StopReason::ExitedWithSignal(pid, signal) => format!("X{:02x};process:{:x}", signal, pid),
+        StopReason::ThreadExited(thread, status) => format!("w{:x};{}", status, format_id(thread.tid)),
+        StopReason::NoMoreThreads => String::from("N"),
+    }
+}
+
+/// Describe why a thread stopped, naming it for GDB to switch to
+fn thread_stop_reply(pid: u32, tid: u32, reason: StopReason) -> String {
+    match reason {
+        StopReason::Signal(signal) => format!("T{:02x}thread:p{:x}.{:x};", signal, pid, tid),
+        reason => stop_reply(reason),
+    }
+}
+
+/// Describe why the tracee stopped in all-stop mode, naming the thread that
+/// stopped, which is the current one
+fn current_stop_reply<T: Target>(app: &App<T>, reason: StopReason) -> String {
+    thread_stop_reply(app.tracee().pid(), app.tracee().current_thread(), reason)
+}
+
+/// Parse the actions of `vCont;ACTION[:THREAD]...`, after the first `;`
+fn parse_vcont(args: &str) -> Result<Vec<(VCont, Option<ThreadId>)>> {
+    let signal = |text: &str| u8::from_str_radix(text, 16).map_err(|_| parse_error());
+    args.split(';')
+        .map(|item| {
+            let (action, thread) = match item.find(':') {
+                Some(colon) => (&item[..colon], Some(parse_thread(&item[colon + 1..])?)),
+                None => (item, None),
+            };
+            let (kind, rest) = action.split_at(min(1, action.len()));
+            let action = match (kind, rest) {
+                ("c", "") => VCont::Continue,
+                ("C", signal_hex) => VCont::ContinueWithSignal(signal(signal_hex)?),
+                ("s", "") => VCont::Step,
+                ("S", signal_hex) => VCont::StepWithSignal(signal(signal_hex)?),
+                ("t", "") => VCont::Stop,
+                ("r", range) => {
+                    let comma = range.find(',').ok_or_else(parse_error)?;
+                    VCont::RangeStep(parse_hex(&range[..comma])?..parse_hex(&range[comma + 1..])?)
+                },
+                _ => return Err(parse_error()),
+            };
+            Ok((action, thread))
+        })
+        .collect()
+}
+
+/// Parse the arguments of `QTFrame`: a frame number, where -1 comes as
+/// ffffffff, or `pc:`, `tdp:`, `range:` or `outside:` with addresses
+fn parse_frame_query(args: &str) -> Result<FrameQuery> {
+    let mut fields = args.split(':');
+    let kind = fields.next().unwrap_or_default();
+    let mut field = || fields.next().ok_or_else(parse_error).and_then(parse_hex);
+    Ok(match kind {
+        "pc" => FrameQuery::Pc(field()?),
+        "tdp" => FrameQuery::Tracepoint(field()?),
+        "range" => FrameQuery::Range(field()?, field()?),
+        "outside" => FrameQuery::Outside(field()?, field()?),
+        "-1" => FrameQuery::Number(None),
+        number => match parse_hex(number)? {
+            0xFFFF_FFFF => FrameQuery::Number(None),
+            number => FrameQuery::Number(Some(number as usize)),
+        },
+    })
+}
+
+/// Handle `qGetTLSAddr:THREAD,OFFSET,LM`
+fn tls_address<T: Target>(app: &App<T>, args: &str) -> Result<String> {
+    let mut fields = args.split(',');
+    let mut field = || fields.next().ok_or_else(parse_error);
+    let thread = parse_thread(field()?)?;
+    let offset = parse_hex(field()?)?;
+    let lm = parse_hex(field()?)?;
+    app.tls_address(thread, offset, lm).map(|address| format!("{:x}", address))
+}
+
+/// Parse a thread ID, `[pPID.]TID` with -1 for all and 0 for any
+fn parse_thread(text: &str) -> Result<ThreadId> {
+    fn id(text: &str) -> Result<Id> {
+        Ok(match text {
+            "-1" => Id::All,
+            "0" => Id::Any,
+            _ => Id::Id(u32::from_str_radix(text, 16).map_err(|_| parse_error())?),
+        })
+    }
+    match text.strip_prefix('p') {
+        Some(text) => {
+            let dot = text.find('.').ok_or_else(parse_error)?;
+            Ok(ThreadId { pid: id(&text[..dot])?, tid: id(&text[dot + 1..])? })
+        },
+        None => Ok(ThreadId { pid: Id::Any, tid: id(text)? }),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{write_notification, write_packet, Input, Packets};
+    use crate::{
+        os::{Launch, Os, Registers, Target},
+        record::Recording,
+        trace::{hex, FrameQuery, Trace},
+        App, SharedWriter,
+    };
+
+    use gdb_remote_protocol::{Handler, MemoryRegion, StopReason, VCont};
+
+    use std::{
+        cell::RefCell,
+        ffi::OsStr,
+        fs::{self, File},
+        io::{self, prelude::*, BufReader},
+        os::unix::{
+            ffi::OsStrExt,
+            io::{AsRawFd, FromRawFd},
+        },
+        path::Path,
+        rc::Rc,
+        time::Duration,
+    };
+
+    /// Everything written to it, kept for looking at afterwards
+    #[derive(Clone, Default)]
+    pub struct Output(pub Rc<RefCell<Vec<u8>>>);
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packets(packets: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for packet in packets {
+            write_packet(&mut data, packet).unwrap();
+        }
+        data
+    }
+
+    pub fn app() -> App<Os> {
+        launch(&["true"], None)
+    }
+
+    fn launch(args: &[&str], recording: Option<Recording>) -> App<Os> {
+        let launch = Launch::new(args);
+        let tracee = Os::new(&launch).unwrap();
+        App::new(tracee, launch, Trace::default(), recording, None)
+    }
+
+    /// The reading and the writing end of a new pipe
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    /// Run `input` through the server's own handling, returning what's left
+    /// for the library and what was replied
+    pub fn filter<T: Target>(app: &App<T>, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        // Closed after the input, like the connection would be
+        let (reader, mut writer) = pipe();
+        writer.write_all(input).unwrap();
+        drop(writer);
+        filter_from(app, reader)
+    }
+
+    /// Run what arrives on `reader` through the server's own handling, until
+    /// it's closed
+    fn filter_from<T: Target>(app: &App<T>, reader: File) -> (Vec<u8>, Vec<u8>) {
+        let output = Output::default();
+        let writer = SharedWriter(Rc::new(RefCell::new(Box::new(output.clone()))));
+        let fd = reader.as_raw_fd();
+        let input = Input::new(Box::new(reader), fd);
+        let mut passed = Vec::new();
+        Packets::new(app, &input, writer).read_to_end(&mut passed).unwrap();
+        let replied = output.0.borrow().clone();
+        (passed, replied)
+    }
+
+    #[test]
+    fn environment() {
+        let app = app();
+        let input = packets(&[
+            "QEnvironmentReset",
+            // GDBSERVER_TEST=a=b
+            "QEnvironmentHexEncoded:4744425345525645525f544553543d613d62",
+            // HOME
+            "QEnvironmentUnset:484f4d45",
+            // /tmp
+            "QSetWorkingDir:2f746d70",
+            "QStartupWithShell:1",
+            "g",
+        ]);
+        let (passed, replied) = filter(&app, &[b"+", &input[..]].concat());
+
+        assert_eq!(passed, [b"+", &packets(&["g"])[..]].concat());
+        assert_eq!(String::from_utf8(replied).unwrap(), "+$OK#9a".repeat(5));
+        let launch = app.launch.borrow();
+        assert_eq!(launch.env.get(OsStr::new("GDBSERVER_TEST")).map(|value| &**value), Some(OsStr::new("a=b")));
+        assert_eq!(launch.env.get(OsStr::new("HOME")), None);
+        assert_eq!(launch.cwd.as_deref(), Some(Path::new("/tmp")));
+        assert!(launch.startup_with_shell);
+        drop(launch);
+
+        filter(&app, &packets(&["QSetWorkingDir:"]));
+        assert_eq!(app.launch.borrow().cwd, None);
+    }
+
+    #[test]
+    fn run() {
+        let app = app();
+        let old = app.tracee().pid();
+        (&app).set_address_randomization(false).unwrap();
+        let input = packets(&[
+            "!",
+            // GDBSERVER_TEST=a=b
+            "QEnvironmentHexEncoded:4744425345525645525f544553543d613d62",
+            // /tmp
+            "QSetWorkingDir:2f746d70",
+            "QStartupWithShell:1",
+            // sleep with an argument only a shell turns into 10
+            &format!("vRun;{};{}", hex(b"sleep"), hex(b"$((5+5))")),
+        ]);
+        let (passed, replied) = filter(&app, &input);
+
+        assert!(passed.is_empty());
+        let pid = app.tracee().pid();
+        assert_ne!(pid, old);
+        let stopped = format!("T05thread:p{0:x}.{0:x};", pid);
+        let expected = packets(&["OK", "OK", "OK", "OK", &stopped]);
+        assert_eq!(String::from_utf8(replied).unwrap(), String::from_utf8(expected).unwrap().replace('$', "+$"));
+
+        let read = |name: &str| fs::read(format!("/proc/{}/{}", pid, name)).unwrap();
+        assert!(read("environ").split(|&b| b == 0).any(|var| var == b"GDBSERVER_TEST=a=b"));
+        assert_eq!(read("cmdline"), b"sleep\x0010\x00");
+        assert_eq!(fs::read_link(format!("/proc/{}/cwd", pid)).unwrap(), Path::new("/tmp"));
+        let personality = String::from_utf8(read("personality")).unwrap();
+        // ADDR_NO_RANDOMIZE
+        assert_ne!(u32::from_str_radix(personality.trim(), 16).unwrap() & 0x0040000, 0);
+        (&app).kill(None).unwrap();
+    }
+
+    #[test]
+    fn threads_xml() {
+        // The thread is named after the link it's executed through
+        let name = format!("a<b&c{}", std::process::id());
+        let link = std::env::temp_dir().join(&name);
+        std::os::unix::fs::symlink("/bin/sleep", &link).unwrap();
+        let app = launch(&[link.to_str().unwrap(), "1"], None);
+        fs::remove_file(&link).unwrap();
+
+        let xml = app.threads_xml().unwrap();
+        let escaped = name.replace('&', "&amp;").replace('<', "&lt;");
+        assert!(xml.contains(&format!(" name=\"{}\"/>", escaped)), "{}", xml);
+        assert!(xml.contains(" core=\""), "{}", xml);
+        (&app).kill(None).unwrap();
+    }
+
+    #[test]
+    fn tls_address() {
+        let app = app();
+        let tid = app.tracee().current_thread();
+        let input = packets(&[
+            // `true` has no TLS of its own to find variables in
+            &format!("qGetTLSAddr:p{:x}.{:x},10,0", app.tracee().pid(), tid),
+            &format!("qGetTLSAddr:{:x},10,0", tid),
+            "qGetTLSAddr:p1,10,0",
+            "qGetTLSAddr:1,10",
+        ]);
+        let (passed, replied) = filter(&app, &input);
+
+        assert!(passed.is_empty());
+        let replies = packets(&["Efc", "Efc", "Eff", "Eff"]);
+        assert_eq!(String::from_utf8(replied).unwrap(), String::from_utf8(replies).unwrap().replace('$', "+$"));
+    }
+
+    /// Run the tracee to its end with a tracepoint where it starts, which
+    /// collects the registers once. Returns the address of the tracepoint.
+    fn collect_once(app: &App<Os>) -> u64 {
+        let rip = app.tracee().getregs().unwrap().rip.unwrap();
+        let input = packets(&[
+            "QTinit",
+            &format!("QTDP:1:{:x}:E:0:1-", rip),
+            &format!("QTDP:-1:{:x}:R1ffff", rip),
+            "QTStart",
+        ]);
+        let (_, replied) = filter(app, &input);
+        assert_eq!(String::from_utf8(replied).unwrap(), "+$OK#9a".repeat(4));
+
+        let status = app.vcont(vec![(VCont::Continue, None)]).unwrap();
+        assert_eq!(status, StopReason::Exited(u64::from(app.tracee().pid()), 0));
+        rip
+    }
+
+    #[test]
+    fn tracepoint() {
+        let app = app();
+        let rip = collect_once(&app);
+
+        let input = packets(&["qTStatus", "QTStop", "QTFrame:tdp:1"]);
+        let (_, replied) = filter(&app, &input);
+        let replied = String::from_utf8(replied).unwrap();
+        assert!(replied.starts_with("+$T0;tpasscount:1;tframes:1;"), "{}", replied);
+        let rest = String::from_utf8(packets(&["OK", "F0T1"])).unwrap().replace('$', "+$");
+        assert!(replied.ends_with(&rest), "{}", replied);
+        let regs = Registers::decode(&(&app).read_general_registers().unwrap());
+        assert_eq!(regs.rip, Some(rip));
+
+        // Nothing more from that tracepoint, and back to the tracee
+        let (_, replied) = filter(&app, &packets(&["QTFrame:tdp:1", "QTFrame:ffffffff"]));
+        assert_eq!(replied, [&b"+"[..], &packets(&["F-1"]), b"+", &packets(&["F-1"])].concat());
+        assert!(app.trace.borrow().selected().is_none());
+    }
+
+    #[test]
+    fn save_trace() {
+        let app = app();
+        let rip = collect_once(&app);
+        let path = std::env::temp_dir().join(format!("gdbserver-trace-{}", std::process::id()));
+
+        let input = packets(&["QTStop", &format!("QTSave:{}", hex(path.as_os_str().as_bytes()))]);
+        let (_, replied) = filter(&app, &input);
+        assert_eq!(String::from_utf8(replied).unwrap(), "+$OK#9a".repeat(2));
+
+        let loaded = Trace::load(&mut BufReader::new(File::open(&path).unwrap()));
+        fs::remove_file(&path).unwrap();
+        let mut loaded = loaded.unwrap();
+        assert_eq!(loaded.status(), app.trace_status());
+        assert_eq!(loaded.select(FrameQuery::Tracepoint(1)), Some((0, 1)));
+        let regs = Registers::decode(&loaded.selected().unwrap().registers);
+        assert_eq!(regs.rip, Some(rip));
+    }
+
+    #[test]
+    fn reverse() {
+        let app = launch(&["true"], Some(Recording::default()));
+        let start = (&app).read_general_registers().unwrap();
+        let rsp = app.tracee().getregs().unwrap().rsp.unwrap();
+        let stack = || (&app).read_memory(MemoryRegion { address: rsp - 16, length: 16 }).unwrap();
+        let below = stack();
+        let stopped = format!("T05thread:p{0:x}.{0:x};", app.tracee().pid());
+
+        // Into the dynamic loader's entry, which calls a function. The first
+        // step only finishes the execve.
+        let mut steps = 0;
+        while stack() == below {
+            assert!(steps < 4);
+            (&app).vcont(vec![(VCont::Step, None)]).unwrap();
+            steps += 1;
+        }
+        let (passed, replied) = filter(&app, &packets(&vec!["bs"; steps]));
+        assert!(passed.is_empty());
+        assert_eq!(replied, [&b"+"[..], &packets(&[&stopped])].concat().repeat(steps));
+        assert_eq!((&app).read_general_registers().unwrap(), start);
+        assert_eq!(stack(), below);
+
+        for _ in 0..3 {
+            (&app).vcont(vec![(VCont::Step, None)]).unwrap();
+        }
+        let (_, replied) = filter(&app, &packets(&["bc"]));
+        assert_eq!(replied, [&b"+"[..], &packets(&[&stopped])].concat());
+        assert_eq!((&app).read_general_registers().unwrap(), start);
+        assert_eq!(stack(), below);
+    }
+
+    #[test]
+    fn non_stop() {
+        let app = launch(&["sleep", "10"], None);
+        assert!((&app).query_supported_features().iter().any(|feature| feature == "QNonStop+"));
+
+        let pid = app.tracee().pid();
+        let thread = format!("p{:x}.{:x}", pid, pid);
+        let input = packets(&["QNonStop:1", "?", "vStopped", &format!("vCont;c:{}", thread), "?"]);
+        let (passed, replied) = filter(&app, &input);
+
+        assert!(passed.is_empty());
+        let replies = packets(&["OK", &format!("T05thread:{};", thread), "OK", "OK", "OK"]);
+        assert_eq!(String::from_utf8(replied).unwrap(), String::from_utf8(replies).unwrap().replace('$', "+$"));
+        assert!(app.running.borrow().contains_key(&pid));
+
+        // GDB is notified of the stop while waiting for more input, and
+        // takes it once that arrives
+        let (reader, mut writer) = pipe();
+        writer.write_all(&packets(&[&format!("vCont;t:{}", thread)])).unwrap();
+        let rest = packets(&["vStopped", "QNonStop:0"]);
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            writer.write_all(&rest).unwrap();
+        });
+        let (passed, replied) = filter_from(&app, reader);
+        sender.join().unwrap();
+
+        assert!(passed.is_empty());
+        let mut notification = Vec::new();
+        write_notification(&mut notification, &format!("Stop:T00thread:{};", thread)).unwrap();
+        let replies = [&b"+"[..], &packets(&["OK"]), &notification, b"+", &packets(&["OK"]), b"+", &packets(&["OK"])].concat();
+        assert_eq!(String::from_utf8(replied).unwrap(), String::from_utf8(replies).unwrap());
+        assert!(!app.non_stop());
+        assert!(app.running.borrow().is_empty());
+    }
+
+    #[test]
+    fn interrupt() {
+        use crate::os::Interrupt;
+
+        // Input that has already arrived, with more to come on a pipe
+        let (reader, mut writer) = pipe();
+        let fd = reader.as_raw_fd();
+        let input = Input::new(Box::new(reader), fd);
+        input.buffer.borrow_mut().extend(b"+$X1000,1:\x03#2b");
+        assert!(!input.interrupted());
+
+        writer.write_all(b"+\x03+").unwrap();
+        assert!(input.interrupted());
+        assert!(!input.interrupted());
+        assert_eq!(input.buffer.borrow().iter().copied().collect::<Vec<u8>>(), b"+$X1000,1:\x03#2b++");
+
+        drop(writer);
+        assert!(input.interrupted());
+    }
+
+    #[test]
+    fn bad_checksum() {
+        // Left for the library to ask for again
+        let (passed, replied) = filter(&app(), b"$QEnvironmentReset#00");
+        assert_eq!(passed, b"$QEnvironmentReset#00");
+        assert!(replied.is_empty());
+    }
+}
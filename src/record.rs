@@ -0,0 +1,171 @@
+//! Recording of everything the tracee does, one instruction at a time, so
+//! that it can be undone again for GDB's reverse execution. Each step saves
+//! the registers and memory the instruction could change. What system calls
+//! write to memory isn't known, and is not undone.
+
+use crate::{
+    breakpoint::Breakpoints,
+    os::{Registers, Target},
+    x86::{self, Segment},
+    Result,
+};
+
+use gdb_remote_protocol::Error;
+
+use std::collections::VecDeque;
+
+/// How many instructions are remembered before the oldest are forgotten,
+/// the same as GDB's own `record full` by default
+const HISTORY_LIMIT: usize = 200_000;
+/// How far below the stack pointer an instruction can write, enough for any
+/// push, call or the red zone
+const STACK_WINDOW: u64 = 256;
+const PAGE_SIZE: u64 = 4096;
+
+/// How to undo one instruction
+struct Step {
+    thread: u32,
+    /// Offsets into the encoded registers, and the bytes there before
+    registers: Vec<(u16, u8)>,
+    /// Memory the instruction could have written, and what was there before
+    memory: Vec<(u64, Vec<u8>)>,
+}
+
+#[derive(Default)]
+pub struct Recording {
+    history: VecDeque<Step>,
+}
+
+/// Read a register by its number in instruction encodings
+fn register(regs: &Registers, number: u8) -> u64 {
+    let reg = match number {
+        0 => regs.rax,
+        1 => regs.rcx,
+        2 => regs.rdx,
+        3 => regs.rbx,
+        4 => regs.rsp,
+        5 => regs.rbp,
+        6 => regs.rsi,
+        7 => regs.rdi,
+        8 => regs.r8,
+        9 => regs.r9,
+        10 => regs.r10,
+        11 => regs.r11,
+        12 => regs.r12,
+        13 => regs.r13,
+        14 => regs.r14,
+        _ => regs.r15,
+    };
+    reg.unwrap_or(0)
+}
+
+/// Save the memory at the address as GDB would see it, skipping any pages
+/// that can't be read
+fn save<T: Target>(
+    tracee: &T,
+    breakpoints: &Breakpoints,
+    address: u64,
+    len: u64,
+    memory: &mut Vec<(u64, Vec<u8>)>,
+) {
+    let end = address.saturating_add(len);
+    let mut at = address;
+    while at < end {
+        let next = (at / PAGE_SIZE).saturating_add(1).saturating_mul(PAGE_SIZE).min(end);
+        let mut block = vec![0; (next - at) as usize];
+        if tracee.getmem(at as usize, &mut block).is_ok() {
+            breakpoints.hide(at, &mut block);
+            memory.push((at, block));
+        }
+        at = next;
+    }
+}
+
+impl Recording {
+    /// Forget everything recorded, for when the tracee is gone
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    /// Single-step the current thread, past the breakpoint it's at if any,
+    /// remembering how to undo it. Returns false if it stopped for anything
+    /// but the step, which leaves nothing to undo.
+    pub fn step<T: Target>(&mut self, tracee: &T, breakpoints: &Breakpoints, signal: Option<u8>) -> Result<bool> {
+        let regs = tracee.getregs()?;
+        let rip = regs.rip.unwrap_or(0);
+        let mut memory = Vec::new();
+
+        save(tracee, breakpoints, regs.rsp.unwrap_or(0).wrapping_sub(STACK_WINDOW), STACK_WINDOW, &mut memory);
+
+        // The instruction may end right before an unreadable page
+        let mut blocks = Vec::new();
+        save(tracee, breakpoints, rip, x86::MAX_LEN as u64, &mut blocks);
+        let mut code = Vec::new();
+        for (address, block) in blocks {
+            if address != rip + code.len() as u64 {
+                break;
+            }
+            code.extend(block);
+        }
+        if let Some(insn) = x86::decode(&code, rip) {
+            if let Some(operand) = insn.memory {
+                let address = operand.address(
+                    |number| register(&regs, number),
+                    |segment| match segment {
+                        Segment::Fs => regs.fs_base.unwrap_or(0),
+                        Segment::Gs => regs.gs_base.unwrap_or(0),
+                    },
+                    rip.wrapping_add(insn.len as u64),
+                );
+                save(tracee, breakpoints, address, operand.size as u64, &mut memory);
+            }
+        }
+
+        let mut before = Vec::new();
+        regs.encode(&mut before);
+        let thread = tracee.current_thread();
+        if !breakpoints.step(tracee, signal)? {
+            return Ok(false);
+        }
+
+        let mut after = Vec::new();
+        tracee.getregs()?.encode(&mut after);
+        let registers = before.iter()
+            .zip(&after)
+            .enumerate()
+            .filter(|(_, (before, after))| before != after)
+            .map(|(offset, (&before, _))| (offset as u16, before))
+            .collect();
+
+        if self.history.len() >= HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(Step {
+            thread,
+            registers,
+            memory,
+        });
+        Ok(true)
+    }
+
+    /// Undo the last recorded instruction, selecting the thread that ran it.
+    /// Returns false if there is nothing left to undo.
+    pub fn step_back<T: Target>(&mut self, tracee: &T, breakpoints: &mut Breakpoints) -> Result<bool> {
+        let step = match self.history.pop_back() {
+            Some(step) => step,
+            None => return Ok(false),
+        };
+        tracee.select_thread(step.thread)?;
+
+        for (address, block) in step.memory.iter().rev() {
+            breakpoints.write(tracee, *address, block)?;
+        }
+        let mut regs = Vec::new();
+        tracee.getregs()?.encode(&mut regs);
+        for &(offset, byte) in &step.registers {
+            *regs.get_mut(usize::from(offset)).ok_or(Error::Error(libc::EIO as u8))? = byte;
+        }
+        tracee.setregs(&Registers::decode(&regs))?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io,
+    mem::MaybeUninit,
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
+};
+
+fn speed(baud: u32) -> io::Result<libc::speed_t> {
+    Ok(match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        921600 => libc::B921600,
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported baud rate {}", baud),
+        )),
+    })
+}
+
+/// Open a tty device and put it in raw mode at the specified baud rate, ready
+/// to carry the remote protocol.
+pub fn open(path: &str, baud: u32) -> io::Result<File> {
+    let speed = speed(baud)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+
+    unsafe {
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        if libc::tcgetattr(file.as_raw_fd(), termios.as_mut_ptr()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut termios = termios.assume_init();
+
+        libc::cfmakeraw(&mut termios);
+        // Ignore modem control lines, and block until at least one byte is read
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+
+        if libc::cfsetispeed(&mut termios, speed) == -1
+            || libc::cfsetospeed(&mut termios, speed) == -1
+            || libc::tcsetattr(file.as_raw_fd(), libc::TCSANOW, &termios) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(file)
+}
+
+/// A terminal in raw mode, put back the way it was once dropped
+#[cfg(target_os = "linux")]
+pub struct RawMode {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+#[cfg(target_os = "linux")]
+impl RawMode {
+    /// Put the terminal `fd` in raw mode, so keys like ^C and ^Z reach
+    /// whatever reads from it instead of signaling the server. Returns None
+    /// if it isn't a terminal.
+    pub fn new(fd: RawFd) -> io::Result<Option<Self>> {
+        unsafe {
+            if libc::isatty(fd) == 0 {
+                return Ok(None);
+            }
+            let mut termios = MaybeUninit::<libc::termios>::uninit();
+            if libc::tcgetattr(fd, termios.as_mut_ptr()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let saved = termios.assume_init();
+
+            let mut termios = saved;
+            libc::cfmakeraw(&mut termios);
+            // The server's own messages still need their newlines turned
+            // into line breaks
+            termios.c_oflag |= libc::OPOST;
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Some(Self { fd, saved }))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+/// Allocate a new pseudo-terminal, returning the master side and the path of
+/// the slave side
+#[cfg(target_os = "linux")]
+pub fn openpty() -> io::Result<(File, String)> {
+    unsafe {
+        let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if master == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(master);
+
+        if libc::grantpt(master.as_raw_fd()) == -1 || libc::unlockpt(master.as_raw_fd()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut name = [0 as libc::c_char; 128];
+        let error = libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len());
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+        let name = CString::from_vec_unchecked(
+            name.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect(),
+        );
+
+        Ok((master, name.into_string().expect("pty names are ascii")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn raw_pty() {
+        let (mut master, path) = super::openpty().unwrap();
+        let mut slave = super::open(&path, 115200).unwrap();
+
+        // Without raw mode, this would be line buffered and echoed back
+        master.write_all(b"$?#3f").unwrap();
+        let mut buf = [0; 5];
+        slave.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"$?#3f");
+
+        slave.write_all(b"+\n").unwrap();
+        let mut buf = [0; 2];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"+\n");
+    }
+
+    #[test]
+    fn bad_baud() {
+        assert!(super::speed(1234).is_err());
+    }
+}
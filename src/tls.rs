@@ -0,0 +1,138 @@
+//! Locating thread-local variables the way the C libraries lay them out on
+//! x86_64, where the thread pointer is `fs_base` and the static TLS blocks
+//! sit right below it
+
+use crate::{
+    elf::{self, ProgramHeader},
+    os::Target,
+    Result, ERROR_NO_TLS,
+};
+
+use gdb_remote_protocol::Error;
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+// Fields of the public part of struct link_map, see <link.h>
+const LINK_MAP_NAME: u64 = 8;
+const LINK_MAP_PREV: u64 = 32;
+// Both glibc and musl keep the DTV right after the self pointer of the TCB
+const TCB_DTV: u64 = 8;
+// Longest path read out of a link map
+const MAX_NAME: usize = 4096;
+
+fn read_word<T: Target>(tracee: &T, address: u64) -> Result<u64> {
+    let mut word = [0; mem::size_of::<u64>()];
+    tracee.getmem(address as usize, &mut word)?;
+    Ok(u64::from_ne_bytes(word))
+}
+
+fn read_string<T: Target>(tracee: &T, mut address: u64) -> Result<Vec<u8>> {
+    let mut string = Vec::new();
+    while string.len() < MAX_NAME {
+        let mut chunk = [0; 64];
+        tracee.getmem(address as usize, &mut chunk)?;
+        if let Some(end) = chunk.iter().position(|&b| b == 0) {
+            string.extend_from_slice(&chunk[..end]);
+            break;
+        }
+        string.extend_from_slice(&chunk);
+        address += chunk.len() as u64;
+    }
+    Ok(string)
+}
+
+/// Return the PT_TLS header of a module, if it has thread-local storage
+fn tls_header(path: &Path) -> Option<ProgramHeader> {
+    let mut file = File::open(path).ok()?;
+    elf::program_headers(&mut file)
+        .ok()?
+        .into_iter()
+        .find(|header| header.kind == elf::PT_TLS)
+}
+
+/// Returns true if the executable is loaded by musl's dynamic linker, which
+/// uses plain pointers for its DTV entries instead of glibc's pairs
+fn uses_musl(exe: &Path) -> bool {
+    let interp = File::open(exe).ok().and_then(|mut file| {
+        let header = elf::program_headers(&mut file)
+            .ok()?
+            .into_iter()
+            .find(|header| header.kind == elf::PT_INTERP)?;
+        elf::segment(&mut file, &header).ok()
+    });
+    interp.map(|interp| interp.windows(4).any(|w| w == b"musl")).unwrap_or(false)
+}
+
+/// Distance of the first TLS block below the thread pointer. Both glibc and
+/// musl place it as close as possible while keeping its alignment.
+fn static_offset(tls: &ProgramHeader) -> u64 {
+    let align = tls.align.max(1);
+    tls.memsz + (0u64.wrapping_sub(tls.memsz + tls.vaddr) & (align - 1))
+}
+
+/// Find the address of the variable at the offset in the TLS block of the
+/// module with the link map at `lm`. A null link map means the program is
+/// statically linked, so the executable is the only module.
+pub fn address<T: Target>(tracee: &T, exe: &Path, tp: u64, offset: u64, lm: u64) -> Result<u64> {
+    if lm == 0 {
+        let tls = tls_header(exe).ok_or(Error::Error(ERROR_NO_TLS))?;
+        return Ok(tp - static_offset(&tls) + offset);
+    }
+
+    // Module IDs are handed out in load order to modules with TLS, which is
+    // the order of the link maps for everything loaded at startup
+    let mut modid = 0;
+    let mut map = lm;
+    while map != 0 {
+        let name = read_string(tracee, read_word(tracee, map + LINK_MAP_NAME)?)?;
+        let path = if name.is_empty() {
+            exe.to_owned()
+        } else {
+            PathBuf::from(OsStr::from_bytes(&name))
+        };
+        match tls_header(&path) {
+            Some(_) => modid += 1,
+            None if map == lm => return Err(Error::Error(ERROR_NO_TLS)),
+            None => (),
+        }
+        map = read_word(tracee, map + LINK_MAP_PREV)?;
+    }
+
+    let stride = if uses_musl(exe) { 8 } else { 16 };
+    let dtv = read_word(tracee, tp + TCB_DTV)?;
+    let block = read_word(tracee, dtv + modid * stride)?;
+
+    // Blocks of dlopen'd modules are only allocated once first used
+    if block == 0 || block == u64::MAX {
+        return Err(Error::Error(ERROR_NO_TLS));
+    }
+    Ok(block + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{static_offset, ProgramHeader};
+
+    #[test]
+    fn static_offset_alignment() {
+        let mut tls = ProgramHeader {
+            memsz: 0x14,
+            align: 8,
+            vaddr: 0x4000,
+            ..ProgramHeader::default()
+        };
+        assert_eq!(static_offset(&tls), 0x18);
+
+        // The block must start just as misaligned as its image
+        tls.vaddr = 0x4004;
+        assert_eq!(static_offset(&tls), 0x14);
+        tls.vaddr = 0x4002;
+        assert_eq!(static_offset(&tls), 0x16);
+    }
+}
@@ -0,0 +1,730 @@
+//! Tracepoints, which collect registers and memory into a trace buffer each
+//! time they're hit instead of stopping, for GDB to look through later with
+//! `tfind`. See "Tracepoint Packets" in the GDB manual.
+
+use crate::{
+    agent::{self, Context},
+    breakpoint::Breakpoints,
+    os::{Registers, Target},
+    packets::{parse_bytes, parse_error, parse_hex},
+    Result, ERROR_TRACE_LOADED,
+};
+
+use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use gdb_remote_protocol::Error;
+use log::debug;
+
+use std::{
+    fmt,
+    io::{self, prelude::*},
+};
+
+/// How much collected data the trace buffer holds, the same as GDB's own
+/// gdbserver by default
+const BUFFER_SIZE: usize = 5 * 1024 * 1024;
+
+/// The start of a trace file, as written by GDB's `tsave`
+const TFILE_MAGIC: &[u8] = b"\x7FTRACE0\n";
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn register_block_size() -> usize {
+    let mut block = Vec::new();
+    Registers::default().encode(&mut block);
+    block.len()
+}
+
+/// What a tracepoint collects when hit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Registers. GDB's mask of which ones it wants is only kept to describe
+    /// the action again, as all of them are always collected.
+    Registers(String),
+    /// Memory at an offset from a register, or at an absolute address
+    Memory { base: Option<u64>, offset: u64, len: u64 },
+    /// Memory picked by the trace instructions of an agent expression
+    Expression(Vec<u8>),
+}
+impl Action {
+    /// Parse the actions of a `QTDP` packet, which follow each other without
+    /// anything in between
+    fn parse_all(mut text: &str) -> Result<Vec<Self>> {
+        let fields_end = |text: &str| {
+            text.find(|c: char| !c.is_ascii_hexdigit() && c != ',' && c != '-')
+                .unwrap_or(text.len())
+        };
+
+        let mut actions = Vec::new();
+        while let Some(kind) = text.chars().next() {
+            text = &text[1..];
+            match kind {
+                'R' => {
+                    let end = fields_end(text);
+                    actions.push(Action::Registers(text[..end].to_owned()));
+                    text = &text[end..];
+                },
+                'M' => {
+                    let end = fields_end(text);
+                    let mut fields = text[..end].split(',');
+                    let mut field = || fields.next().ok_or_else(parse_error);
+                    let base = match field()? {
+                        "-1" => None,
+                        base => Some(parse_hex(base)?),
+                    };
+                    actions.push(Action::Memory {
+                        base,
+                        offset: parse_hex(field()?)?,
+                        len: parse_hex(field()?)?,
+                    });
+                    text = &text[end..];
+                },
+                'X' => {
+                    let comma = text.find(',').ok_or_else(parse_error)?;
+                    let len = parse_hex(&text[..comma])? as usize;
+                    let end = comma + 1 + 2 * len;
+                    let code = text.get(comma + 1..end).ok_or_else(parse_error)?;
+                    actions.push(Action::Expression(parse_bytes(code)?));
+                    text = &text[end..];
+                },
+                _ => return Err(parse_error()),
+            }
+        }
+        Ok(actions)
+    }
+}
+/// Formats the action like in `QTDP`
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Registers(mask) => write!(f, "R{}", mask),
+            Action::Memory { base: None, offset, len } => write!(f, "M-1,{:x},{:x}", offset, len),
+            Action::Memory { base: Some(base), offset, len } => write!(f, "M{:x},{:x},{:x}", base, offset, len),
+            Action::Expression(code) => write!(f, "X{:x},{}", code.len(), hex(code)),
+        }
+    }
+}
+
+pub struct Tracepoint {
+    pub number: u64,
+    pub address: u64,
+    pub enabled: bool,
+    /// For how many single-steps after a hit to collect `step_actions`
+    pub step_count: u64,
+    /// How many hits of this tracepoint end the trace, or 0 for no limit
+    pub pass_count: u64,
+    /// An agent expression that has to be true to collect anything
+    pub condition: Option<Vec<u8>>,
+    pub actions: Vec<Action>,
+    pub step_actions: Vec<Action>,
+    hits: u64,
+}
+/// Formats the tracepoint like in `QTDP`, without its actions
+impl fmt::Display for Tracepoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:x}:{:x}:{}:{:x}:{:x}",
+            self.number,
+            self.address,
+            if self.enabled { 'E' } else { 'D' },
+            self.step_count,
+            self.pass_count
+        )?;
+        if let Some(condition) = &self.condition {
+            write!(f, ":X{:x},{}", condition.len(), hex(condition))?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything collected by one hit or step of a tracepoint
+pub struct Frame {
+    /// The number of the tracepoint that collected it
+    pub tracepoint: u64,
+    /// All registers, laid out like the reply to `g`, or nothing if they
+    /// weren't collected
+    pub registers: Vec<u8>,
+    /// Blocks of memory and their addresses
+    pub memory: Vec<(u64, Vec<u8>)>,
+}
+impl Frame {
+    fn size(&self) -> usize {
+        self.registers.len() + self.memory.iter().map(|(_, block)| block.len()).sum::<usize>()
+    }
+
+    fn pc(&self) -> Option<u64> {
+        if self.registers.is_empty() {
+            return None;
+        }
+        Registers::decode(&self.registers).rip
+    }
+
+    /// Copy collected memory into the buffer, and return how much of it from
+    /// the start could be filled
+    pub fn read(&self, address: u64, buf: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = address + done as u64;
+            let block = self.memory.iter().find(|(start, block)| {
+                *start <= at && at < start + block.len() as u64
+            });
+            let (start, block) = match block {
+                Some(block) => block,
+                None => break,
+            };
+            let block = &block[(at - start) as usize..];
+            let len = block.len().min(buf.len() - done);
+            buf[done..done + len].copy_from_slice(&block[..len]);
+            done += len;
+        }
+        done
+    }
+}
+
+/// Lets agent expressions look at a thread that hit a tracepoint, and
+/// gathers the memory they trace
+struct Collector<'a, T: Target> {
+    tracee: &'a T,
+    regs: Registers,
+    memory: Vec<(u64, Vec<u8>)>,
+}
+impl<'a, T: Target> Context for Collector<'a, T> {
+    fn register(&mut self, regno: u64) -> Result<u64> {
+        self.regs.get(regno).ok_or(Error::Unimplemented)
+    }
+    fn memory(&mut self, address: u64, buf: &mut [u8]) -> Result<()> {
+        self.tracee.getmem(address as usize, buf)?;
+        Ok(())
+    }
+    fn trace(&mut self, address: u64, size: u64) -> Result<()> {
+        let mut block = vec![0; size.min(BUFFER_SIZE as u64) as usize];
+        self.tracee.getmem(address as usize, &mut block)?;
+        self.memory.push((address, block));
+        Ok(())
+    }
+}
+impl<'a, T: Target> Collector<'a, T> {
+    fn new(tracee: &'a T) -> Result<Self> {
+        Ok(Self {
+            tracee,
+            regs: tracee.getregs()?,
+            memory: Vec::new(),
+        })
+    }
+
+    /// Carry out the actions, skipping any memory that can't be read
+    fn collect(mut self, tracepoint: u64, actions: &[Action]) -> Frame {
+        for action in actions {
+            let result = match *action {
+                Action::Registers(_) => Ok(()),
+                Action::Memory { base, offset, len } => {
+                    let base = base.map(|regno| self.register(regno)).unwrap_or(Ok(0));
+                    base.and_then(|base| self.trace(base.wrapping_add(offset), len))
+                },
+                Action::Expression(ref code) => agent::exec(code, &mut self),
+            };
+            if let Err(err) = result {
+                debug!("Tracepoint {} failed to collect {}: {:?}", tracepoint, action, err);
+            }
+        }
+
+        let mut registers = Vec::new();
+        self.regs.encode(&mut registers);
+        Frame {
+            tracepoint,
+            registers,
+            memory: self.memory,
+        }
+    }
+}
+
+/// Whether the trace is running, or why it isn't
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Status {
+    #[default]
+    NotRun,
+    Running,
+    /// Stopped by GDB
+    Stopped,
+    /// Stopped because the buffer was full
+    Full,
+    /// Stopped because the tracepoint was hit as often as allowed
+    PassCount(u64),
+    /// Stopped because the condition of the tracepoint failed to evaluate
+    Error(u64),
+}
+impl Status {
+    /// Parse the reply to `qTStatus`, after the T, as found in trace files.
+    /// A trace saved while running isn't anymore once loaded.
+    fn parse(status: &str) -> Self {
+        let reason = status.split(';').nth(1).unwrap_or("");
+        let tracepoint = reason.rsplit(':').next().and_then(|number| u64::from_str_radix(number, 16).ok());
+        match (reason.split(':').next(), tracepoint) {
+            (Some("tnotrun"), _) => Status::NotRun,
+            (Some("tfull"), _) => Status::Full,
+            (Some("tpasscount"), Some(tracepoint)) => Status::PassCount(tracepoint),
+            (Some("terror"), Some(tracepoint)) => Status::Error(tracepoint),
+            _ => Status::Stopped,
+        }
+    }
+}
+/// What `QTFrame` looks for. Everything but a frame number is searched for
+/// starting after the frame currently selected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameQuery {
+    /// The frame with this number, or none to go back to the live tracee
+    Number(Option<usize>),
+    /// A frame collected at this address
+    Pc(u64),
+    /// A frame collected by this tracepoint
+    Tracepoint(u64),
+    /// A frame collected between these addresses, inclusive
+    Range(u64, u64),
+    /// A frame collected outside these addresses
+    Outside(u64, u64),
+}
+
+#[derive(Default)]
+pub struct Trace {
+    tracepoints: Vec<Tracepoint>,
+    /// Whether the actions being defined are while-stepping ones, which last
+    /// until the next tracepoint is defined
+    stepping: bool,
+    status: Status,
+    frames: Vec<Frame>,
+    /// Bytes of the buffer taken up by frames
+    used: usize,
+    /// The frame register and memory reads come from instead of the tracee
+    selected: Option<usize>,
+    /// Whether the trace was loaded from a file, and can only be looked at
+    read_only: bool,
+}
+impl Trace {
+    /// Forget all tracepoints and what they collected
+    pub fn init(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::Error(ERROR_TRACE_LOADED));
+        }
+        *self = Self::default();
+        Ok(())
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Define a tracepoint, or add actions to the last one, given the
+    /// arguments of `QTDP`
+    pub fn define(&mut self, definition: &str) -> Result<()> {
+        if self.read_only {
+            return Err(Error::Error(ERROR_TRACE_LOADED));
+        }
+        // A trailing dash only says more actions are coming
+        let definition = definition.strip_suffix('-').unwrap_or(definition);
+
+        if let Some(more) = definition.strip_prefix('-') {
+            let mut fields = more.splitn(3, ':');
+            let mut field = || fields.next().ok_or_else(parse_error);
+            let number = parse_hex(field()?)?;
+            let address = parse_hex(field()?)?;
+            let mut actions = field()?;
+            if let Some(rest) = actions.strip_prefix('S') {
+                self.stepping = true;
+                actions = rest;
+            }
+
+            let tracepoint = self.tracepoints
+                .iter_mut()
+                .find(|tracepoint| tracepoint.number == number && tracepoint.address == address)
+                .ok_or_else(parse_error)?;
+            let actions = Action::parse_all(actions)?;
+            if self.stepping {
+                tracepoint.step_actions.extend(actions);
+            } else {
+                tracepoint.actions.extend(actions);
+            }
+            return Ok(());
+        }
+
+        let mut fields = definition.split(':');
+        let mut field = || fields.next().ok_or_else(parse_error);
+        let mut tracepoint = Tracepoint {
+            number: parse_hex(field()?)?,
+            address: parse_hex(field()?)?,
+            enabled: match field()? {
+                "E" => true,
+                "D" => false,
+                _ => return Err(parse_error()),
+            },
+            step_count: parse_hex(field()?)?,
+            pass_count: parse_hex(field()?)?,
+            condition: None,
+            actions: Vec::new(),
+            step_actions: Vec::new(),
+            hits: 0,
+        };
+        for option in fields {
+            match option.chars().next() {
+                Some('X') => {
+                    let comma = option.find(',').ok_or_else(parse_error)?;
+                    let code = parse_bytes(&option[comma + 1..])?;
+                    if code.len() as u64 != parse_hex(&option[1..comma])? {
+                        return Err(parse_error());
+                    }
+                    tracepoint.condition = Some(code);
+                },
+                // Fast and static tracepoints, which aren't advertised
+                _ => return Err(Error::Unimplemented),
+            }
+        }
+
+        self.stepping = false;
+        self.tracepoints.retain(|old| (old.number, old.address) != (tracepoint.number, tracepoint.address));
+        self.tracepoints.push(tracepoint);
+        Ok(())
+    }
+
+    /// Start collecting, and return the addresses of the enabled tracepoints
+    /// for inserting breakpoints at
+    pub fn start(&mut self) -> Result<Vec<u64>> {
+        if self.read_only {
+            return Err(Error::Error(ERROR_TRACE_LOADED));
+        }
+        self.frames.clear();
+        self.used = 0;
+        self.selected = None;
+        self.status = Status::Running;
+        for tracepoint in &mut self.tracepoints {
+            tracepoint.hits = 0;
+        }
+        Ok(self.tracepoints
+            .iter()
+            .filter(|tracepoint| tracepoint.enabled)
+            .map(|tracepoint| tracepoint.address)
+            .collect())
+    }
+
+    pub fn stop(&mut self) {
+        if self.running() {
+            self.status = Status::Stopped;
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.status == Status::Running
+    }
+
+    /// Describe the trace in the format of the reply to `qTStatus`
+    pub fn status(&self) -> String {
+        let reason = match self.status {
+            Status::NotRun => String::from("tnotrun:0"),
+            Status::Running => String::from("tunknown:0"),
+            Status::Stopped => String::from("tstop::0"),
+            Status::Full => String::from("tfull:0"),
+            Status::PassCount(number) => format!("tpasscount:{:x}", number),
+            Status::Error(number) => format!("terror:{}:{:x}", hex(b"condition failed"), number),
+        };
+        format!(
+            "T{};{};tframes:{:x};tcreated:{:x};tfree:{:x};tsize:{:x};circular:0;disconn:0",
+            self.running() as u8,
+            reason,
+            self.frames.len(),
+            self.frames.len(),
+            BUFFER_SIZE - self.used,
+            BUFFER_SIZE
+        )
+    }
+
+    /// Keep a frame if it fits in the buffer, or stop the trace
+    fn record(&mut self, frame: Frame) {
+        if self.used + frame.size() > BUFFER_SIZE {
+            self.status = Status::Full;
+            return;
+        }
+        self.used += frame.size();
+        self.frames.push(frame);
+    }
+
+    /// Collect frames for the tracepoints at the address the current thread
+    /// just hit. Returns the tracepoints, by their index, that want frames
+    /// for the steps that follow too.
+    pub fn hit<T: Target>(&mut self, tracee: &T, address: u64) -> Result<Vec<usize>> {
+        let mut stepping = Vec::new();
+        for i in 0..self.tracepoints.len() {
+            let tracepoint = &self.tracepoints[i];
+            if !self.running() || !tracepoint.enabled || tracepoint.address != address {
+                continue;
+            }
+
+            let mut collector = Collector::new(tracee)?;
+            if let Some(condition) = &tracepoint.condition {
+                match agent::eval(condition, &mut collector) {
+                    Ok(0) => continue,
+                    Ok(_) => (),
+                    Err(err) => {
+                        debug!("Condition of tracepoint {} failed: {:?}", tracepoint.number, err);
+                        self.status = Status::Error(tracepoint.number);
+                        break;
+                    },
+                }
+            }
+            let frame = collector.collect(tracepoint.number, &tracepoint.actions);
+            if tracepoint.step_count > 0 {
+                stepping.push(i);
+            }
+
+            let tracepoint = &mut self.tracepoints[i];
+            tracepoint.hits += 1;
+            let out_of_passes = tracepoint.pass_count != 0 && tracepoint.hits >= tracepoint.pass_count;
+            let number = tracepoint.number;
+            self.record(frame);
+            if out_of_passes && self.running() {
+                self.status = Status::PassCount(number);
+            }
+        }
+        Ok(stepping)
+    }
+
+    /// Collect the while-stepping frames of the tracepoints returned by `hit`,
+    /// after the current thread took its first step past them, by stepping
+    /// it further. Returns false if it stopped for anything but a step.
+    pub fn while_stepping<T: Target>(&mut self, tracee: &T, breakpoints: &Breakpoints, stepping: &[usize]) -> Result<bool> {
+        let steps = stepping.iter().map(|&i| self.tracepoints[i].step_count).max().unwrap_or(0);
+        for step in 1..=steps {
+            if step > 1 && !breakpoints.step(tracee, None)? {
+                return Ok(false);
+            }
+            for &i in stepping {
+                let tracepoint = &self.tracepoints[i];
+                if !self.running() || tracepoint.step_count < step {
+                    continue;
+                }
+                let frame = Collector::new(tracee)?.collect(tracepoint.number, &tracepoint.step_actions);
+                self.record(frame);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Select the frame that register and memory reads come from. Returns
+    /// its number and tracepoint, or None and keeps the current selection if
+    /// there's no such frame.
+    pub fn select(&mut self, query: FrameQuery) -> Option<(usize, u64)> {
+        let after = self.selected.map_or(0, |selected| selected + 1);
+        let found = match query {
+            FrameQuery::Number(None) => {
+                self.selected = None;
+                return None;
+            },
+            FrameQuery::Number(Some(number)) => Some(number).filter(|&number| number < self.frames.len()),
+            _ => (after..self.frames.len()).find(|&number| {
+                let frame = &self.frames[number];
+                match (query, frame.pc()) {
+                    (FrameQuery::Tracepoint(tracepoint), _) => frame.tracepoint == tracepoint,
+                    (FrameQuery::Pc(address), Some(pc)) => pc == address,
+                    (FrameQuery::Range(start, end), Some(pc)) => start <= pc && pc <= end,
+                    (FrameQuery::Outside(start, end), Some(pc)) => pc < start || end < pc,
+                    _ => false,
+                }
+            }),
+        };
+        let number = found?;
+        self.selected = Some(number);
+        Some((number, self.frames[number].tracepoint))
+    }
+
+    /// The frame to read registers and memory from, if any
+    pub fn selected(&self) -> Option<&Frame> {
+        self.selected.map(|number| &self.frames[number])
+    }
+
+    /// Write the tracepoints and their frames in the trace file format of
+    /// GDB's `tsave`, which `target tfile` can open
+    pub fn save<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(TFILE_MAGIC)?;
+        writeln!(out, "R {:x}", register_block_size())?;
+        for line in include_str!("../target-desc.xml").lines() {
+            writeln!(out, "tdesc {}", line)?;
+        }
+        // The same as the reply to qTStatus, but for whether it's running
+        writeln!(out, "status {}", &self.status()[1..])?;
+        for tracepoint in &self.tracepoints {
+            let (number, address) = (tracepoint.number, tracepoint.address);
+            writeln!(out, "tp T{}", tracepoint)?;
+            for action in &tracepoint.actions {
+                writeln!(out, "tp A{:x}:{:x}:{}", number, address, action)?;
+            }
+            for action in &tracepoint.step_actions {
+                writeln!(out, "tp S{:x}:{:x}:{}", number, address, action)?;
+            }
+        }
+        writeln!(out)?;
+
+        for frame in &self.frames {
+            let mut data = Vec::new();
+            if !frame.registers.is_empty() {
+                data.push(b'R');
+                data.extend_from_slice(&frame.registers);
+            }
+            for (address, block) in &frame.memory {
+                // Memory blocks have a 16-bit length
+                for (i, chunk) in block.chunks(usize::from(u16::MAX)).enumerate() {
+                    data.push(b'M');
+                    data.write_u64::<NativeEndian>(address + (i * usize::from(u16::MAX)) as u64)?;
+                    data.write_u16::<NativeEndian>(chunk.len() as u16)?;
+                    data.extend_from_slice(chunk);
+                }
+            }
+            out.write_u16::<NativeEndian>(frame.tracepoint as u16)?;
+            out.write_u32::<NativeEndian>(data.len() as u32)?;
+            out.write_all(&data)?;
+        }
+        // Frames end with one of tracepoint 0
+        out.write_u32::<NativeEndian>(0)?;
+        Ok(())
+    }
+
+    /// Read a trace file written by `save` or GDB's `tsave`. The trace in it
+    /// can be looked through, but not changed or started again.
+    pub fn load<R: BufRead>(input: &mut R) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what);
+
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if magic[..] != *TFILE_MAGIC {
+            return Err(invalid("not a trace file"));
+        }
+
+        let mut trace = Trace {
+            status: Status::Stopped,
+            ..Trace::default()
+        };
+        loop {
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end_matches('\n');
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some(size) = line.strip_prefix("R ") {
+                if usize::from_str_radix(size, 16).ok() != Some(register_block_size()) {
+                    return Err(invalid("registers of another architecture"));
+                }
+            } else if let Some(status) = line.strip_prefix("status ") {
+                trace.status = Status::parse(status);
+            } else if let Some(definition) = line.strip_prefix("tp ") {
+                let mut fields = definition.get(1..).unwrap_or("").splitn(3, ':');
+                let mut field = || fields.next().unwrap_or("");
+                let result = match definition.chars().next() {
+                    Some('T') => trace.define(&definition[1..]),
+                    Some('A') => trace.define(&format!("-{}:{}:{}", field(), field(), field())),
+                    Some('S') => trace.define(&format!("-{}:{}:S{}", field(), field(), field())),
+                    // Source strings, which GDB only wants for itself
+                    _ => Ok(()),
+                };
+                result.map_err(|_| invalid("bad tracepoint definition"))?;
+            }
+            // Anything else, like the target description, isn't needed
+        }
+
+        loop {
+            let tracepoint = input.read_u16::<NativeEndian>()?;
+            if tracepoint == 0 {
+                break;
+            }
+            let mut data = vec![0; input.read_u32::<NativeEndian>()? as usize];
+            input.read_exact(&mut data)?;
+
+            let mut frame = Frame {
+                tracepoint: u64::from(tracepoint),
+                registers: Vec::new(),
+                memory: Vec::new(),
+            };
+            let mut data = &data[..];
+            while !data.is_empty() {
+                match data.read_u8()? {
+                    b'R' => {
+                        frame.registers = vec![0; register_block_size()];
+                        data.read_exact(&mut frame.registers)?;
+                    },
+                    b'M' => {
+                        let address = data.read_u64::<NativeEndian>()?;
+                        let mut block = vec![0; usize::from(data.read_u16::<NativeEndian>()?)];
+                        data.read_exact(&mut block)?;
+                        frame.memory.push((address, block));
+                    },
+                    // Trace state variables, which are never collected here
+                    b'V' => {
+                        data.read_u32::<NativeEndian>()?;
+                        data.read_u64::<NativeEndian>()?;
+                    },
+                    _ => return Err(invalid("unknown kind of collected data")),
+                }
+            }
+            trace.used += frame.size();
+            trace.frames.push(frame);
+        }
+
+        trace.read_only = true;
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{register_block_size, Action, Frame, Trace};
+
+    #[test]
+    fn define() {
+        let mut trace = Trace::default();
+        trace.define("1:401136:E:2:5:X3,260000-").unwrap();
+        trace.define("-1:401136:R1ffffM-1,601040,4M6,fffffffffffffff8,8-").unwrap();
+        // Everything after the S is for while-stepping
+        trace.define("-1:401136:SX6,2600070d0827-").unwrap();
+        trace.define("-1:401136:R1ffff").unwrap();
+
+        let tracepoint = &trace.tracepoints[0];
+        assert_eq!(tracepoint.to_string(), "1:401136:E:2:5:X3,260000");
+        assert_eq!(tracepoint.actions, vec![
+            Action::Registers(String::from("1ffff")),
+            Action::Memory { base: None, offset: 0x601040, len: 4 },
+            Action::Memory { base: Some(6), offset: 0xFFFF_FFFF_FFFF_FFF8, len: 8 },
+        ]);
+        assert_eq!(tracepoint.step_actions, vec![
+            Action::Expression(vec![0x26, 0, 7, 0x0D, 8, 0x27]),
+            Action::Registers(String::from("1ffff")),
+        ]);
+        assert_eq!(tracepoint.actions[2].to_string(), "M6,fffffffffffffff8,8");
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut trace = Trace::default();
+        trace.define("1:401136:E:0:0").unwrap();
+        trace.define("-1:401136:R1ffffM-1,601040,4").unwrap();
+        trace.start().unwrap();
+        trace.stop();
+        trace.record(Frame {
+            tracepoint: 1,
+            registers: vec![7; register_block_size()],
+            memory: vec![(0x601040, vec![1, 2, 3, 4])],
+        });
+
+        let mut file = Vec::new();
+        trace.save(&mut file).unwrap();
+        let mut loaded = Trace::load(&mut &file[..]).unwrap();
+        assert_eq!(loaded.status(), trace.status());
+        assert_eq!(loaded.tracepoints[0].actions, trace.tracepoints[0].actions);
+
+        let mut buf = [0; 8];
+        assert_eq!(loaded.select(super::FrameQuery::Tracepoint(1)), Some((0, 1)));
+        assert_eq!(loaded.selected().unwrap().read(0x601042, &mut buf), 2);
+        assert_eq!(buf[..2], [3, 4]);
+        assert!(loaded.start().is_err());
+    }
+}
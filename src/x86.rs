@@ -0,0 +1,513 @@
+//! Just enough x86_64 instruction decoding to find where control flow can
+//! leave a straight run of instructions, and what memory each one touches
+
+/// Where execution can go after an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Always the next instruction
+    Next,
+    /// Either the next instruction or the target, like a conditional jump
+    Branch(u64),
+    /// Always the target
+    Jump(u64),
+    /// The target, which returns to the next instruction
+    Call(u64),
+    /// Somewhere only known once executed, like returns, indirect jumps and
+    /// system calls
+    Indirect,
+    /// A breakpoint instruction, which stops the tracee by itself
+    Trap,
+}
+
+/// A segment whose base is added to an address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segment {
+    Fs,
+    Gs,
+}
+
+/// Memory an instruction may access, other than through the stack pointer.
+/// Registers are numbered like in the encoding, from rax as 0 to r15 as 15.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operand {
+    pub segment: Option<Segment>,
+    pub base: Option<u8>,
+    pub index: Option<u8>,
+    pub scale: u8,
+    pub disp: i64,
+    /// Whether the displacement is from the end of the instruction
+    pub rip_relative: bool,
+    /// An upper bound of how many bytes are accessed
+    pub size: usize,
+}
+impl Operand {
+    /// Calculate the address, reading registers by their number and segment
+    /// bases with the functions
+    pub fn address<R, S>(&self, register: R, segment: S, end: u64) -> u64
+    where
+        R: Fn(u8) -> u64,
+        S: Fn(Segment) -> u64,
+    {
+        let mut address = self.disp as u64;
+        if self.rip_relative {
+            address = address.wrapping_add(end);
+        }
+        if let Some(base) = self.base {
+            address = address.wrapping_add(register(base));
+        }
+        if let Some(index) = self.index {
+            address = address.wrapping_add(register(index).wrapping_mul(u64::from(self.scale)));
+        }
+        if let Some(seg) = self.segment {
+            address = address.wrapping_add(segment(seg));
+        }
+        address
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub len: usize,
+    pub flow: Flow,
+    pub memory: Option<Operand>,
+}
+
+/// The longest any x86 instruction may be
+pub const MAX_LEN: usize = 15;
+/// The breakpoint instruction
+pub const INT3: u8 = 0xCC;
+/// How much memory a plain operand can access, at most one zmm register
+const OPERAND_SIZE: usize = 64;
+/// How much the xsave family of instructions can write, a generous bound
+const XSAVE_SIZE: usize = 4096;
+// Registers named by instructions that implicitly use them
+const RDI: u8 = 7;
+
+#[derive(Clone, Copy)]
+enum Imm {
+    None,
+    Byte,
+    Word,
+    /// 32 bits, or 16 with an operand size prefix
+    Z,
+    /// Like Z, but 64 bits with REX.W
+    V,
+    /// ENTER's 16 + 8 bits
+    Enter,
+    /// A memory offset the size of an address
+    Offset,
+}
+
+struct Prefixes {
+    operand_size: bool,
+    address_size: bool,
+    segment: Option<Segment>,
+    rex_w: bool,
+    rex_x: bool,
+    rex_b: bool,
+}
+
+/// Returns whether a one-byte opcode has a ModRM byte, and its immediate.
+/// None means the opcode isn't valid in 64-bit mode.
+fn one_byte(opcode: u8) -> Option<(bool, Imm)> {
+    Some(match opcode {
+        0x00..=0x3F => match opcode & 7 {
+            0..=3 => (true, Imm::None),
+            4 => (false, Imm::Byte),
+            5 => (false, Imm::Z),
+            _ => return None,
+        },
+        0x50..=0x5F => (false, Imm::None),
+        0x63 => (true, Imm::None),
+        0x68 => (false, Imm::Z),
+        0x69 => (true, Imm::Z),
+        0x6A => (false, Imm::Byte),
+        0x6B => (true, Imm::Byte),
+        0x6C..=0x6F => (false, Imm::None),
+        0x70..=0x7F => (false, Imm::Byte),
+        0x80 | 0x83 => (true, Imm::Byte),
+        0x81 => (true, Imm::Z),
+        0x84..=0x8F => (true, Imm::None),
+        0x90..=0x99 | 0x9B..=0x9F => (false, Imm::None),
+        0xA0..=0xA3 => (false, Imm::Offset),
+        0xA4..=0xA7 | 0xAA..=0xAF => (false, Imm::None),
+        0xA8 => (false, Imm::Byte),
+        0xA9 => (false, Imm::Z),
+        0xB0..=0xB7 => (false, Imm::Byte),
+        0xB8..=0xBF => (false, Imm::V),
+        0xC0 | 0xC1 | 0xC6 => (true, Imm::Byte),
+        0xC2 | 0xCA => (false, Imm::Word),
+        0xC3 | 0xC9 | 0xCB | 0xCC | 0xCF => (false, Imm::None),
+        0xC7 => (true, Imm::Z),
+        0xC8 => (false, Imm::Enter),
+        0xCD => (false, Imm::Byte),
+        0xD0..=0xD3 | 0xD8..=0xDF => (true, Imm::None),
+        0xD7 => (false, Imm::None),
+        0xE0..=0xE7 => (false, Imm::Byte),
+        0xE8 | 0xE9 => (false, Imm::Z),
+        0xEB => (false, Imm::Byte),
+        0xEC..=0xEF | 0xF1 | 0xF4 | 0xF5 | 0xF8..=0xFD => (false, Imm::None),
+        0xF6 | 0xF7 | 0xFE | 0xFF => (true, Imm::None),
+        _ => return None,
+    })
+}
+
+/// Same as one_byte, for opcodes following 0x0F
+fn two_byte(opcode: u8) -> Option<(bool, Imm)> {
+    Some(match opcode {
+        0x00..=0x03 | 0x0D | 0x10..=0x1F | 0x20..=0x23 | 0x28..=0x2F => (true, Imm::None),
+        0x05..=0x09 | 0x0B | 0x0E | 0x30..=0x37 | 0x77 => (false, Imm::None),
+        0x0F => (true, Imm::Byte),
+        0x40..=0x6F | 0x74..=0x76 | 0x78..=0x7F => (true, Imm::None),
+        0x70..=0x73 => (true, Imm::Byte),
+        0x80..=0x8F => (false, Imm::Z),
+        0x90..=0x9F => (true, Imm::None),
+        0xA0..=0xA2 | 0xA8..=0xAA | 0xC8..=0xCF => (false, Imm::None),
+        0xA3 | 0xA5 | 0xAB | 0xAD..=0xAF | 0xB0..=0xB9 | 0xBB..=0xBF => (true, Imm::None),
+        0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6 => (true, Imm::Byte),
+        0xC0 | 0xC1 | 0xC3 | 0xC7 => (true, Imm::None),
+        0xD0..=0xFF => (true, Imm::None),
+        _ => return None,
+    })
+}
+
+/// Length of a ModRM byte and everything it implies, or None if cut short
+fn modrm_len(bytes: &[u8]) -> Option<usize> {
+    let modrm = *bytes.first()?;
+    let (mode, rm) = (modrm >> 6, modrm & 7);
+    if mode == 3 {
+        return Some(1);
+    }
+
+    let mut len = 1;
+    let mut base = rm;
+    if rm == 4 {
+        base = bytes.get(1)? & 7;
+        len += 1;
+    }
+    len += match mode {
+        0 if rm == 5 || (rm == 4 && base == 5) => 4,
+        0 => 0,
+        1 => 1,
+        _ => 4,
+    };
+    Some(len)
+}
+
+/// The memory operand of a ModRM byte, if it has one. The displacement of
+/// EVEX instructions is compressed, and has to be scaled.
+fn modrm_operand(bytes: &[u8], prefixes: &Prefixes, disp_scale: i64) -> Option<Operand> {
+    let modrm = *bytes.first()?;
+    let (mode, rm) = (modrm >> 6, modrm & 7);
+    if mode == 3 {
+        return None;
+    }
+    let extend = |extended: bool, reg: u8| if extended { reg | 8 } else { reg };
+
+    let mut operand = Operand {
+        segment: prefixes.segment,
+        base: Some(extend(prefixes.rex_b, rm)),
+        index: None,
+        scale: 1,
+        disp: 0,
+        rip_relative: false,
+        size: OPERAND_SIZE,
+    };
+    let mut i = 1;
+    if rm == 4 {
+        let sib = *bytes.get(1)?;
+        i += 1;
+        operand.scale = 1 << (sib >> 6);
+        // Without REX.X, the stack pointer as index means no index at all
+        operand.index = Some(extend(prefixes.rex_x, (sib >> 3) & 7)).filter(|&index| index != 4);
+        if mode == 0 && sib & 7 == 5 {
+            operand.base = None;
+        } else {
+            operand.base = Some(extend(prefixes.rex_b, sib & 7));
+        }
+    } else if mode == 0 && rm == 5 {
+        operand.base = None;
+        operand.rip_relative = true;
+    }
+
+    let disp32 = |bytes: &[u8]| -> Option<i64> {
+        let disp = bytes.get(i..i + 4)?;
+        Some(i64::from(i32::from_le_bytes([disp[0], disp[1], disp[2], disp[3]])))
+    };
+    operand.disp = match mode {
+        0 if operand.base.is_none() => disp32(bytes)?,
+        0 => 0,
+        1 => i64::from(*bytes.get(i)? as i8) * disp_scale,
+        _ => disp32(bytes)?,
+    };
+    Some(operand)
+}
+
+fn imm_len(imm: Imm, prefixes: &Prefixes) -> usize {
+    match imm {
+        Imm::None => 0,
+        Imm::Byte => 1,
+        Imm::Word => 2,
+        Imm::Enter => 3,
+        Imm::Z if prefixes.operand_size => 2,
+        Imm::Z => 4,
+        Imm::V if prefixes.rex_w => 8,
+        Imm::V => imm_len(Imm::Z, prefixes),
+        Imm::Offset if prefixes.address_size => 4,
+        Imm::Offset => 8,
+    }
+}
+
+fn rel(bytes: &[u8], end: u64) -> u64 {
+    let disp = match bytes.len() {
+        1 => i64::from(bytes[0] as i8),
+        _ => i64::from(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+    };
+    end.wrapping_add(disp as u64)
+}
+
+/// Decode the length, control flow and memory operand of the instruction at
+/// the start of the bytes, which were read from the address. Returns None
+/// for anything not understood, which is best single-stepped.
+pub fn decode(bytes: &[u8], address: u64) -> Option<Instruction> {
+    let mut prefixes = Prefixes {
+        operand_size: false,
+        address_size: false,
+        segment: None,
+        rex_w: false,
+        rex_x: false,
+        rex_b: false,
+    };
+
+    let mut i = 0;
+    loop {
+        match *bytes.get(i)? {
+            0x66 => prefixes.operand_size = true,
+            0x67 => prefixes.address_size = true,
+            0x64 => prefixes.segment = Some(Segment::Fs),
+            0x65 => prefixes.segment = Some(Segment::Gs),
+            0xF0 | 0xF2 | 0xF3 | 0x26 | 0x2E | 0x36 | 0x3E => (),
+            _ => break,
+        }
+        i += 1;
+    }
+    // REX must come right before the opcode
+    if let 0x40..=0x4F = bytes.get(i)? {
+        prefixes.rex_w = bytes[i] & 0x08 != 0;
+        prefixes.rex_x = bytes[i] & 0x02 != 0;
+        prefixes.rex_b = bytes[i] & 0x01 != 0;
+        i += 1;
+    }
+
+    let opcode = *bytes.get(i)?;
+    i += 1;
+
+    // VEX and EVEX encoded instructions are never control flow. Their
+    // register extension bits are inverted.
+    let vex = match opcode {
+        0xC5 => Some((1, 1)),
+        0xC4 => Some((2, bytes.get(i)? & 0x1F)),
+        0x62 => Some((3, bytes.get(i)? & 0x03)),
+        _ => None,
+    };
+    if let Some((payload, map)) = vex {
+        let mut disp_scale = 1;
+        if opcode != 0xC5 {
+            prefixes.rex_x = bytes[i] & 0x40 == 0;
+            prefixes.rex_b = bytes[i] & 0x20 == 0;
+        }
+        if opcode == 0x62 {
+            // Compressed displacements are in units of the whole vector, or
+            // of one element when broadcasting
+            let p2 = *bytes.get(i + 2)?;
+            disp_scale = match (p2 & 0x10 != 0, bytes[i + 1] & 0x80 != 0) {
+                (true, true) => 8,
+                (true, false) => 4,
+                (false, _) => 16 << ((p2 >> 5) & 3).min(2),
+            };
+        }
+        i += payload;
+        let opcode = *bytes.get(i)?;
+        i += 1;
+        let imm = match map {
+            1 if opcode == 0x77 => return Some(Instruction { len: i, flow: Flow::Next, memory: None }),
+            1 => two_byte(opcode)?.1,
+            2 => Imm::None,
+            3 => Imm::Byte,
+            _ => return None,
+        };
+        let memory = modrm_operand(bytes.get(i..)?, &prefixes, disp_scale);
+        i += modrm_len(bytes.get(i..)?)? + imm_len(imm, &prefixes);
+        return if i <= bytes.len().min(MAX_LEN) {
+            Some(Instruction { len: i, flow: Flow::Next, memory })
+        } else {
+            None
+        };
+    }
+
+    // The second byte of two-byte opcodes, which is all that's needed of
+    // them to tell control flow apart
+    let mut escaped = None;
+    let (modrm, imm) = if opcode == 0x0F {
+        let opcode = *bytes.get(i)?;
+        i += 1;
+        match opcode {
+            0x38 => {
+                i += 1;
+                (true, Imm::None)
+            },
+            0x3A => {
+                i += 1;
+                (true, Imm::Byte)
+            },
+            _ => {
+                escaped = Some(opcode);
+                two_byte(opcode)?
+            },
+        }
+    } else {
+        one_byte(opcode)?
+    };
+
+    let mut reg = None;
+    let mut memory = None;
+    if modrm {
+        reg = Some((bytes.get(i)? >> 3) & 7);
+        memory = modrm_operand(&bytes[i..], &prefixes, 1);
+        i += modrm_len(&bytes[i..])?;
+    }
+    match (opcode, escaped) {
+        // String instructions write where rdi points
+        (0x6C, None) | (0x6D, None) | (0xA4, None) | (0xA5, None) | (0xAA, None) | (0xAB, None)
+        | (0x0F, Some(0xF7)) => {
+            memory = Some(Operand {
+                segment: None,
+                base: Some(RDI),
+                index: None,
+                scale: 1,
+                disp: 0,
+                rip_relative: false,
+                size: OPERAND_SIZE,
+            });
+        },
+        (0x0F, Some(0xAE)) | (0x0F, Some(0xC7)) => {
+            if let Some(memory) = &mut memory {
+                memory.size = XSAVE_SIZE;
+            }
+        },
+        _ => (),
+    }
+    let imm_size = match (opcode, escaped, reg) {
+        // TEST is the only form of these with an immediate
+        (0xF6, None, Some(0..=1)) => 1,
+        (0xF7, None, Some(0..=1)) => imm_len(Imm::Z, &prefixes),
+        // Near branches ignore the operand size prefix in 64-bit mode
+        (0xE8, None, _) | (0xE9, None, _) | (0x0F, Some(0x80..=0x8F), _) => 4,
+        _ => imm_len(imm, &prefixes),
+    };
+    let imm_start = i;
+    i += imm_size;
+
+    if i > bytes.len() || i > MAX_LEN {
+        return None;
+    }
+
+    let end = address.wrapping_add(i as u64);
+    let target = || rel(&bytes[imm_start..i], end);
+    let flow = match (opcode, escaped) {
+        (0x70..=0x7F, None) | (0xE0..=0xE3, None) | (0x0F, Some(0x80..=0x8F)) => Flow::Branch(target()),
+        (0xE9, None) | (0xEB, None) => Flow::Jump(target()),
+        (0xE8, None) => Flow::Call(target()),
+        (0xCC, None) => Flow::Trap,
+        (0xC2, None) | (0xC3, None) | (0xCA, None) | (0xCB, None) | (0xCD, None) | (0xCF, None)
+        | (0xF1, None) | (0xF4, None) => Flow::Indirect,
+        // syscall, sysret, ud2, sysenter, sysexit
+        (0x0F, Some(0x05)) | (0x0F, Some(0x07)) | (0x0F, Some(0x0B)) | (0x0F, Some(0x34))
+        | (0x0F, Some(0x35)) => Flow::Indirect,
+        // Indirect calls and jumps
+        (0xFF, None) if matches!(reg, Some(2..=5)) => Flow::Indirect,
+        // xbegin jumps to its fallback on abort
+        (0xC7, None) if reg == Some(7) => Flow::Indirect,
+        _ => Flow::Next,
+    };
+    Some(Instruction { len: i, flow, memory })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Flow, Operand, Segment};
+
+    fn check(bytes: &[u8], flow: Flow) {
+        let decoded = decode(bytes, 0x1000).map(|insn| (insn.len, insn.flow));
+        assert_eq!(decoded, Some((bytes.len(), flow)), "{:02X?}", bytes);
+    }
+
+    fn operand(bytes: &[u8]) -> Option<Operand> {
+        decode(bytes, 0x1000).unwrap().memory
+    }
+
+    fn memory(base: Option<u8>, index: Option<u8>, scale: u8, disp: i64) -> Option<Operand> {
+        Some(Operand {
+            segment: None,
+            base,
+            index,
+            scale,
+            disp,
+            rip_relative: false,
+            size: 64,
+        })
+    }
+
+    #[test]
+    fn lengths() {
+        check(&[0x55], Flow::Next); // push rbp
+        check(&[0x48, 0x89, 0xE5], Flow::Next); // mov rbp, rsp
+        check(&[0x48, 0x83, 0xEC, 0x10], Flow::Next); // sub rsp, 0x10
+        check(&[0x8B, 0x05, 0x78, 0x56, 0x34, 0x12], Flow::Next); // mov eax, [rip+0x12345678]
+        check(&[0x48, 0x8B, 0x44, 0x24, 0x08], Flow::Next); // mov rax, [rsp+8]
+        check(&[0x48, 0xB8, 1, 2, 3, 4, 5, 6, 7, 8], Flow::Next); // movabs rax, imm64
+        check(&[0x66, 0xC7, 0x45, 0xFC, 0x01, 0x00], Flow::Next); // mov word [rbp-4], 1
+        check(&[0xF7, 0xC1, 1, 0, 0, 0], Flow::Next); // test ecx, 1
+        check(&[0xF7, 0xD8], Flow::Next); // neg eax
+        check(&[0xF3, 0x0F, 0x1E, 0xFA], Flow::Next); // endbr64
+        check(&[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00], Flow::Next); // nopw [rax+rax]
+        check(&[0xC5, 0xF9, 0x6F, 0xC1], Flow::Next); // vmovdqa xmm0, xmm1
+        check(&[0x66, 0x0F, 0x3A, 0x0F, 0xC1, 0x08], Flow::Next); // palignr xmm0, xmm1, 8
+    }
+
+    #[test]
+    fn control_flow() {
+        check(&[0x75, 0xFE], Flow::Branch(0x1000)); // jne to itself
+        check(&[0x0F, 0x84, 0x10, 0, 0, 0], Flow::Branch(0x1016)); // je
+        check(&[0xEB, 0x02], Flow::Jump(0x1004));
+        check(&[0xE8, 0xFB, 0xFF, 0xFF, 0xFF], Flow::Call(0x1000));
+        check(&[0xC3], Flow::Indirect); // ret
+        check(&[0xFF, 0xE0], Flow::Indirect); // jmp rax
+        check(&[0xFF, 0x15, 0, 0, 0, 0], Flow::Indirect); // call [rip]
+        check(&[0x0F, 0x05], Flow::Indirect); // syscall
+        check(&[0xCC], Flow::Trap);
+        assert_eq!(decode(&[0x0F, 0x84, 0x10], 0), None);
+    }
+
+    #[test]
+    fn operands() {
+        // mov [rbp-8], rax
+        assert_eq!(operand(&[0x48, 0x89, 0x45, 0xF8]), memory(Some(5), None, 1, -8));
+        // mov eax, [rip+0x12345678]
+        let relative = memory(None, None, 1, 0x12345678).map(|op| Operand { rip_relative: true, ..op });
+        assert_eq!(operand(&[0x8B, 0x05, 0x78, 0x56, 0x34, 0x12]), relative);
+        // mov [r12+r13*8+0x10], ecx
+        assert_eq!(operand(&[0x43, 0x89, 0x4C, 0xEC, 0x10]), memory(Some(12), Some(13), 8, 0x10));
+        // mov rax, fs:[0x28]
+        let fs = memory(None, None, 1, 0x28).map(|op| Operand { segment: Some(Segment::Fs), ..op });
+        assert_eq!(operand(&[0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0, 0, 0]), fs);
+        // vmovdqu [rdi+0x20], ymm0
+        assert_eq!(operand(&[0xC5, 0xFE, 0x7F, 0x47, 0x20]), memory(Some(7), None, 1, 0x20));
+        // vmovdqu64 [rdi+0x40], zmm16, with the displacement in whole vectors
+        assert_eq!(operand(&[0x62, 0xE1, 0xFE, 0x48, 0x7F, 0x47, 0x01]), memory(Some(7), None, 1, 0x40));
+        // rep stosb
+        assert_eq!(operand(&[0xF3, 0xAA]), memory(Some(7), None, 1, 0));
+        // mov eax, ecx
+        assert_eq!(operand(&[0x89, 0xC8]), None);
+    }
+}
@@ -0,0 +1,70 @@
+//! Helpers for the tests that run the server on programs from binaries/
+
+// Not every test uses all of them
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::prelude::*,
+    path::{Path, PathBuf},
+    process::{self, Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Compile a C program from binaries/ into the tests' own directory, with
+/// extra flags for the compiler
+pub fn compile(source: &str, flags: &[&str]) -> PathBuf {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("binaries");
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join(source.trim_end_matches(".c"));
+    // Tests running at the same time may build the same program, so each
+    // build is moved in place only once it's complete
+    let build = BUILDS.fetch_add(1, Ordering::SeqCst);
+    let partial = output.with_extension(format!("{}-{}", process::id(), build));
+    let status = Command::new("cc")
+        .current_dir(&dir)
+        .args(flags)
+        .arg(source)
+        .arg("-o")
+        .arg(&partial)
+        .status()
+        .expect("cc is needed to build the test programs");
+    assert!(status.success(), "failed to build {}", source);
+    fs::rename(&partial, &output).unwrap();
+    output
+}
+
+pub fn send(stdin: &mut impl Write, packet: &str) {
+    let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(stdin, "${}#{:02x}", packet, checksum).unwrap();
+    stdin.flush().unwrap();
+}
+
+pub fn receive(stdout: &mut impl Read) -> String {
+    let mut packet = Vec::new();
+    let mut byte = [0];
+    loop {
+        stdout.read_exact(&mut byte).unwrap();
+        match byte[0] {
+            b'+' if packet.is_empty() => (),
+            b'#' => break,
+            b'$' => packet.clear(),
+            b => packet.push(b),
+        }
+    }
+    let mut checksum = [0; 2];
+    stdout.read_exact(&mut checksum).unwrap();
+    String::from_utf8(packet).unwrap()
+}
+
+/// Start the server on `program`, speaking the protocol on its stdio
+pub fn serve(program: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_gdbserver"))
+        .args(["--type", "stdio"])
+        .arg(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
@@ -0,0 +1,95 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{compile, receive, send, serve};
+use std::{
+    convert::TryInto,
+    fs,
+    io::prelude::*,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+};
+
+/// Assemble binaries/minimal.asm into the tests' own directory
+fn minimal_asm() -> PathBuf {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("binaries");
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join("minimal-asm");
+    let object = output.with_extension("o");
+    let status = Command::new("nasm")
+        .current_dir(&dir)
+        .args(["-f", "elf64", "minimal.asm", "-o"])
+        .arg(&object)
+        .status()
+        .expect("nasm is needed to build minimal.asm");
+    assert!(status.success(), "failed to assemble minimal.asm");
+    let status = Command::new("ld").arg(&object).arg("-o").arg(&output).status().unwrap();
+    assert!(status.success(), "failed to link minimal.asm");
+    output
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Ask the server where the program is stopped, then kill it
+fn stop_address(mut server: Child) -> u64 {
+    let mut stdin = server.stdin.take().unwrap();
+    let mut stdout = server.stdout.take().unwrap();
+
+    send(&mut stdin, "g");
+    let registers = receive(&mut stdout);
+    // rip comes after the 16 general purpose registers
+    let rip = u64::from_str_radix(&registers[16 * 16..17 * 16], 16).unwrap().swap_bytes();
+
+    stdin.write_all(b"+").unwrap();
+    send(&mut stdin, "k");
+    drop(stdin);
+    server.wait().unwrap();
+    rip
+}
+
+#[test]
+#[ignore = "needs nasm"]
+fn stops_at_entry() {
+    let program = minimal_asm();
+
+    let elf = fs::read(&program).unwrap();
+    let entry = read_u64(&elf, 24);
+
+    let rip = stop_address(serve(&program));
+    assert_eq!(rip, entry);
+}
+
+#[test]
+fn pie_stops_in_dynamic_loader() {
+    // A position independent and dynamically linked version of minimal.c
+    let program = compile("minimal.c", &["-fPIE", "-pie"]);
+
+    let mut server = serve(&program);
+    // Once the server answers, the program is running. Both it and the loader
+    // were relocated, so find out where from its auxiliary vector.
+    send(server.stdin.as_mut().unwrap(), "?");
+    receive(server.stdout.as_mut().unwrap());
+    server.stdin.as_mut().unwrap().write_all(b"+").unwrap();
+    let children = fs::read_to_string(format!("/proc/{0}/task/{0}/children", server.id())).unwrap();
+    let pid: u32 = children.split_whitespace().next().unwrap().parse().unwrap();
+    let auxv = fs::read(format!("/proc/{}/auxv", pid)).unwrap();
+    let auxv: Vec<(u64, u64)> = (0..auxv.len() / 16)
+        .map(|i| (read_u64(&auxv, i * 16), read_u64(&auxv, i * 16 + 8)))
+        .collect();
+    let find = |kind| auxv.iter().find(|&&(key, _)| key == kind).map(|&(_, value)| value).unwrap();
+    let (base, entry) = (find(7), find(9)); // AT_BASE and AT_ENTRY
+
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap();
+    let loader = maps.lines()
+        .find(|line| line.starts_with(&format!("{:x}-", base)))
+        .and_then(|line| line.split_whitespace().nth(5))
+        .unwrap();
+    let loader_entry = read_u64(&fs::read(loader).unwrap(), 24);
+
+    let rip = stop_address(server);
+    assert_ne!(base, 0);
+    assert_ne!(rip, entry);
+    assert_eq!(rip, base + loader_entry);
+}
@@ -0,0 +1,140 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{compile, receive, send};
+use std::{
+    fs,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{self, Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The server, killed if a test fails before it's done with it
+struct Server(Child);
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start the server on `program`, listening on a socket named after `name`
+fn listen(program: &Path, name: &str, args: &[&str]) -> (Server, PathBuf) {
+    let socket = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}-{}.sock", name, process::id()));
+    let server = Command::new(env!("CARGO_BIN_EXE_gdbserver"))
+        .args(["--type", "unix", "--addr"])
+        .arg(&socket)
+        .args(args)
+        .arg(program)
+        // Keep the program's output out of the tests'
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    (Server(server), socket)
+}
+
+/// Connect to the server, once it's listening, and turn acknowledgments off
+fn connect(socket: &Path) -> UnixStream {
+    let start = Instant::now();
+    let mut stream = loop {
+        match UnixStream::connect(socket) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(10)),
+            Err(err) => panic!("server isn't listening: {}", err),
+        }
+    };
+    send(&mut stream, "QStartNoAckMode");
+    assert_eq!(receive(&mut stream), "OK");
+    stream
+}
+
+fn request(stream: &mut UnixStream, packet: &str) -> String {
+    send(stream, packet);
+    receive(stream)
+}
+
+/// Wait for the server to exit by itself
+fn exited(server: &mut Child) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if server.try_wait().unwrap().is_some() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+#[test]
+fn reconnect() {
+    let program = compile("signal.c", &[]);
+    let (server, socket) = listen(&program, "reconnect", &[]);
+
+    // Until the program raises SIGUSR1
+    let mut stream = connect(&socket);
+    let launched = request(&mut stream, "g");
+    let stop = request(&mut stream, "vCont;c");
+    let registers = request(&mut stream, "g");
+    assert_ne!(registers, launched);
+    drop(stream);
+
+    // Still stopped there for the next GDB
+    let mut stream = connect(&socket);
+    assert_eq!(request(&mut stream, "?"), stop);
+    assert_eq!(request(&mut stream, "g"), registers);
+    assert!(request(&mut stream, "vCont;c").starts_with("W00;"));
+    drop(stream);
+
+    // Exited, so started again as a new process
+    let mut stream = connect(&socket);
+    let restarted = request(&mut stream, "?");
+    assert!(restarted.starts_with("T05thread:"), "{}", restarted);
+    assert_ne!(restarted, stop);
+    drop(stream);
+
+    drop(server);
+    fs::remove_file(socket).unwrap();
+}
+
+#[test]
+fn once() {
+    let program = compile("signal.c", &[]);
+    let (mut server, socket) = listen(&program, "once", &["--once"]);
+
+    let mut stream = connect(&socket);
+    assert!(request(&mut stream, "?").starts_with("T05thread:"));
+    drop(stream);
+
+    assert!(exited(&mut server.0), "server is still waiting for GDB");
+    assert!(server.0.wait().unwrap().success());
+    fs::remove_file(socket).unwrap();
+}
+
+#[test]
+fn wait_for_crash_exit() {
+    let program = compile("minimal.c", &[]);
+    let (mut server, socket) = listen(&program, "exit", &["--wait-for-crash"]);
+
+    // Nothing to debug
+    assert!(exited(&mut server.0), "server is waiting for GDB");
+    assert!(server.0.wait().unwrap().success());
+    assert!(!socket.exists());
+}
+
+#[test]
+fn wait_for_crash() {
+    let program = compile("sigsegv.c", &[]);
+    let (server, socket) = listen(&program, "crash", &["--wait-for-crash"]);
+
+    // Left where it crashed
+    let mut stream = connect(&socket);
+    let stop = request(&mut stream, "?");
+    assert!(stop.starts_with("T0bthread:"), "{}", stop);
+    drop(stream);
+
+    drop(server);
+    fs::remove_file(socket).unwrap();
+}
@@ -0,0 +1,55 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{compile, receive, send, serve};
+use std::fs;
+
+/// Read the registers of a thread, with `request` sending a packet and
+/// returning the reply
+fn registers(request: &mut impl FnMut(&str) -> String, pid: u32, tid: u32) -> String {
+    assert_eq!(request(&format!("Hgp{:x}.{:x}", pid, tid)), "OK");
+    request("g")
+}
+
+#[test]
+fn step_one_thread() {
+    let program = compile("threads.c", &["-pthread"]);
+    let mut server = serve(&program);
+    let mut stdin = server.stdin.take().unwrap();
+    let mut stdout = server.stdout.take().unwrap();
+    let mut request = |packet: &str| {
+        send(&mut stdin, packet);
+        receive(&mut stdout)
+    };
+    assert_eq!(request("QStartNoAckMode"), "OK");
+
+    // Until the main thread raises SIGTRAP, with the other one spinning
+    let stop = request("vCont;c");
+    let thread = stop.strip_prefix("T05thread:p").and_then(|rest| rest.strip_suffix(';')).unwrap();
+    let dot = thread.find('.').unwrap();
+    let pid = u32::from_str_radix(&thread[..dot], 16).unwrap();
+    let main = u32::from_str_radix(&thread[dot + 1..], 16).unwrap();
+    assert_eq!(main, pid);
+    let spinning: u32 = fs::read_dir(format!("/proc/{}/task", pid))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap().parse().unwrap())
+        .find(|&tid| tid != main)
+        .unwrap();
+
+    let main_before = registers(&mut request, pid, main);
+    let spinning_before = registers(&mut request, pid, spinning);
+
+    // Only the thread it names, and the reply says so
+    let stop = request(&format!("vCont;s:p{:x}.{:x}", pid, spinning));
+    assert_eq!(stop, format!("T05thread:p{:x}.{:x};", pid, spinning));
+    // rip comes after the 16 general purpose registers
+    let rip = |registers: &str| String::from(&registers[16 * 16..17 * 16]);
+    let spinning_after = registers(&mut request, pid, spinning);
+    assert_ne!(rip(&spinning_after), rip(&spinning_before));
+    assert_eq!(registers(&mut request, pid, main), main_before);
+
+    send(&mut stdin, "k");
+    drop(stdin);
+    server.wait().unwrap();
+}